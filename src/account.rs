@@ -0,0 +1,252 @@
+use std::collections::{HashMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+
+use crate::block::Block;
+use crate::transaction::Transaction;
+
+/// Balance and transaction counter of a single address
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct Account {
+    pub balance: u64,
+    pub nonce: u64,
+}
+
+/// Accounts touched by a block with their state before it (`None` if new)
+pub type AccountUndo = Vec<(String, Option<Account>)>;
+
+/// Accounts as changed by transactions that are not in a block yet (e.g.
+/// the mempool), on top of an `AccountState`
+#[derive(Debug, Clone, Default)]
+pub struct AccountPending {
+    accounts: HashMap<String, Account>,
+}
+
+/// Account-based ledger state: a balance and nonce per address
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AccountState {
+    accounts: HashMap<String, Account>,
+}
+
+impl AccountState {
+    /// Create an empty account state
+    pub fn new() -> Self {
+        AccountState::default()
+    }
+
+    /// Look up an account (unknown addresses have zero balance and nonce)
+    pub fn get(&self, address: &str) -> Account {
+        self.accounts.get(address).copied().unwrap_or_default()
+    }
+
+    /// Balance of an address
+    pub fn balance(&self, address: &str) -> u64 {
+        self.get(address).balance
+    }
+
+    /// Nonce the next transaction from `address` must carry
+    pub fn nonce(&self, address: &str) -> u64 {
+        self.get(address).nonce
+    }
+
+    /// Number of known accounts
+    pub fn len(&self) -> usize {
+        self.accounts.len()
+    }
+
+    /// Check if there are no known accounts
+    pub fn is_empty(&self) -> bool {
+        self.accounts.is_empty()
+    }
+
+    /// Check that a list of transactions can be applied in order on top of this state.
+    /// Each transfer must carry the sender's next nonce and its outputs plus fee
+    /// must be covered by the sender's balance. Returns the total fees paid.
+    pub fn check_transactions(&self, transactions: &[Transaction]) -> Result<u64, String> {
+        let mut pending = AccountPending::default();
+        transactions.iter().try_fold(0u64, |fees, tx| {
+            fees.checked_add(self.add_pending(&mut pending, tx)?)
+                .ok_or_else(|| String::from("Total fees overflow"))
+        })
+    }
+
+    /// Check that a transaction can be applied on top of this state and the
+    /// `pending` transactions before it, and add it to `pending`. Returns its
+    /// fee (zero for a coinbase). `pending` is left unchanged on error.
+    pub fn add_pending(&self, pending: &mut AccountPending, tx: &Transaction) -> Result<u64, String> {
+        let output_total = tx
+            .output_total()
+            .ok_or_else(|| format!("Transaction {} output total overflows", tx.hash()))?;
+        let current = |address: &str| pending.accounts.get(address).copied().unwrap_or_else(|| self.get(address));
+
+        let mut changes: HashMap<&str, Account> = HashMap::new();
+        if !tx.is_coinbase() {
+            if !tx.inputs.is_empty() {
+                return Err(format!("Transaction {} has inputs in account mode", tx.hash()));
+            }
+
+            let sender = current(&tx.from);
+            if tx.nonce != sender.nonce {
+                return Err(format!(
+                    "Transaction {} has nonce {}, expected {}",
+                    tx.hash(),
+                    tx.nonce,
+                    sender.nonce
+                ));
+            }
+
+            let cost = output_total
+                .checked_add(tx.fee)
+                .ok_or_else(|| format!("Transaction {} cost overflows", tx.hash()))?;
+
+            if cost > sender.balance {
+                return Err(format!(
+                    "Transaction {} spends {} but {} only has {}",
+                    tx.hash(),
+                    cost,
+                    tx.from,
+                    sender.balance
+                ));
+            }
+
+            changes.insert(
+                &tx.from,
+                Account {
+                    balance: sender.balance - cost,
+                    nonce: sender.nonce + 1,
+                },
+            );
+        }
+
+        for output in &tx.outputs {
+            let recipient = changes
+                .entry(output.address.as_str())
+                .or_insert_with(|| current(&output.address));
+            recipient.balance = recipient
+                .balance
+                .checked_add(output.amount)
+                .ok_or_else(|| format!("Balance of {} overflows", output.address))?;
+        }
+
+        for (address, account) in changes {
+            pending.accounts.insert(address.to_string(), account);
+        }
+        Ok(if tx.is_coinbase() { 0 } else { tx.fee })
+    }
+
+    /// Apply a block's transactions: debit senders, bump their nonces and credit recipients.
+    /// Returns the previous state of every touched account, needed to undo the block.
+    pub fn apply_block(&mut self, block: &Block) -> AccountUndo {
+        let mut undo: AccountUndo = Vec::new();
+        let mut touched: HashSet<String> = HashSet::new();
+        let mut remember = |accounts: &HashMap<String, Account>, address: &String| {
+            if touched.insert(address.clone()) {
+                undo.push((address.clone(), accounts.get(address).copied()));
+            }
+        };
+
+        for tx in &block.transactions {
+            if !tx.is_coinbase() {
+                remember(&self.accounts, &tx.from);
+                let cost = tx.output_total().unwrap_or(0).saturating_add(tx.fee);
+                let sender = self.accounts.entry(tx.from.clone()).or_default();
+                sender.balance = sender.balance.saturating_sub(cost);
+                sender.nonce += 1;
+            }
+
+            for output in &tx.outputs {
+                remember(&self.accounts, &output.address);
+                let recipient = self.accounts.entry(output.address.clone()).or_default();
+                recipient.balance = recipient.balance.saturating_add(output.amount);
+            }
+        }
+
+        undo
+    }
+
+    /// Revert `apply_block` by restoring the accounts it touched
+    pub fn undo_block(&mut self, undo: AccountUndo) {
+        for (address, account) in undo {
+            match account {
+                Some(account) => self.accounts.insert(address, account),
+                None => self.accounts.remove(&address),
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::ZERO_HASH;
+    use crate::transaction::{OutPoint, TxOutput};
+
+    /// Block at height 1 with the given transactions (not mined)
+    fn block(transactions: Vec<Transaction>) -> Block {
+        Block::new(1, ZERO_HASH.to_string(), transactions, 0)
+    }
+
+    /// State in which `alice` owns 50 coins
+    fn funded() -> AccountState {
+        let mut accounts = AccountState::new();
+        accounts.apply_block(&block(vec![Transaction::coinbase(String::from("alice"), 50, 1)]));
+        accounts
+    }
+
+    fn transfer(nonce: u64, amount: u64, fee: u64) -> Transaction {
+        let outputs = vec![TxOutput::new(String::from("bob"), amount)];
+        Transaction::transfer(String::from("alice"), nonce, fee, outputs)
+    }
+
+    #[test]
+    fn transfers_move_balances_and_bump_the_nonce() {
+        let mut accounts = funded();
+        let txs = vec![transfer(0, 20, 2), transfer(1, 10, 1)];
+        assert_eq!(accounts.check_transactions(&txs), Ok(3));
+
+        accounts.apply_block(&block(txs));
+        assert_eq!(accounts.balance("alice"), 17);
+        assert_eq!(accounts.balance("bob"), 30);
+        assert_eq!(accounts.nonce("alice"), 2);
+        assert_eq!(accounts.nonce("bob"), 0);
+    }
+
+    #[test]
+    fn replayed_and_skipped_nonces_are_rejected() {
+        let mut accounts = funded();
+        let first = transfer(0, 10, 0);
+        accounts.apply_block(&block(vec![first.clone()]));
+
+        assert!(accounts.check_transactions(&[first]).is_err());
+        assert!(accounts.check_transactions(&[transfer(2, 10, 0)]).is_err());
+        assert!(accounts.check_transactions(&[transfer(1, 10, 0), transfer(1, 10, 0)]).is_err());
+        assert_eq!(accounts.check_transactions(&[transfer(1, 10, 0)]), Ok(0));
+    }
+
+    #[test]
+    fn overspends_and_inputs_are_rejected() {
+        let accounts = funded();
+        assert!(accounts.check_transactions(&[transfer(0, 50, 1)]).is_err());
+        assert!(accounts.check_transactions(&[transfer(0, 30, 0), transfer(1, 30, 0)]).is_err());
+        assert!(accounts.check_transactions(&[transfer(0, u64::MAX, 1)]).is_err());
+
+        let mut with_inputs = transfer(0, 10, 0);
+        with_inputs.inputs.push(OutPoint::new("ab".repeat(32), 0));
+        assert!(accounts.check_transactions(&[with_inputs]).is_err());
+    }
+
+    #[test]
+    fn undo_restores_touched_accounts() {
+        let mut accounts = funded();
+        let spending = block(vec![transfer(0, 20, 5)]);
+
+        let undo = accounts.apply_block(&spending);
+        assert_eq!(accounts.len(), 2);
+        accounts.undo_block(undo);
+
+        assert_eq!(accounts.get("alice"), Account { balance: 50, nonce: 0 });
+        assert_eq!(accounts.balance("bob"), 0);
+        // Accounts created by the block are removed again
+        assert_eq!(accounts.len(), 1);
+    }
+}
@@ -0,0 +1,144 @@
+use ripemd::Ripemd160;
+use secp256k1::{PublicKey, Secp256k1, SecretKey};
+use sha2::{Digest, Sha256};
+
+/// Version byte prepended to the public key hash before encoding
+pub const ADDRESS_VERSION: u8 = 0x00;
+
+/// RIPEMD-160 of SHA-256, as used for Bitcoin public key hashes
+pub fn hash160(data: &[u8]) -> [u8; 20] {
+    let sha = Sha256::digest(data);
+    Ripemd160::digest(sha).into()
+}
+
+/// First four bytes of double SHA-256, used as the Base58Check checksum
+fn checksum(data: &[u8]) -> [u8; 4] {
+    let hash = Sha256::digest(Sha256::digest(data));
+    [hash[0], hash[1], hash[2], hash[3]]
+}
+
+/// Encode a public key hash as a Base58Check address
+pub fn encode(pubkey_hash: &[u8; 20]) -> String {
+    let mut data = Vec::with_capacity(25);
+    data.push(ADDRESS_VERSION);
+    data.extend_from_slice(pubkey_hash);
+    let check = checksum(&data);
+    data.extend_from_slice(&check);
+    bs58::encode(data).into_string()
+}
+
+/// Decode a Base58Check address into its public key hash
+pub fn decode(address: &str) -> Result<[u8; 20], String> {
+    let data = bs58::decode(address)
+        .into_vec()
+        .map_err(|e| format!("Invalid address {}: {}", address, e))?;
+
+    if data.len() != 25 {
+        return Err(format!("Invalid address {}: wrong length", address));
+    }
+    if data[0] != ADDRESS_VERSION {
+        return Err(format!("Invalid address {}: unknown version {}", address, data[0]));
+    }
+    if checksum(&data[..21]) != data[21..] {
+        return Err(format!("Invalid address {}: bad checksum", address));
+    }
+
+    let mut pubkey_hash = [0u8; 20];
+    pubkey_hash.copy_from_slice(&data[1..21]);
+    Ok(pubkey_hash)
+}
+
+/// Check if a string is a well-formed address
+pub fn is_valid(address: &str) -> bool {
+    decode(address).is_ok()
+}
+
+/// Derive the address of a public key (hash160 of its compressed encoding)
+pub fn from_public_key(public_key: &PublicKey) -> String {
+    encode(&hash160(&public_key.serialize()))
+}
+
+/// Derive the address of a hex-encoded public key
+pub fn from_public_key_hex(public_key_hex: &str) -> Result<String, String> {
+    let bytes = hex::decode(public_key_hex).map_err(|e| e.to_string())?;
+    let public_key = PublicKey::from_slice(&bytes).map_err(|e| e.to_string())?;
+    Ok(from_public_key(&public_key))
+}
+
+/// A key pair together with its derived address
+#[derive(Debug, Clone)]
+pub struct Wallet {
+    pub secret_key: String,
+    pub public_key: String,
+    pub address: String,
+}
+
+impl Wallet {
+    /// Generate a new random key pair
+    pub fn generate() -> Self {
+        let secp = Secp256k1::new();
+        let (secret_key, public_key) = secp.generate_keypair(&mut rand::thread_rng());
+        Wallet {
+            secret_key: hex::encode(secret_key.secret_bytes()),
+            public_key: hex::encode(public_key.serialize()),
+            address: from_public_key(&public_key),
+        }
+    }
+
+    /// Load a wallet from a hex-encoded secret key
+    pub fn from_secret_key(secret_key_hex: &str) -> Result<Self, String> {
+        let secp = Secp256k1::new();
+        let bytes = hex::decode(secret_key_hex).map_err(|e| e.to_string())?;
+        let secret_key = SecretKey::from_slice(&bytes).map_err(|e| e.to_string())?;
+        let public_key = PublicKey::from_secret_key(&secp, &secret_key);
+        Ok(Wallet {
+            secret_key: secret_key_hex.to_string(),
+            public_key: hex::encode(public_key.serialize()),
+            address: from_public_key(&public_key),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Base58Check encoding of arbitrary payload bytes
+    fn encode_raw(payload: &[u8]) -> String {
+        let mut data = payload.to_vec();
+        data.extend_from_slice(&checksum(payload));
+        bs58::encode(data).into_string()
+    }
+
+    #[test]
+    fn addresses_round_trip() {
+        let wallet = Wallet::generate();
+        let hash = decode(&wallet.address).unwrap();
+        assert_eq!(encode(&hash), wallet.address);
+        assert_eq!(from_public_key_hex(&wallet.public_key), Ok(wallet.address.clone()));
+        assert_eq!(Wallet::from_secret_key(&wallet.secret_key).unwrap().address, wallet.address);
+    }
+
+    #[test]
+    fn bad_checksum_is_rejected() {
+        let mut data = bs58::decode(Wallet::generate().address).into_vec().unwrap();
+        data[24] ^= 1;
+        assert!(decode(&bs58::encode(data).into_string()).unwrap_err().contains("checksum"));
+    }
+
+    #[test]
+    fn unknown_version_is_rejected() {
+        let mut payload = vec![ADDRESS_VERSION + 1];
+        payload.extend_from_slice(&[7u8; 20]);
+        assert!(decode(&encode_raw(&payload)).unwrap_err().contains("version"));
+    }
+
+    #[test]
+    fn wrong_length_is_rejected() {
+        let mut short = vec![ADDRESS_VERSION];
+        short.extend_from_slice(&[7u8; 19]);
+        assert!(decode(&encode_raw(&short)).unwrap_err().contains("length"));
+        assert!(!is_valid("0OIl"));
+        assert!(!is_valid(""));
+    }
+}
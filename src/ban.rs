@@ -0,0 +1,381 @@
+//! Misbehaviour scores and bans.
+//!
+//! Every offence adds to the sending peer's score; once the score reaches
+//! `BAN_THRESHOLD` the peer is banned for the configured time. Scores fall
+//! by a point every `SCORE_DECAY_INTERVAL`, so occasional slips of an honest
+//! peer do not add up to a ban over a long connection. Peers are
+//! identified by the IP address their connection comes from, never by an
+//! address they report about themselves, so a banned peer cannot get back in
+//! by claiming another address and cannot get someone else banned by
+//! claiming theirs. Whitelisted IPs are never scored, so nodes sharing a
+//! host (such as a local test network) do not ban one another. The ban
+//! list is kept in a JSON file when the node has a data directory.
+//!
+//! Listing and lifting bans needs the node's admin token, which is written
+//! to `ADMIN_TOKEN_FILE` in the data directory.
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::ValidationError;
+use crate::network_time;
+
+/// Score at which a peer is banned
+pub const BAN_THRESHOLD: u32 = 100;
+
+/// Seconds after which a peer's score falls by one point
+pub const SCORE_DECAY_INTERVAL: u64 = 60;
+
+/// Default number of seconds a ban lasts
+pub const DEFAULT_BAN_TIME: u64 = 24 * 60 * 60;
+
+/// Name of the ban list file in the data directory
+pub const BAN_FILE: &str = "banlist.json";
+
+/// Name of the admin token file in the data directory
+pub const ADMIN_TOKEN_FILE: &str = "admin.token";
+
+/// Misbehaviour a peer can be scored for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Offence {
+    /// Sent a block or header without valid proof of work
+    InvalidProofOfWork,
+    /// Sent a transaction with a bad signature
+    BadSignature,
+    /// Sent a block breaking another consensus rule
+    InvalidBlock,
+    /// Sent a frame or message that could not be decoded
+    MalformedMessage,
+    /// Sent a response or data nobody asked for
+    UnsolicitedData,
+}
+
+impl Offence {
+    /// Points added to the peer's score
+    pub fn score(&self) -> u32 {
+        match self {
+            Offence::InvalidProofOfWork | Offence::BadSignature | Offence::InvalidBlock => BAN_THRESHOLD,
+            Offence::MalformedMessage => 50,
+            Offence::UnsolicitedData => 10,
+        }
+    }
+
+    /// Offence of a peer that sent a block or header rejected with `error`,
+    /// if the rejection is the sender's fault. Blocks that are merely early,
+    /// out of order or already known are not punished.
+    pub fn for_block(error: &ValidationError) -> Option<Offence> {
+        match error {
+            ValidationError::BadProofOfWork | ValidationError::BadDifficulty => Some(Offence::InvalidProofOfWork),
+            ValidationError::BadSignature(_) => Some(Offence::BadSignature),
+            ValidationError::BadIndex { .. }
+            | ValidationError::BadPrevHash
+            | ValidationError::NonCanonicalHeader
+            | ValidationError::BadHash
+            | ValidationError::UnsupportedVersion(_)
+            | ValidationError::TimestampTooOld { .. }
+            | ValidationError::BadMerkleRoot
+            | ValidationError::BlockTooLarge { .. }
+            | ValidationError::BadCoinbase(_)
+            | ValidationError::InvalidSpend(_) => Some(Offence::InvalidBlock),
+            ValidationError::EmptyChain
+            | ValidationError::UnknownParent(_)
+            | ValidationError::ReorgTooDeep { .. }
+            | ValidationError::KnownInvalid(_)
+            | ValidationError::TimestampTooNew { .. }
+            | ValidationError::DuplicateTransaction(_) => None,
+        }
+    }
+
+    /// Offence of a peer that sent a transaction rejected with `error`.
+    /// Spends that conflict with the mempool can happen to honest peers.
+    pub fn for_transaction(error: &ValidationError) -> Option<Offence> {
+        match error {
+            ValidationError::BadSignature(_) => Some(Offence::BadSignature),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Offence {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Offence::InvalidProofOfWork => write!(f, "invalid proof of work"),
+            Offence::BadSignature => write!(f, "bad signature"),
+            Offence::InvalidBlock => write!(f, "invalid block"),
+            Offence::MalformedMessage => write!(f, "malformed message"),
+            Offence::UnsolicitedData => write!(f, "unsolicited data"),
+        }
+    }
+}
+
+/// A banned peer
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Ban {
+    /// IP address of the peer
+    pub ip: IpAddr,
+    /// Time the ban ends, in seconds since the Unix epoch
+    pub until: u64,
+    /// Offence that got the peer banned
+    pub reason: String,
+}
+
+/// Misbehaviour scores of connected peers and the list of banned peers
+#[derive(Debug)]
+pub struct BanManager {
+    /// Score of each IP and the time it last decayed
+    scores: HashMap<IpAddr, (u32, u64)>,
+    bans: HashMap<IpAddr, Ban>,
+    /// IPs that are never scored or banned
+    whitelist: HashSet<IpAddr>,
+    /// Seconds a ban lasts
+    ban_time: u64,
+    /// File the ban list is saved to
+    path: Option<PathBuf>,
+}
+
+impl Default for BanManager {
+    fn default() -> Self {
+        BanManager::new(DEFAULT_BAN_TIME)
+    }
+}
+
+impl BanManager {
+    /// Create a manager keeping its ban list in memory
+    pub fn new(ban_time: u64) -> Self {
+        BanManager {
+            scores: HashMap::new(),
+            bans: HashMap::new(),
+            whitelist: HashSet::new(),
+            ban_time,
+            path: None,
+        }
+    }
+
+    /// Create a manager keeping its ban list in `path`, loading the bans
+    /// saved there that have not expired
+    pub fn open(path: &Path, ban_time: u64) -> Result<Self, String> {
+        let mut manager = BanManager::new(ban_time);
+        if path.exists() {
+            let data = fs::read(path).map_err(|e| format!("Cannot read {}: {}", path.display(), e))?;
+            let bans: Vec<Ban> =
+                serde_json::from_slice(&data).map_err(|e| format!("Bad ban list {}: {}", path.display(), e))?;
+            let now = network_time::local_time();
+            manager.bans = bans
+                .into_iter()
+                .filter(|ban| ban.until > now)
+                .map(|ban| (ban.ip, ban))
+                .collect();
+        }
+        manager.path = Some(path.to_path_buf());
+        Ok(manager)
+    }
+
+    /// Never score or ban the peers on `ip`
+    pub fn whitelist(&mut self, ip: IpAddr) {
+        self.scores.remove(&ip);
+        self.whitelist.insert(ip);
+    }
+
+    /// Check if the peers on `ip` are exempt from bans
+    pub fn is_whitelisted(&self, ip: IpAddr) -> bool {
+        self.whitelist.contains(&ip)
+    }
+
+    /// Current misbehaviour score of a peer
+    pub fn score(&self, ip: IpAddr) -> u32 {
+        let now = network_time::local_time();
+        self.scores.get(&ip).map_or(0, |&score| decay(score, now).0)
+    }
+
+    /// Add an offence to a peer's score, banning it once the score reaches
+    /// `BAN_THRESHOLD`. Returns true if the peer was banned.
+    pub fn misbehaving(&mut self, ip: IpAddr, offence: Offence) -> bool {
+        if self.is_whitelisted(ip) {
+            return false;
+        }
+        // Scores that decayed to nothing are forgotten
+        let now = network_time::local_time();
+        for score in self.scores.values_mut() {
+            *score = decay(*score, now);
+        }
+        self.scores.retain(|_, (points, _)| *points > 0);
+
+        let (points, _) = self.scores.entry(ip).or_insert((0, now));
+        *points = points.saturating_add(offence.score());
+        if *points < BAN_THRESHOLD {
+            return false;
+        }
+        self.ban(ip, &offence.to_string());
+        true
+    }
+
+    /// Ban a peer for the configured time
+    pub fn ban(&mut self, ip: IpAddr, reason: &str) {
+        self.scores.remove(&ip);
+        let ban = Ban {
+            ip,
+            until: network_time::local_time() + self.ban_time,
+            reason: reason.to_string(),
+        };
+        self.bans.insert(ip, ban);
+        self.save();
+    }
+
+    /// Check if a peer is banned (a whitelisted one never is, even if it
+    /// was banned before being whitelisted)
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        !self.is_whitelisted(ip)
+            && self
+                .bans
+                .get(&ip)
+                .is_some_and(|ban| ban.until > network_time::local_time())
+    }
+
+    /// Bans that have not expired, ending soonest first
+    pub fn bans(&self) -> Vec<Ban> {
+        let now = network_time::local_time();
+        let mut bans: Vec<Ban> = self.bans.values().filter(|ban| ban.until > now).cloned().collect();
+        bans.sort_by(|a, b| a.until.cmp(&b.until).then_with(|| a.ip.cmp(&b.ip)));
+        bans
+    }
+
+    /// Lift the ban of a peer. Returns false if it was not banned.
+    pub fn unban(&mut self, ip: IpAddr) -> bool {
+        let removed = self.bans.remove(&ip).is_some();
+        if removed {
+            self.save();
+        }
+        removed
+    }
+
+    /// Lift every ban, returning how many there were
+    pub fn clear(&mut self) -> usize {
+        let count = self.bans().len();
+        self.bans.clear();
+        self.save();
+        count
+    }
+
+    /// Write the ban list to its file, if it has one
+    fn save(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let result = serde_json::to_vec_pretty(&self.bans())
+            .map_err(|e| e.to_string())
+            .and_then(|data| fs::write(path, data).map_err(|e| e.to_string()));
+        if let Err(e) = result {
+            eprintln!("Failed to save ban list to {}: {}", path.display(), e);
+        }
+    }
+}
+
+/// A score and the time it last decayed, decayed to `now`. Time left over
+/// from a partial interval is kept for the next decay.
+fn decay((points, updated): (u32, u64), now: u64) -> (u32, u64) {
+    let intervals = now.saturating_sub(updated) / SCORE_DECAY_INTERVAL;
+    let points = points.saturating_sub(intervals.min(u32::MAX as u64) as u32);
+    (points, updated + intervals * SCORE_DECAY_INTERVAL)
+}
+
+/// New random admin token
+pub fn generate_admin_token() -> String {
+    hex::encode(rand::random::<[u8; 32]>())
+}
+
+/// Write the admin token to `path`, readable by its owner only
+pub fn write_admin_token(path: &Path, token: &str) -> Result<(), String> {
+    // Removed first so a file made readable to others is not reused
+    let _ = fs::remove_file(path);
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options
+        .open(path)
+        .and_then(|mut file| std::io::Write::write_all(&mut file, token.as_bytes()))
+        .map_err(|e| format!("Cannot write {}: {}", path.display(), e))
+}
+
+/// Read the admin token written by a node to `path`
+pub fn read_admin_token(path: &Path) -> Result<String, String> {
+    fs::read_to_string(path)
+        .map(|token| token.trim().to_string())
+        .map_err(|e| format!("Cannot read {}: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn offences_add_up_to_a_ban_of_the_ip() {
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let mut bans = BanManager::default();
+        assert!(!bans.misbehaving(ip, Offence::MalformedMessage));
+        assert_eq!(bans.score(ip), 50);
+        assert!(bans.misbehaving(ip, Offence::MalformedMessage));
+        assert!(bans.is_banned(ip));
+        assert!(!bans.is_banned("10.0.0.2".parse().unwrap()));
+
+        assert!(bans.unban(ip));
+        assert!(!bans.is_banned(ip));
+        assert_eq!(bans.score(ip), 0);
+    }
+
+    #[test]
+    fn scores_decay_over_time() {
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let mut bans = BanManager::default();
+        assert!(!bans.misbehaving(ip, Offence::MalformedMessage));
+
+        // As if the offence was 30 intervals and a bit ago
+        bans.scores.get_mut(&ip).unwrap().1 -= 30 * SCORE_DECAY_INTERVAL + 1;
+        assert_eq!(bans.score(ip), 20);
+        assert!(!bans.misbehaving(ip, Offence::MalformedMessage));
+        assert_eq!(bans.score(ip), 70);
+
+        // Forgotten once it has decayed completely
+        bans.scores.get_mut(&ip).unwrap().1 -= 70 * SCORE_DECAY_INTERVAL;
+        assert_eq!(bans.score(ip), 0);
+        assert!(!bans.misbehaving("10.0.0.2".parse().unwrap(), Offence::UnsolicitedData));
+        assert!(!bans.scores.contains_key(&ip));
+    }
+
+    #[test]
+    fn whitelisted_ips_are_never_banned() {
+        let ip: IpAddr = "127.0.0.1".parse().unwrap();
+        let mut bans = BanManager::default();
+        bans.ban(ip, "invalid block");
+        bans.whitelist(ip);
+        assert!(!bans.is_banned(ip));
+
+        assert!(!bans.misbehaving(ip, Offence::InvalidBlock));
+        assert_eq!(bans.score(ip), 0);
+        assert!(!bans.is_banned(ip));
+    }
+
+    #[test]
+    fn admin_token_is_read_back() {
+        let path = crate::storage::test_dir("admin-token");
+        std::fs::create_dir_all(&path).unwrap();
+        let path = path.join(ADMIN_TOKEN_FILE);
+        let token = generate_admin_token();
+        assert_eq!(token.len(), 64);
+        assert_ne!(token, generate_admin_token());
+
+        write_admin_token(&path, &token).unwrap();
+        assert_eq!(read_admin_token(&path).unwrap(), token);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
@@ -1,82 +1,194 @@
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 
+use crate::merkle::{self, MerkleProof};
+use crate::pow::{self, U256};
 use crate::transaction::Transaction;
 
+/// Hash used as the parent of the genesis block
+pub const ZERO_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// Current block header version
+pub const BLOCK_VERSION: u32 = 1;
+
+/// The part of a block covered by proof of work
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BlockHeader {
+    pub version: u32,
+    pub prev_hash: String,
+    pub merkle_root: String,
+    pub timestamp: u64,
+    /// Compact encoding of the proof-of-work target
+    pub bits: u32,
+    pub nonce: u64,
+}
+
+impl BlockHeader {
+    /// Size of the binary encoding in bytes
+    pub const SIZE: usize = 4 + 32 + 32 + 8 + 4 + 8;
+
+    /// Fixed-layout binary encoding: version (u32 LE), previous hash (32 bytes),
+    /// merkle root (32 bytes), timestamp (u64 LE), bits (u32 LE), nonce (u64 LE)
+    pub fn encode(&self) -> [u8; Self::SIZE] {
+        let mut bytes = [0u8; Self::SIZE];
+        bytes[0..4].copy_from_slice(&self.version.to_le_bytes());
+        bytes[4..36].copy_from_slice(&hash_bytes(&self.prev_hash));
+        bytes[36..68].copy_from_slice(&hash_bytes(&self.merkle_root));
+        bytes[68..76].copy_from_slice(&self.timestamp.to_le_bytes());
+        bytes[76..80].copy_from_slice(&self.bits.to_le_bytes());
+        bytes[80..88].copy_from_slice(&self.nonce.to_le_bytes());
+        bytes
+    }
+
+    /// Decode a header from its binary encoding
+    pub fn decode(bytes: &[u8]) -> Result<Self, String> {
+        if bytes.len() != Self::SIZE {
+            return Err(format!("Header must be {} bytes, got {}", Self::SIZE, bytes.len()));
+        }
+
+        let u32_at = |at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap());
+        let u64_at = |at: usize| u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap());
+
+        Ok(BlockHeader {
+            version: u32_at(0),
+            prev_hash: hex::encode(&bytes[4..36]),
+            merkle_root: hex::encode(&bytes[36..68]),
+            timestamp: u64_at(68),
+            bits: u32_at(76),
+            nonce: u64_at(80),
+        })
+    }
+
+    /// Check that both hashes are in their only valid spelling: 64 lowercase
+    /// hex digits. Other spellings would encode to the same bytes, giving a
+    /// different header with the same hash.
+    pub fn is_canonical(&self) -> bool {
+        is_canonical_hash(&self.prev_hash) && is_canonical_hash(&self.merkle_root)
+    }
+
+    /// SHA-256 hash of the binary encoding
+    pub fn hash(&self) -> String {
+        hex::encode(Sha256::digest(self.encode()))
+    }
+
+    /// Check if `hash`, read as a big-endian number, is at or below this header's target
+    pub fn meets_target(&self, hash: &str) -> bool {
+        pow::hash_meets_bits(hash, self.bits)
+    }
+
+    /// Expected number of hashes needed to produce this header
+    pub fn work(&self) -> U256 {
+        pow::work_from_bits(self.bits)
+    }
+}
+
+/// Check if a hash is 64 lowercase hex digits
+fn is_canonical_hash(hash: &str) -> bool {
+    hash.len() == 64 && hash.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// Raw bytes of a hex-encoded 32-byte hash; malformed hashes encode as zeros
+/// (headers containing them fail `BlockHeader::is_canonical`)
+fn hash_bytes(hash: &str) -> [u8; 32] {
+    let mut bytes = [0u8; 32];
+    if let Ok(decoded) = hex::decode(hash) {
+        if decoded.len() == 32 {
+            bytes.copy_from_slice(&decoded);
+        }
+    }
+    bytes
+}
+
 /// A block in the blockchain
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Block {
     pub index: u64,
-    pub timestamp: u64,
-    pub prev_hash: String,
+    pub header: BlockHeader,
     pub hash: String,
-    pub nonce: u64,
-    pub difficulty: usize,
     pub transactions: Vec<Transaction>,
 }
 
 impl Block {
     /// Create a new block (not yet mined)
-    pub fn new(index: u64, prev_hash: String, transactions: Vec<Transaction>, difficulty: usize) -> Self {
+    pub fn new(index: u64, prev_hash: String, transactions: Vec<Transaction>, bits: u32) -> Self {
         let timestamp = std::time::SystemTime::now()
             .duration_since(std::time::UNIX_EPOCH)
             .unwrap()
             .as_secs();
 
+        let header = BlockHeader {
+            version: BLOCK_VERSION,
+            prev_hash,
+            merkle_root: merkle::merkle_root(&[]),
+            timestamp,
+            bits,
+            nonce: 0,
+        };
+
         let mut block = Block {
             index,
-            timestamp,
-            prev_hash,
+            header,
             hash: String::new(),
-            nonce: 0,
-            difficulty,
             transactions,
         };
+        block.header.merkle_root = block.calculate_merkle_root();
         block.hash = block.calculate_hash();
         block
     }
 
-    /// Calculate SHA-256 hash of the block
-    pub fn calculate_hash(&self) -> String {
-        let tx_data: String = self
-            .transactions
-            .iter()
-            .map(|tx| tx.hash())
-            .collect::<Vec<String>>()
-            .join("");
+    /// Hashes of the block's transactions, in order
+    pub fn transaction_hashes(&self) -> Vec<String> {
+        self.transactions.iter().map(|tx| tx.hash()).collect()
+    }
+
+    /// Leaf hashes of the block's transactions, in order
+    pub fn leaf_hashes(&self) -> Vec<String> {
+        self.transactions.iter().map(|tx| tx.leaf_hash()).collect()
+    }
 
-        let data = format!(
-            "{}{}{}{}{}",
-            self.index, self.timestamp, self.prev_hash, self.nonce, tx_data
-        );
+    /// Merkle root over the block's transactions, signatures included
+    pub fn calculate_merkle_root(&self) -> String {
+        merkle::merkle_root(&self.leaf_hashes())
+    }
+
+    /// Build a proof that the transaction with `tx_hash` is part of this block
+    pub fn merkle_proof(&self, tx_hash: &str) -> Option<MerkleProof> {
+        let index = self.transaction_hashes().iter().position(|h| h == tx_hash)?;
+        MerkleProof::new(&self.leaf_hashes(), index)
+    }
+
+    /// Calculate SHA-256 hash of the block header
+    pub fn calculate_hash(&self) -> String {
+        self.header.hash()
+    }
 
-        let mut hasher = Sha256::new();
-        hasher.update(data.as_bytes());
-        hex::encode(hasher.finalize())
+    /// Total serialized size of the block's transactions in bytes
+    pub fn transactions_size(&self) -> usize {
+        self.transactions.iter().map(|tx| tx.size()).sum()
     }
 
-    /// Mine the block by finding a valid nonce
+    /// Mine the block by finding a nonce whose header hash is at or below the target
     pub fn mine(&mut self) {
-        let target = "0".repeat(self.difficulty);
+        let target = pow::target_from_bits(self.header.bits).unwrap_or_default();
         loop {
-            self.hash = self.calculate_hash();
-            if self.hash.starts_with(&target) {
+            let digest = Sha256::digest(self.header.encode());
+            if U256::from_big_endian(&digest) <= target {
+                self.hash = hex::encode(digest);
                 println!("Block {} mined! Hash: {}", self.index, self.hash);
                 break;
             }
-            self.nonce += 1;
+            self.header.nonce += 1;
         }
     }
 
     /// Verify if the block has valid proof of work
     pub fn is_valid_pow(&self) -> bool {
-        let target = "0".repeat(self.difficulty);
-        self.hash == self.calculate_hash() && self.hash.starts_with(&target)
+        self.hash == self.calculate_hash() && self.header.meets_target(&self.hash)
     }
 
     /// Create genesis block
-    pub fn genesis(difficulty: usize) -> Self {
-        let mut block = Block::new(0, String::from("0"), vec![], difficulty);
+    pub fn genesis(bits: u32) -> Self {
+        let mut block = Block::new(0, String::from(ZERO_HASH), vec![], bits);
         block.mine();
         block
     }
@@ -1,143 +1,1650 @@
-use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
 
-use crate::block::Block;
-use crate::transaction::Transaction;
+use crate::block::{Block, BlockHeader, BLOCK_VERSION, ZERO_HASH};
+use crate::difficulty;
+use crate::error::ValidationError;
+use crate::index::{ChainIndex, TxLocation};
+use crate::ledger::{LedgerState, PendingState};
+use crate::merkle::MerkleProof;
+use crate::network_time;
+use crate::params::ChainParams;
+use crate::pow::U256;
+use crate::storage::DiskStore;
+use crate::store::{ChainStore, MemoryStore};
+use crate::transaction::{OutPoint, Transaction};
 
-/// The blockchain - a chain of blocks
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct Blockchain {
-    pub chain: Vec<Block>,
-    pub difficulty: usize,
+/// Metadata key recording the ledger model a store was built with
+const LEDGER_METADATA: &str = "ledger";
+
+/// Fewest recent block bodies a pruned node keeps: reorganizations deeper
+/// than this can no longer be followed, as the undo data is gone
+pub const MIN_PRUNE_DEPTH: u64 = 10;
+
+/// Stored side blocks more than this many blocks below the active tip are
+/// deleted on load instead of being put back on their branches
+pub const MAX_SIDE_BLOCK_DEPTH: u64 = 100;
+
+/// Outcome of adding a block to the block tree
+#[derive(Debug, Clone)]
+pub enum BlockStatus {
+    /// The block extended the active chain
+    Connected,
+    /// The block was stored on a side branch with no more work than the active chain
+    SideBranch,
+    /// The block's branch overtook the active chain
+    Reorganized(Reorg),
+    /// The block was already known
+    AlreadyKnown,
 }
 
-impl Blockchain {
-    /// Create a new blockchain with genesis block
-    pub fn new(difficulty: usize) -> Self {
-        let genesis = Block::genesis(difficulty);
-        Blockchain {
-            chain: vec![genesis],
-            difficulty,
+/// Blocks switched during a chain reorganization
+#[derive(Debug, Clone)]
+pub struct Reorg {
+    /// Height of the last block both branches share (`None` if not even the genesis)
+    pub fork_height: Option<u64>,
+    /// Blocks removed from the active chain, tip first
+    pub disconnected: Vec<Block>,
+    /// Blocks added to the active chain, oldest first
+    pub connected: Vec<Block>,
+}
+
+/// Progress of checking a header chain that arrives in batches
+#[derive(Debug)]
+pub struct HeaderCheck {
+    /// Ancestry of the next header
+    ancestry: Ancestry,
+    /// Cumulative work of the branch up to the last checked header
+    work: U256,
+}
+
+impl HeaderCheck {
+    /// Cumulative work of the branch ending at the last checked header
+    pub fn work(&self) -> U256 {
+        self.work
+    }
+}
+
+/// Headers a new block is checked against
+#[derive(Debug)]
+struct Ancestry {
+    /// Height of the new block
+    height: u64,
+    /// The most recent headers before it, oldest first and ending with its parent
+    headers: Vec<BlockHeader>,
+}
+
+/// The blockchain - a tree of blocks whose branch with the most
+/// cumulative work forms the active chain. The active chain and ledger state
+/// live in a `ChainStore`; side branches are kept in memory.
+#[derive(Debug)]
+pub struct Blockchain<S: ChainStore = MemoryStore> {
+    /// Consensus rules this node validates with (local setting, never taken from peers)
+    pub params: ChainParams,
+    /// Active chain, undo data and ledger state
+    store: S,
+    /// Cumulative work up to and including each active chain block
+    chain_work: Vec<U256>,
+    /// Known blocks that are not on the active chain, by hash
+    side_blocks: HashMap<String, Block>,
+    /// Hashes of blocks that failed validation
+    invalid: HashSet<String>,
+    /// Seconds to add to the local clock to get network-adjusted time
+    time_offset: i64,
+    /// Number of recent block bodies to keep (`None` keeps every block)
+    prune_depth: Option<u64>,
+    /// Transaction and address index of the active chain, if enabled
+    index: Option<ChainIndex>,
+}
+
+impl Blockchain<MemoryStore> {
+    /// Create a new in-memory blockchain with genesis block
+    pub fn new(params: ChainParams) -> Self {
+        let mut blockchain = Blockchain::empty(params);
+        blockchain.create_genesis();
+        blockchain
+    }
+
+    /// Create an empty in-memory blockchain (for syncing from peers)
+    pub fn empty(params: ChainParams) -> Self {
+        Blockchain::with_store(params, MemoryStore::new()).expect("an empty in-memory store always loads")
+    }
+}
+
+impl Blockchain<DiskStore> {
+    /// Open the blockchain stored in `dir` (empty if nothing is stored yet)
+    pub fn open(params: ChainParams, dir: &Path) -> Result<Self, String> {
+        Blockchain::with_store(params, DiskStore::open(dir)?)
+    }
+}
+
+impl<S: ChainStore> Blockchain<S> {
+    /// Load a blockchain from a store, revalidating every stored block.
+    /// Blocks that fail validation are removed from the store with their descendants.
+    /// A pruned store keeps its ledger state, which can no longer be replayed.
+    /// Stored side branch blocks are checked and put back on their branches,
+    /// unless they are stale, orphaned or invalid, in which case they are deleted.
+    pub fn with_store(params: ChainParams, store: S) -> Result<Self, String> {
+        let model = params.ledger.to_string();
+        match store.metadata(LEDGER_METADATA) {
+            Some(stored) if stored != model.as_bytes() => {
+                return Err(format!(
+                    "Stored chain uses the {} ledger, not {}",
+                    String::from_utf8_lossy(&stored),
+                    model
+                ));
+            }
+            _ => {}
         }
+
+        let mut blockchain = Blockchain::from_parts(params, store);
+        blockchain.store.set_metadata(LEDGER_METADATA, model.as_bytes())?;
+        blockchain.reload()?;
+        blockchain.load_side_blocks();
+        Ok(blockchain)
     }
 
-    /// Create an empty blockchain (for syncing from peers)
-    pub fn empty(difficulty: usize) -> Self {
+    fn from_parts(params: ChainParams, store: S) -> Self {
         Blockchain {
-            chain: vec![],
-            difficulty,
+            params,
+            store,
+            chain_work: vec![],
+            side_blocks: HashMap::new(),
+            invalid: HashSet::new(),
+            time_offset: 0,
+            prune_depth: None,
+            index: None,
+        }
+    }
+
+    /// Replay the stored chain from genesis, rebuilding the ledger state and
+    /// chain work, and drop the stored blocks from the first invalid one on
+    fn reload(&mut self) -> Result<(), String> {
+        let pruned = self.store.pruned_height() > 0;
+        let mut state = LedgerState::new(self.params.ledger);
+        let mut work = U256::zero();
+        self.chain_work.clear();
+
+        let len = self.store.len();
+        for height in 0..len {
+            match self.check_stored_block(height, &state) {
+                Ok(header) => {
+                    if !pruned {
+                        state.apply_block(&self.store.block(height).unwrap());
+                    }
+                    work += header.work();
+                    self.chain_work.push(work);
+                }
+                Err(e) if height < self.store.pruned_height() => {
+                    return Err(format!("Stored header #{} is invalid: {}", height, e));
+                }
+                Err(e) => {
+                    eprintln!("Stored block #{} is invalid, dropping it and its descendants: {}", height, e);
+                    while self.store.len() > height {
+                        if let Some(block) = self.store.pop_block()? {
+                            self.store.remove_side_block(&block.hash)?;
+                        }
+                    }
+                    break;
+                }
+            }
+        }
+
+        if !pruned {
+            self.store.set_state(state)?;
+        } else if self.store.state().model() != self.params.ledger {
+            return Err(String::from("Stored ledger state does not match the ledger model"));
+        }
+        Ok(())
+    }
+
+    /// Put the stored blocks that are not on the active chain back on their
+    /// side branches, parents first, and switch to a branch with more work
+    /// (e.g. if the node stopped during a reorganization). Blocks too far
+    /// below the tip, whose parent is gone (pruned or invalid) or that fail
+    /// their checks are deleted from the store.
+    fn load_side_blocks(&mut self) {
+        let mut blocks = self.store.side_blocks();
+        blocks.sort_by_key(|block| block.index);
+        let stale_below = self.store.len().saturating_sub(MAX_SIDE_BLOCK_DEPTH).max(self.store.pruned_height());
+        for block in blocks {
+            let keep = if block.index < stale_below || !self.contains_block(&block.header.prev_hash) {
+                false
+            } else {
+                let ancestry = self.branch_ancestry(&block.header.prev_hash);
+                match self.check_context(&block, &ancestry) {
+                    Ok(()) => true,
+                    Err(e) => {
+                        eprintln!("Stored side block #{} is invalid: {}", block.index, e);
+                        false
+                    }
+                }
+            };
+            if keep {
+                self.side_blocks.insert(block.hash.clone(), block);
+            } else if let Err(e) = self.store.remove_side_block(&block.hash) {
+                eprintln!("Failed to delete stored side block #{}: {}", block.index, e);
+            }
+        }
+
+        let best = self.side_blocks.keys().max_by_key(|hash| self.branch_work(hash)).cloned();
+        if let Some(best) = best {
+            if self.branch_work(&best) > self.total_work() {
+                if let Err(e) = self.reorganize(&best) {
+                    eprintln!("Failed to switch to the stored branch with more work: {}", e);
+                }
+            }
+        }
+    }
+
+    /// Check the stored active chain block at `height` against its ancestors,
+    /// returning its header. Pruned blocks are checked by header only, and the
+    /// ledger rules (against `state`) only while the chain is unpruned.
+    fn check_stored_block(&self, height: u64, state: &LedgerState) -> Result<BlockHeader, ValidationError> {
+        let ancestry = self.ancestry_at(height);
+        let pruned_height = self.store.pruned_height();
+        if height < pruned_height {
+            let header = self.store.header(height).ok_or(ValidationError::EmptyChain)?;
+            self.check_header(&header, &ancestry)?;
+            return Ok(header);
+        }
+
+        let block = self.store.block(height).ok_or(ValidationError::EmptyChain)?;
+        if pruned_height > 0 {
+            self.check_context(&block, &ancestry)?;
+        } else {
+            self.check_block(&block, &ancestry, state)?;
+        }
+        Ok(block.header)
+    }
+
+    /// Keep only the bodies of the last `depth` blocks (`None` keeps every block).
+    /// Headers and the ledger state are always kept.
+    pub fn set_prune_depth(&mut self, depth: Option<u64>) -> Result<(), String> {
+        if let Some(depth) = depth {
+            if depth < MIN_PRUNE_DEPTH {
+                return Err(format!("Prune depth must be at least {} blocks", MIN_PRUNE_DEPTH));
+            }
+        }
+        self.prune_depth = depth;
+        self.prune();
+        Ok(())
+    }
+
+    /// Turn the transaction and address index on or off. Enabling it indexes
+    /// the stored block bodies; blocks pruned before that are not covered.
+    pub fn set_indexing(&mut self, enabled: bool) {
+        if !enabled {
+            self.index = None;
+            return;
+        }
+        if self.index.is_some() {
+            return;
+        }
+
+        let mut index = ChainIndex::new();
+        for height in self.store.pruned_height()..self.store.len() {
+            if let Some(block) = self.store.block(height) {
+                index.connect_block(&block);
+            }
+        }
+        self.index = Some(index);
+    }
+
+    /// The transaction and address index, if enabled
+    pub fn index(&self) -> Option<&ChainIndex> {
+        self.index.as_ref()
+    }
+
+    /// Location of an active chain transaction (requires the index)
+    pub fn transaction_location(&self, tx_hash: &str) -> Option<TxLocation> {
+        self.index.as_ref()?.transaction(tx_hash).cloned()
+    }
+
+    /// Look up an active chain transaction and its location (requires the
+    /// index, and the body of its block not to be pruned)
+    pub fn get_transaction(&self, tx_hash: &str) -> Option<(Transaction, TxLocation)> {
+        let location = self.transaction_location(tx_hash)?;
+        let block = self.store.block(location.height)?;
+        let tx = block.transactions.into_iter().nth(location.position as usize)?;
+        Some((tx, location))
+    }
+
+    /// Active chain transactions sending from or paying to an address, oldest
+    /// first, with their hashes (requires the index)
+    pub fn address_history(&self, address: &str) -> Vec<(String, TxLocation)> {
+        match &self.index {
+            Some(index) => index.address_history(address),
+            None => vec![],
+        }
+    }
+
+    /// Lowest height whose block body is still available
+    pub fn pruned_height(&self) -> u64 {
+        self.store.pruned_height()
+    }
+
+    /// Check if block bodies have been pruned
+    pub fn is_pruned(&self) -> bool {
+        self.store.pruned_height() > 0
+    }
+
+    /// Discard block bodies beyond the prune depth
+    fn prune(&mut self) {
+        if let Some(depth) = self.prune_depth {
+            let height = self.store.len().saturating_sub(depth);
+            if height > self.store.pruned_height() {
+                if let Err(e) = self.store.prune(height) {
+                    eprintln!("Failed to prune blocks below #{}: {}", height, e);
+                }
+            }
+        }
+    }
+
+    /// Mine and append the genesis block to an empty chain
+    pub fn create_genesis(&mut self) {
+        let genesis = Block::genesis(self.params.genesis_bits);
+        self.push_block(genesis);
+        self.prune();
+    }
+
+    /// The underlying store
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    /// Current ledger state (UTXO set or account balances)
+    pub fn state(&self) -> &LedgerState {
+        self.store.state()
+    }
+
+    /// Confirmed balance of an address
+    pub fn balance(&self, address: &str) -> u64 {
+        self.state().balance(address)
+    }
+
+    /// Build an unsigned transaction paying `amount` plus `fee` from `from` to `to`
+    pub fn create_transaction(&self, from: &str, to: &str, amount: u64, fee: u64) -> Result<Transaction, String> {
+        self.state().create_transaction(from, to, amount, fee)
+    }
+
+    /// Choose transactions for the next block: mempool transactions are taken in
+    /// order of fee rate (fee per byte) while they still fit in the block and
+    /// remain valid, followed by a coinbase collecting the subsidy and fees.
+    /// Transactions depending on a skipped parent are retried once it is included.
+    /// Each candidate is checked against the state plus the transactions
+    /// selected so far, without replaying them.
+    pub fn block_template(&self, mempool: &[Transaction], miner_address: &str) -> Vec<Transaction> {
+        let index = self.store.len();
+        let state = self.state();
+        let coinbase_size = Transaction::coinbase(miner_address.to_string(), 0, index).size();
+        let budget = self.params.max_block_size.saturating_sub(coinbase_size);
+
+        // Sort by fee rate, highest first; ties keep arrival order
+        let by_hash: HashMap<String, &Transaction> = mempool.iter().map(|tx| (tx.hash(), tx)).collect();
+        let mut candidates: Vec<(u64, usize, &Transaction)> = mempool
+            .iter()
+            .map(|tx| (state.fee(tx, &by_hash).unwrap_or(0), tx.size(), tx))
+            .collect();
+        candidates.sort_by(|a, b| (b.0 as u128 * a.1 as u128).cmp(&(a.0 as u128 * b.1 as u128)));
+
+        let mut selected: Vec<Transaction> = Vec::new();
+        let mut pending = PendingState::new(state.model());
+        let mut size = 0;
+        let mut fees: u64 = 0;
+        loop {
+            let mut progress = false;
+            candidates.retain(|(_, tx_size, tx)| {
+                if size + tx_size > budget {
+                    return true;
+                }
+                match state.add_pending(&mut pending, tx) {
+                    Ok(fee) => {
+                        selected.push((*tx).clone());
+                        size += tx_size;
+                        fees = fees.saturating_add(fee);
+                        progress = true;
+                        false
+                    }
+                    Err(_) => true,
+                }
+            });
+            if !progress {
+                break;
+            }
+        }
+
+        let reward = self.params.subsidy(index).saturating_add(fees);
+        selected.insert(0, Transaction::coinbase(miner_address.to_string(), reward, index));
+        selected
+    }
+
+    /// Find the active chain block containing a transaction and prove its inclusion
+    pub fn transaction_proof(&self, tx_hash: &str) -> Option<(Block, MerkleProof)> {
+        if let Some(index) = &self.index {
+            let block = self.store.block(index.transaction(tx_hash)?.height)?;
+            let proof = block.merkle_proof(tx_hash)?;
+            return Some((block, proof));
         }
+        (0..self.store.len())
+            .filter_map(|height| self.store.block(height))
+            .find_map(|block| block.merkle_proof(tx_hash).map(|proof| (block, proof)))
     }
 
     /// Get the latest block
-    pub fn last_block(&self) -> Option<&Block> {
-        self.chain.last()
+    pub fn last_block(&self) -> Option<Block> {
+        self.store.len().checked_sub(1).and_then(|height| self.store.block(height))
     }
 
-    /// Add a new block with transactions
-    pub fn add_block(&mut self, transactions: Vec<Transaction>) -> &Block {
-        let (index, prev_hash) = match self.last_block() {
-            Some(block) => (block.index + 1, block.hash.clone()),
-            None => (0, String::from("0")),
+    /// Hash of the latest block (`ZERO_HASH` for an empty chain)
+    pub fn tip_hash(&self) -> String {
+        self.store
+            .len()
+            .checked_sub(1)
+            .and_then(|height| self.store.hash_at(height))
+            .unwrap_or_else(|| String::from(ZERO_HASH))
+    }
+
+    /// Active chain block at `height`
+    pub fn block_at(&self, height: u64) -> Option<Block> {
+        self.store.block(height)
+    }
+
+    /// All blocks of the active chain, genesis first
+    pub fn blocks(&self) -> Vec<Block> {
+        (0..self.store.len()).filter_map(|height| self.store.block(height)).collect()
+    }
+
+    /// Check if a block is known, on the active chain or a side branch
+    pub fn contains_block(&self, hash: &str) -> bool {
+        self.store.height_of(hash).is_some() || self.side_blocks.contains_key(hash)
+    }
+
+    /// Look up a known block by hash
+    pub fn get_block(&self, hash: &str) -> Option<Block> {
+        match self.store.height_of(hash) {
+            Some(height) => self.store.block(height),
+            None => self.side_blocks.get(hash).cloned(),
+        }
+    }
+
+    /// Hashes describing the active chain to a peer: the last few blocks from
+    /// the tip back, then exponentially sparser ones, ending with the genesis
+    pub fn block_locator(&self) -> Vec<String> {
+        let mut locator = Vec::new();
+        let mut height = match self.store.len().checked_sub(1) {
+            Some(tip) => tip,
+            None => return locator,
         };
+        let mut step = 1;
+        loop {
+            locator.extend(self.store.hash_at(height));
+            if height == 0 {
+                break;
+            }
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+            height = height.saturating_sub(step);
+        }
+        locator
+    }
+
+    /// Active chain headers following the first locator hash found on the
+    /// active chain (from the genesis if none is), at most `max` of them
+    pub fn locate_headers(&self, locator: &[String], max: usize) -> Vec<BlockHeader> {
+        let start = locator
+            .iter()
+            .find_map(|hash| self.store.height_of(hash))
+            .map(|height| height + 1)
+            .unwrap_or(0);
+        (start..self.store.len())
+            .take(max)
+            .filter_map(|height| self.store.header(height))
+            .collect()
+    }
+
+    /// Check a chain of headers extending a known block: each must follow
+    /// the previous one and meet the timestamp, difficulty and proof-of-work
+    /// rules. Returns the cumulative work of the branch the headers end.
+    pub fn check_headers(&self, headers: &[BlockHeader]) -> Result<U256, ValidationError> {
+        let first = match headers.first() {
+            Some(first) => first,
+            None => return Ok(self.total_work()),
+        };
+        let mut check = self.start_headers(&first.prev_hash)?;
+        self.continue_headers(&mut check, headers)?;
+        Ok(check.work())
+    }
+
+    /// Start checking a header chain whose first header builds on the known block `parent`
+    pub fn start_headers(&self, parent: &str) -> Result<HeaderCheck, ValidationError> {
+        if parent != ZERO_HASH && !self.contains_block(parent) {
+            return Err(ValidationError::UnknownParent(parent.to_string()));
+        }
+        Ok(HeaderCheck {
+            ancestry: self.branch_ancestry(parent),
+            work: self.branch_work(parent),
+        })
+    }
 
-        let mut block = Block::new(index, prev_hash, transactions, self.difficulty);
+    /// Check the next headers of a chain, following those already checked
+    pub fn continue_headers(&self, check: &mut HeaderCheck, headers: &[BlockHeader]) -> Result<(), ValidationError> {
+        let lookback = self.lookback();
+        for header in headers {
+            let hash = header.hash();
+            if self.invalid.contains(&hash) {
+                return Err(ValidationError::KnownInvalid(hash));
+            }
+            self.check_header(header, &check.ancestry)?;
+            check.work += header.work();
+
+            let ancestry = &mut check.ancestry;
+            ancestry.height += 1;
+            ancestry.headers.push(header.clone());
+            if ancestry.headers.len() > lookback {
+                ancestry.headers.remove(0);
+            }
+        }
+        Ok(())
+    }
+
+    /// Number of known blocks on side branches
+    pub fn side_block_count(&self) -> usize {
+        self.side_blocks.len()
+    }
+
+    /// Add a new block with transactions
+    pub fn add_block(&mut self, transactions: Vec<Transaction>) -> Block {
+        let mut block = Block::new(self.store.len(), self.tip_hash(), transactions, self.next_bits());
+        block.header.timestamp = self.next_timestamp();
         block.mine();
-        self.chain.push(block);
-        self.chain.last().unwrap()
+        self.push_block(block.clone());
+        self.prune();
+        block
     }
 
-    /// Add an already mined block (received from network)
-    pub fn add_mined_block(&mut self, block: Block) -> bool {
-        if self.is_valid_new_block(&block) {
-            self.chain.push(block);
-            true
+    /// Add an already mined block (received from network or mined locally).
+    /// Blocks extending the tip are connected; blocks building on another known
+    /// block are kept on a side branch, and the active chain switches to that
+    /// branch as soon as it has more cumulative work.
+    pub fn add_mined_block(&mut self, block: Block) -> Result<BlockStatus, ValidationError> {
+        if self.contains_block(&block.hash) {
+            return Ok(BlockStatus::AlreadyKnown);
+        }
+        // Keyed on the header hash: the block's own hash field is unchecked here
+        if !block.header.is_canonical() {
+            return Err(ValidationError::NonCanonicalHeader);
+        }
+        let header_hash = block.header.hash();
+        if self.invalid.contains(&header_hash) || self.invalid.contains(&block.header.prev_hash) {
+            self.invalid.insert(header_hash.clone());
+            return Err(ValidationError::KnownInvalid(header_hash));
+        }
+        // Not remembered as invalid: the block may become acceptable later
+        self.check_future_drift(block.header.timestamp)?;
+
+        if block.header.prev_hash == self.tip_hash() {
+            self.connect_block(block)?;
+            self.prune();
+            return Ok(BlockStatus::Connected);
+        }
+
+        if block.header.prev_hash != ZERO_HASH && !self.contains_block(&block.header.prev_hash) {
+            return Err(ValidationError::UnknownParent(block.header.prev_hash));
+        }
+
+        // Side branch: everything except the ledger rules can be checked now,
+        // the rest is checked if the branch ever becomes active
+        let ancestry = self.branch_ancestry(&block.header.prev_hash);
+        if let Err(e) = self.check_context(&block, &ancestry) {
+            self.record_failure(&block, &e);
+            return Err(e);
+        }
+
+        let hash = block.hash.clone();
+        if let Err(e) = self.store.put_side_block(&block) {
+            eprintln!("Failed to store block #{}: {}", block.index, e);
+        }
+        self.side_blocks.insert(hash.clone(), block);
+
+        if self.branch_work(&hash) > self.total_work() {
+            self.reorganize(&hash).map(BlockStatus::Reorganized)
         } else {
-            false
+            Ok(BlockStatus::SideBranch)
+        }
+    }
+
+    /// Validate a block on top of the tip and append it, remembering it as
+    /// invalid if its header fails
+    fn connect_block(&mut self, block: Block) -> Result<(), ValidationError> {
+        let ancestry = self.ancestry_at(self.store.len());
+        if let Err(e) = self.check_block(&block, &ancestry, self.store.state()) {
+            self.record_failure(&block, &e);
+            return Err(e);
+        }
+        self.push_block(block);
+        Ok(())
+    }
+
+    /// Remember a block as invalid if `error` is a verdict on its header.
+    /// Failures of the rest of the block are not remembered: a peer could
+    /// send a mangled copy of a valid block to get the real one refused.
+    fn record_failure(&mut self, block: &Block, error: &ValidationError) {
+        if error.is_header_failure() {
+            self.invalid.insert(block.header.hash());
+        }
+    }
+
+    /// Append a block to the active chain without validating it or pruning.
+    /// Storage failures are fatal: the ledger state would no longer match the store.
+    fn push_block(&mut self, block: Block) {
+        let work = self.total_work() + block.header.work();
+        if let Err(e) = self.store.push_block(&block) {
+            panic!("Failed to store block #{}: {}", block.index, e);
+        }
+        self.chain_work.push(work);
+        if let Some(index) = &mut self.index {
+            index.connect_block(&block);
+        }
+    }
+
+    /// Remove the tip of the active chain, reverting its ledger changes
+    fn pop_block(&mut self) -> Option<Block> {
+        let block = match self.store.pop_block() {
+            Ok(popped) => popped?,
+            Err(e) => panic!("Failed to remove the chain tip from storage: {}", e),
+        };
+        self.chain_work.pop();
+        if let Some(index) = &mut self.index {
+            index.disconnect_block(&block);
+        }
+        Some(block)
+    }
+
+    /// Cumulative work of the branch ending at a known block (zero if unknown)
+    pub fn branch_work(&self, hash: &str) -> U256 {
+        let mut work = U256::zero();
+        let mut cursor = hash;
+        while let Some(block) = self.side_blocks.get(cursor) {
+            work += block.header.work();
+            cursor = &block.header.prev_hash;
+        }
+        match self.store.height_of(cursor) {
+            Some(height) => work + self.chain_work[height as usize],
+            None => work,
         }
     }
 
+    /// Switch the active chain to the side branch ending at `new_tip`.
+    /// If a block of the branch turns out to be invalid, the branch from that
+    /// block on is discarded and the previous active chain is restored.
+    fn reorganize(&mut self, new_tip: &str) -> Result<Reorg, ValidationError> {
+        // Pruning waits until the switch is done or undone: undoing it
+        // disconnects the branch blocks connected so far, bodies included
+        let result = self.switch_to_branch(new_tip);
+        self.prune();
+        result
+    }
+
+    /// Disconnect the active chain down to the fork point and connect the
+    /// branch ending at `new_tip`, restoring the old chain on failure
+    fn switch_to_branch(&mut self, new_tip: &str) -> Result<Reorg, ValidationError> {
+        // Walk back to the fork point
+        let mut branch = Vec::new();
+        let mut cursor = new_tip.to_string();
+        while let Some(block) = self.side_blocks.get(&cursor) {
+            branch.push(cursor.clone());
+            cursor = block.header.prev_hash.clone();
+        }
+        branch.reverse();
+        let fork_height = self.store.height_of(&cursor);
+        let keep = fork_height.map(|h| h + 1).unwrap_or(0);
+        // Not remembered as invalid: the branch is fine, we just cannot follow it
+        if keep < self.store.pruned_height() {
+            return Err(ValidationError::ReorgTooDeep {
+                fork_height,
+                pruned_height: self.store.pruned_height(),
+            });
+        }
+
+        let mut disconnected = Vec::new();
+        while self.store.len() > keep {
+            disconnected.extend(self.pop_block());
+        }
+
+        let mut connected = Vec::new();
+        for (i, hash) in branch.iter().enumerate() {
+            let block = self.side_blocks.remove(hash).unwrap();
+            connected.push(block.clone());
+            match self.connect_block(block) {
+                Ok(()) => {}
+                Err(e) => {
+                    // Drop everything built on the failed block in this branch,
+                    // remembering it as invalid only if the block's header is.
+                    // The stored copies go too, so they are not retried on restart.
+                    for hash in &branch[i + 1..] {
+                        self.side_blocks.remove(hash);
+                        if e.is_header_failure() {
+                            self.invalid.insert(hash.clone());
+                        }
+                    }
+                    for hash in &branch[i..] {
+                        if let Err(e) = self.store.remove_side_block(hash) {
+                            eprintln!("Failed to delete stored block {}: {}", hash, e);
+                        }
+                    }
+
+                    // Restore the previous active chain
+                    connected.pop();
+                    while self.store.len() > keep {
+                        let block = self.pop_block().unwrap();
+                        self.side_blocks.insert(block.hash.clone(), block);
+                    }
+                    for block in disconnected.into_iter().rev() {
+                        self.push_block(block);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+
+        // The old active blocks stay known as a side branch
+        for block in &disconnected {
+            self.side_blocks.insert(block.hash.clone(), block.clone());
+        }
+
+        Ok(Reorg {
+            fork_height,
+            disconnected,
+            connected,
+        })
+    }
+
+    /// Number of ancestors the contextual checks look back over
+    fn lookback(&self) -> usize {
+        (self.params.difficulty_window as usize + 1).max(self.params.median_time_span)
+    }
+
+    /// Ancestry of a block at `height` on the active chain
+    fn ancestry_at(&self, height: u64) -> Ancestry {
+        let start = height.saturating_sub(self.lookback() as u64);
+        Ancestry {
+            height,
+            headers: (start..height).filter_map(|h| self.store.header(h)).collect(),
+        }
+    }
+
+    /// Ancestry of a block whose parent is the known block `parent_hash`
+    fn branch_ancestry(&self, parent_hash: &str) -> Ancestry {
+        let height = match self.side_blocks.get(parent_hash) {
+            Some(parent) => parent.index + 1,
+            None => self.store.height_of(parent_hash).map(|h| h + 1).unwrap_or(0),
+        };
+
+        let lookback = self.lookback();
+        let mut headers = Vec::new();
+        let mut cursor = parent_hash;
+        while headers.len() < lookback {
+            if let Some(block) = self.side_blocks.get(cursor) {
+                headers.push(block.header.clone());
+                cursor = &block.header.prev_hash;
+                continue;
+            }
+            if let Some(parent) = self.store.height_of(cursor) {
+                let needed = (lookback - headers.len()) as u64;
+                let start = (parent + 1).saturating_sub(needed);
+                headers.extend((start..=parent).rev().filter_map(|h| self.store.header(h)));
+            }
+            break;
+        }
+        headers.reverse();
+        Ancestry { height, headers }
+    }
+
     /// Check if a new block is valid
     pub fn is_valid_new_block(&self, block: &Block) -> bool {
-        let (expected_index, expected_prev_hash) = match self.last_block() {
-            Some(last) => (last.index + 1, &last.hash),
-            None => (0, &String::from("0")),
-        };
+        self.validate_new_block(block).is_ok()
+    }
 
+    /// Check a new block against the current tip, returning why it is invalid
+    pub fn validate_new_block(&self, block: &Block) -> Result<(), ValidationError> {
+        self.check_future_drift(block.header.timestamp)?;
+        self.check_block(block, &self.ancestry_at(self.store.len()), self.store.state())
+    }
+
+    /// Set how far network-adjusted time is ahead of the local clock, in seconds
+    pub fn set_time_offset(&mut self, offset: i64) {
+        self.time_offset = offset;
+    }
+
+    /// Current network-adjusted time
+    pub fn adjusted_time(&self) -> u64 {
+        network_time::local_time().saturating_add_signed(self.time_offset)
+    }
+
+    /// Median timestamp of the last `median_time_span` blocks of the active chain
+    pub fn median_time_past(&self) -> Option<u64> {
+        self.median_time(&self.ancestry_at(self.store.len()))
+    }
+
+    /// Timestamp for a new block on top of the chain: network-adjusted time,
+    /// but always after the median time past
+    pub fn next_timestamp(&self) -> u64 {
+        let now = self.adjusted_time();
+        match self.median_time_past() {
+            Some(median) => now.max(median + 1),
+            None => now,
+        }
+    }
+
+    /// Median timestamp of the last `median_time_span` headers of an ancestry
+    fn median_time(&self, ancestry: &Ancestry) -> Option<u64> {
+        let span = self.params.median_time_span.max(1);
+        let headers = &ancestry.headers;
+        let mut timestamps: Vec<u64> = headers[headers.len().saturating_sub(span)..]
+            .iter()
+            .map(|h| h.timestamp)
+            .collect();
+        timestamps.sort_unstable();
+        timestamps.get(timestamps.len() / 2).copied()
+    }
+
+    /// Reject timestamps too far ahead of network-adjusted time
+    fn check_future_drift(&self, timestamp: u64) -> Result<(), ValidationError> {
+        let max = self.adjusted_time().saturating_add(self.params.max_future_drift);
+        if timestamp > max {
+            return Err(ValidationError::TimestampTooNew { timestamp, max });
+        }
+        Ok(())
+    }
+
+    /// Check a transaction for mempool admission: it must be signed by its sender
+    /// and spendable on top of the chain state plus the `pending` transactions,
+    /// which it is then added to
+    pub fn validate_transaction(&self, tx: &Transaction, pending: &mut PendingState) -> Result<(), ValidationError> {
+        if tx.is_coinbase() {
+            return Err(ValidationError::InvalidSpend(format!(
+                "coinbase transaction {} outside a block",
+                tx.hash()
+            )));
+        }
+
+        if !tx.verify() {
+            return Err(ValidationError::BadSignature(tx.hash()));
+        }
+
+        self.state()
+            .add_pending(pending, tx)
+            .map_err(ValidationError::InvalidSpend)?;
+        Ok(())
+    }
+
+    /// Compact target the next block on top of the chain must meet
+    pub fn next_bits(&self) -> u32 {
+        self.required_bits(&self.ancestry_at(self.store.len()))
+    }
+
+    /// Compact target required for a block with the given ancestry
+    fn required_bits(&self, ancestry: &Ancestry) -> u32 {
+        let headers: Vec<&BlockHeader> = ancestry.headers.iter().collect();
+        difficulty::next_bits(&self.params, ancestry.height, &headers)
+    }
+
+    /// Check a block with the given ancestry against the ledger state after its parent
+    fn check_block(&self, block: &Block, ancestry: &Ancestry, state: &LedgerState) -> Result<(), ValidationError> {
+        self.check_context(block, ancestry)?;
+
+        // Check that every transaction is covered by coins its sender owns
+        let fees = state
+            .check_transactions(&block.transactions)
+            .map_err(ValidationError::InvalidSpend)?;
+
+        self.check_coinbase(block, fees)
+    }
+
+    /// Check everything about a block that does not depend on the ledger state:
+    /// its position after its ancestry, header, timestamp, proof of work,
+    /// merkle root, size and signatures
+    fn check_context(&self, block: &Block, ancestry: &Ancestry) -> Result<(), ValidationError> {
         // Check index
-        if block.index != expected_index {
-            return false;
+        if block.index != ancestry.height {
+            return Err(ValidationError::BadIndex {
+                expected: ancestry.height,
+                found: block.index,
+            });
         }
 
-        // Check previous hash
-        if &block.prev_hash != expected_prev_hash {
-            return false;
+        self.check_header(&block.header, ancestry)?;
+
+        // Check the block's hash is the one its header commits to
+        if block.hash != block.calculate_hash() {
+            return Err(ValidationError::BadHash);
         }
 
-        // Check proof of work
-        if !block.is_valid_pow() {
-            return false;
+        // Check that the header commits to exactly these transactions
+        if block.header.merkle_root != block.calculate_merkle_root() {
+            return Err(ValidationError::BadMerkleRoot);
+        }
+
+        // Check block size
+        let size = block.transactions_size();
+        if size > self.params.max_block_size {
+            return Err(ValidationError::BlockTooLarge {
+                size,
+                max: self.params.max_block_size,
+            });
+        }
+
+        // Check every transaction signature
+        if let Some(tx) = block.transactions.iter().find(|tx| !tx.verify()) {
+            return Err(ValidationError::BadSignature(tx.hash()));
         }
 
-        true
+        Ok(())
     }
 
-    /// Validate the entire blockchain
-    pub fn is_valid(&self) -> bool {
-        if self.chain.is_empty() {
-            return false;
+    /// Check a header against its ancestry: parent, version, timestamp,
+    /// difficulty and proof of work
+    fn check_header(&self, header: &BlockHeader, ancestry: &Ancestry) -> Result<(), ValidationError> {
+        // Check the header has no second spelling with the same hash
+        if !header.is_canonical() {
+            return Err(ValidationError::NonCanonicalHeader);
         }
 
-        // Check genesis block
-        let genesis = &self.chain[0];
-        if genesis.index != 0 || genesis.prev_hash != "0" {
-            return false;
+        let expected_prev_hash = match ancestry.headers.last() {
+            Some(parent) => parent.hash(),
+            None => String::from(ZERO_HASH),
+        };
+
+        // Check previous hash
+        if header.prev_hash != expected_prev_hash {
+            return Err(ValidationError::BadPrevHash);
         }
 
-        if !genesis.is_valid_pow() {
-            return false;
+        // Check header version
+        if header.version != BLOCK_VERSION {
+            return Err(ValidationError::UnsupportedVersion(header.version));
         }
 
-        // Check each subsequent block
-        for i in 1..self.chain.len() {
-            let block = &self.chain[i];
-            let prev_block = &self.chain[i - 1];
+        // Check the timestamp is after the median of the previous blocks
+        if let Some(median) = self.median_time(ancestry) {
+            if header.timestamp <= median {
+                return Err(ValidationError::TimestampTooOld {
+                    timestamp: header.timestamp,
+                    median,
+                });
+            }
+        }
 
-            // Check index continuity
-            if block.index != prev_block.index + 1 {
-                return false;
+        // Check the header commits to the target required by the retargeting rules
+        if header.bits != self.required_bits(ancestry) {
+            return Err(ValidationError::BadDifficulty);
+        }
+
+        // Check proof of work
+        if !header.meets_target(&header.hash()) {
+            return Err(ValidationError::BadProofOfWork);
+        }
+
+        Ok(())
+    }
+
+    /// Check the coinbase rules: the genesis block has no transactions, every other
+    /// block starts with exactly one coinbase claiming at most the subsidy plus fees
+    fn check_coinbase(&self, block: &Block, fees: u64) -> Result<(), ValidationError> {
+        if block.index == 0 {
+            if !block.transactions.is_empty() {
+                return Err(ValidationError::BadCoinbase(String::from(
+                    "genesis block must not contain transactions",
+                )));
             }
+            return Ok(());
+        }
 
-            // Check hash chain
-            if block.prev_hash != prev_block.hash {
-                return false;
+        let coinbase = match block.transactions.first() {
+            Some(tx) if tx.is_coinbase() => tx,
+            _ => {
+                return Err(ValidationError::BadCoinbase(String::from(
+                    "first transaction is not a coinbase",
+                )))
             }
+        };
+
+        if block.transactions[1..].iter().any(|tx| tx.is_coinbase()) {
+            return Err(ValidationError::BadCoinbase(String::from("more than one coinbase")));
+        }
+
+        if coinbase.inputs != [OutPoint::coinbase(block.index)] {
+            return Err(ValidationError::BadCoinbase(String::from(
+                "coinbase does not commit to the block height",
+            )));
+        }
+
+        let subsidy = self.params.subsidy(block.index);
+        let claimed = coinbase.output_total().unwrap_or(u64::MAX);
+        if claimed > subsidy.saturating_add(fees) {
+            return Err(ValidationError::BadCoinbase(format!(
+                "coinbase claims {} but subsidy plus fees is {}",
+                claimed,
+                subsidy + fees
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Validate the entire blockchain
+    pub fn is_valid(&self) -> bool {
+        self.validate().is_ok()
+    }
+
+    /// Validate the entire blockchain from genesis, returning the first problem found.
+    /// Once pruned, only headers and the remaining bodies can be checked.
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        if self.store.is_empty() {
+            return Err(ValidationError::EmptyChain);
+        }
 
-            // Check proof of work
-            if !block.is_valid_pow() {
-                return false;
+        let pruned = self.is_pruned();
+        let mut state = LedgerState::new(self.params.ledger);
+        for height in 0..self.store.len() {
+            self.check_stored_block(height, &state)?;
+            if !pruned {
+                state.apply_block(&self.store.block(height).ok_or(ValidationError::EmptyChain)?);
             }
         }
 
-        true
+        Ok(())
+    }
+
+    /// Total proof of work of the active chain
+    pub fn total_work(&self) -> U256 {
+        self.chain_work.last().copied().unwrap_or_else(U256::zero)
     }
 
     /// Get chain length
     pub fn len(&self) -> usize {
-        self.chain.len()
+        self.store.len() as usize
     }
 
     /// Check if chain is empty
     pub fn is_empty(&self) -> bool {
-        self.chain.is_empty()
+        self.store.is_empty()
     }
 }
 
-impl std::fmt::Display for Blockchain {
+impl<S: ChainStore> std::fmt::Display for Blockchain<S> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        writeln!(f, "Blockchain (difficulty: {}, blocks: {})", self.difficulty, self.len())?;
-        for block in &self.chain {
+        writeln!(
+            f,
+            "Blockchain (next bits: {:08x}, ledger: {}, blocks: {})",
+            self.next_bits(),
+            self.params.ledger,
+            self.len()
+        )?;
+        for block in self.blocks() {
             writeln!(f, "  {}", block)?;
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::address::Wallet;
+    use crate::pow;
+    use crate::storage::{reopen, test_dir, test_params};
+    use crate::transaction::TxOutput;
+
+    /// Mine a block at `index` on top of `parent` with `bits`, timestamped at
+    /// `timestamp`, without adding it
+    fn mine_block(index: u64, parent: &str, transactions: Vec<Transaction>, bits: u32, timestamp: u64) -> Block {
+        let mut block = Block::new(index, parent.to_string(), transactions, bits);
+        block.header.timestamp = timestamp;
+        block.mine();
+        block
+    }
+
+    /// Mine a block with `transactions` on top of the active chain without adding it
+    fn mine_with<S: ChainStore>(blockchain: &Blockchain<S>, transactions: Vec<Transaction>) -> Block {
+        let (index, tip) = (blockchain.len() as u64, blockchain.tip_hash());
+        mine_block(index, &tip, transactions, blockchain.next_bits(), blockchain.next_timestamp())
+    }
+
+    /// Mine the next block on top of the active chain without adding it
+    fn mine_next<S: ChainStore>(blockchain: &Blockchain<S>, miner: &str) -> Block {
+        mine_with(blockchain, blockchain.block_template(&[], miner))
+    }
+
+    /// Mine a block at `index` on top of the known block `parent`, which need not
+    /// be the tip, paying `reward` to `miner`
+    fn mine_on<S: ChainStore>(blockchain: &Blockchain<S>, parent: &str, index: u64, miner: &str, reward: u64) -> Block {
+        let coinbase = Transaction::coinbase(miner.to_string(), reward, index);
+        let parent_time = blockchain.get_block(parent).unwrap().header.timestamp;
+        let timestamp = blockchain.next_timestamp().max(parent_time + 1);
+        mine_block(index, parent, vec![coinbase], blockchain.next_bits(), timestamp)
+    }
+
+    #[test]
+    fn reorganization_switches_ledger_to_the_branch_with_more_work() {
+        let (a, b) = (Wallet::generate().address, Wallet::generate().address);
+        let mut blockchain = Blockchain::new(test_params());
+        let genesis = blockchain.tip_hash();
+        let subsidy = blockchain.params.subsidy(1);
+
+        let a1 = mine_on(&blockchain, &genesis, 1, &a, subsidy);
+        blockchain.add_mined_block(a1.clone()).unwrap();
+        let b1 = mine_on(&blockchain, &genesis, 1, &b, subsidy);
+        assert!(matches!(blockchain.add_mined_block(b1.clone()), Ok(BlockStatus::SideBranch)));
+        assert_eq!(blockchain.balance(&a), subsidy);
+
+        let b2 = mine_on(&blockchain, &b1.hash, 2, &b, subsidy);
+        let reorg = match blockchain.add_mined_block(b2.clone()) {
+            Ok(BlockStatus::Reorganized(reorg)) => reorg,
+            other => panic!("expected a reorganization, got {:?}", other),
+        };
+        assert_eq!(reorg.fork_height, Some(0));
+        assert_eq!(reorg.disconnected.iter().map(|b| &b.hash).collect::<Vec<_>>(), vec![&a1.hash]);
+        assert_eq!(reorg.connected.iter().map(|b| &b.hash).collect::<Vec<_>>(), vec![&b1.hash, &b2.hash]);
+
+        assert_eq!(blockchain.tip_hash(), b2.hash);
+        assert_eq!(blockchain.balance(&a), 0);
+        assert_eq!(blockchain.balance(&b), 2 * subsidy);
+        // The old active block stays known on a side branch
+        assert!(blockchain.contains_block(&a1.hash));
+        assert!(blockchain.validate().is_ok());
+    }
+
+    #[test]
+    fn failed_reorganization_restores_the_previous_chain() {
+        let (a, b) = (Wallet::generate().address, Wallet::generate().address);
+        let mut blockchain = Blockchain::new(test_params());
+        let genesis = blockchain.tip_hash();
+        let subsidy = blockchain.params.subsidy(1);
+
+        let a1 = mine_on(&blockchain, &genesis, 1, &a, subsidy);
+        blockchain.add_mined_block(a1.clone()).unwrap();
+        let b1 = mine_on(&blockchain, &genesis, 1, &b, subsidy);
+        blockchain.add_mined_block(b1.clone()).unwrap();
+
+        // Claims more than the subsidy: only caught once the branch is connected
+        let b2 = mine_on(&blockchain, &b1.hash, 2, &b, subsidy + 1);
+        assert!(matches!(
+            blockchain.add_mined_block(b2.clone()),
+            Err(ValidationError::BadCoinbase(_))
+        ));
+
+        assert_eq!(blockchain.tip_hash(), a1.hash);
+        assert_eq!(blockchain.len(), 2);
+        assert_eq!(blockchain.balance(&a), subsidy);
+        assert_eq!(blockchain.balance(&b), 0);
+        assert!(blockchain.contains_block(&b1.hash));
+        assert!(!blockchain.contains_block(&b2.hash));
+        assert!(blockchain.validate().is_ok());
+
+        // A body failure is not a verdict on the header: the block is not remembered
+        assert!(matches!(
+            blockchain.add_mined_block(b2),
+            Err(ValidationError::BadCoinbase(_))
+        ));
+    }
+
+    /// Chain of `blocks` valid blocks after the genesis block
+    fn mined_chain(blocks: usize) -> Blockchain {
+        let miner = Wallet::generate().address;
+        let mut blockchain = Blockchain::new(test_params());
+        for _ in 0..blocks {
+            let block = mine_next(&blockchain, &miner);
+            blockchain.add_mined_block(block).unwrap();
+        }
+        blockchain
+    }
+
+    #[test]
+    fn headers_checked_in_batches_match_a_single_check() {
+        let source = mined_chain(5);
+        let mut blockchain = Blockchain::empty(test_params());
+        blockchain.add_mined_block(source.block_at(0).unwrap()).unwrap();
+        let headers: Vec<BlockHeader> = source.blocks()[1..].iter().map(|b| b.header.clone()).collect();
+
+        let mut check = blockchain.start_headers(&headers[0].prev_hash).unwrap();
+        for batch in headers.chunks(2) {
+            blockchain.continue_headers(&mut check, batch).unwrap();
+        }
+        assert_eq!(check.work(), source.total_work());
+        assert_eq!(blockchain.check_headers(&headers), Ok(source.total_work()));
+
+        // A later batch that does not follow the earlier ones fails
+        let mut check = blockchain.start_headers(&headers[0].prev_hash).unwrap();
+        blockchain.continue_headers(&mut check, &headers[..2]).unwrap();
+        assert_eq!(
+            blockchain.continue_headers(&mut check, &headers[3..]),
+            Err(ValidationError::BadPrevHash)
+        );
+    }
+
+    #[test]
+    fn stored_side_blocks_are_reloaded() {
+        let dir = test_dir("side");
+        let side = {
+            let mut blockchain = Blockchain::open(test_params(), &dir).unwrap();
+            blockchain.create_genesis();
+            let active = mine_next(&blockchain, &Wallet::generate().address);
+            let side = mine_next(&blockchain, &Wallet::generate().address);
+            blockchain.add_mined_block(active).unwrap();
+            assert!(matches!(blockchain.add_mined_block(side.clone()), Ok(BlockStatus::SideBranch)));
+            side
+        };
+
+        let mut blockchain = reopen(|| Blockchain::open(test_params(), &dir));
+        assert_eq!(blockchain.side_block_count(), 1);
+        assert!(blockchain.contains_block(&side.hash));
+
+        // The reloaded branch can still overtake the active chain
+        let next = mine_on(&blockchain, &side.hash, 2, &Wallet::generate().address, 50);
+        assert!(matches!(blockchain.add_mined_block(next), Ok(BlockStatus::Reorganized(_))));
+        assert_eq!(blockchain.block_at(1).unwrap().hash, side.hash);
+
+        drop(blockchain);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn stale_and_orphaned_side_blocks_are_deleted_on_load() {
+        let dir = test_dir("stale-side");
+        let miner = Wallet::generate().address;
+        {
+            let mut blockchain = Blockchain::open(test_params(), &dir).unwrap();
+            blockchain.create_genesis();
+            let genesis = blockchain.tip_hash();
+            let subsidy = blockchain.params.subsidy(1);
+            let side = mine_on(&blockchain, &genesis, 1, &Wallet::generate().address, subsidy);
+            blockchain.add_mined_block(mine_next(&blockchain, &miner)).unwrap();
+            blockchain.add_mined_block(side).unwrap();
+
+            // Stored without a known parent
+            let coinbase = Transaction::coinbase(miner.clone(), subsidy, 5);
+            let (bits, timestamp) = (blockchain.next_bits(), blockchain.next_timestamp());
+            let orphan = mine_block(5, &"ab".repeat(32), vec![coinbase], bits, timestamp);
+            blockchain.store.put_side_block(&orphan).unwrap();
+            assert_eq!(blockchain.store.side_blocks().len(), 2);
+
+            for _ in 0..MAX_SIDE_BLOCK_DEPTH {
+                blockchain.add_block(blockchain.block_template(&[], &miner));
+            }
+        }
+
+        let blockchain = reopen(|| Blockchain::open(test_params(), &dir));
+        assert_eq!(blockchain.side_block_count(), 0);
+        assert!(blockchain.store.side_blocks().is_empty());
+
+        drop(blockchain);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn stored_blocks_failing_the_ledger_rules_are_not_retried() {
+        let dir = test_dir("invalid-side");
+        let miner = Wallet::generate().address;
+        let (first, greedy) = {
+            let mut blockchain = Blockchain::open(test_params(), &dir).unwrap();
+            blockchain.create_genesis();
+            let genesis = blockchain.tip_hash();
+            let subsidy = blockchain.params.subsidy(1);
+            blockchain.add_mined_block(mine_next(&blockchain, &miner)).unwrap();
+
+            // A branch with more work whose second block claims too much
+            let first = mine_on(&blockchain, &genesis, 1, &Wallet::generate().address, subsidy);
+            blockchain.add_mined_block(first.clone()).unwrap();
+            let greedy = mine_on(&blockchain, &first.hash, 2, &miner, subsidy + 1);
+            assert!(matches!(
+                blockchain.add_mined_block(greedy.clone()),
+                Err(ValidationError::BadCoinbase(_))
+            ));
+            (first, greedy)
+        };
+
+        let blockchain = reopen(|| Blockchain::open(test_params(), &dir));
+        assert_eq!(blockchain.len(), 2);
+        assert!(blockchain.contains_block(&first.hash));
+        assert!(!blockchain.contains_block(&greedy.hash));
+        let stored: Vec<String> = blockchain.store.side_blocks().into_iter().map(|b| b.hash).collect();
+        assert_eq!(stored, [first.hash]);
+
+        drop(blockchain);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn pruned_chain_keeps_recent_bodies_and_refuses_deep_reorganizations() {
+        let miner = Wallet::generate().address;
+        let mut blockchain = Blockchain::new(test_params());
+        assert!(blockchain.set_prune_depth(Some(MIN_PRUNE_DEPTH - 1)).is_err());
+        blockchain.set_prune_depth(Some(MIN_PRUNE_DEPTH)).unwrap();
+        let genesis = blockchain.block_at(0).unwrap();
+        for _ in 0..MIN_PRUNE_DEPTH + 4 {
+            blockchain.add_block(blockchain.block_template(&[], &miner));
+        }
+
+        assert!(blockchain.is_pruned());
+        assert_eq!(blockchain.pruned_height(), 5);
+        assert!(blockchain.block_at(4).is_none());
+        assert!(blockchain.block_at(5).is_some());
+        assert_eq!(blockchain.balance(&miner), (MIN_PRUNE_DEPTH + 4) * blockchain.params.subsidy(1));
+
+        // A longer branch forking off the genesis block can no longer be followed
+        let mut parent = genesis;
+        let mut result = Ok(BlockStatus::SideBranch);
+        for index in 1..=blockchain.len() as u64 {
+            let coinbase = Transaction::coinbase(Wallet::generate().address, 0, index);
+            let timestamp = parent.header.timestamp + 1;
+            let block = mine_block(index, &parent.hash, vec![coinbase], blockchain.next_bits(), timestamp);
+            result = blockchain.add_mined_block(block.clone());
+            parent = block;
+        }
+        assert!(matches!(result, Err(ValidationError::ReorgTooDeep { .. })));
+        assert_eq!(blockchain.pruned_height(), 5);
+    }
+
+    #[test]
+    fn failed_reorganization_longer_than_the_prune_depth_restores_the_chain() {
+        // Slow branch blocks get easier down to the limit, so the branch needs
+        // many more blocks than the active chain to overtake it
+        let params = ChainParams {
+            pow_limit_bits: pow::bits_for_leading_zeros(0),
+            difficulty_window: 1,
+            ..test_params()
+        };
+        let miner = Wallet::generate().address;
+        let mut blockchain = Blockchain::new(params.clone());
+        blockchain.set_prune_depth(Some(MIN_PRUNE_DEPTH)).unwrap();
+        let genesis = blockchain.block_at(0).unwrap();
+        for index in 1..=3 {
+            let coinbase = Transaction::coinbase(miner.clone(), params.subsidy(index), index);
+            let timestamp = genesis.header.timestamp + index * params.target_block_time;
+            let block = mine_block(index, &blockchain.tip_hash(), vec![coinbase], blockchain.next_bits(), timestamp);
+            blockchain.add_mined_block(block).unwrap();
+        }
+        let tip = blockchain.tip_hash();
+
+        // The branch overpays its miner well past the prune depth
+        let invalid_height = 2 * MIN_PRUNE_DEPTH;
+        let mut headers = vec![genesis.header.clone()];
+        let mut parent = genesis.hash;
+        let mut result = Ok(BlockStatus::SideBranch);
+        for index in 1..200 {
+            let reward = params.subsidy(index) + u64::from(index == invalid_height);
+            let coinbase = Transaction::coinbase(Wallet::generate().address, reward, index);
+            let bits = difficulty::next_bits(&params, index, &headers.iter().collect::<Vec<_>>());
+            let timestamp = headers.last().unwrap().timestamp + 4 * params.target_block_time;
+            let block = mine_block(index, &parent, vec![coinbase], bits, timestamp);
+            parent = block.hash.clone();
+            headers.push(block.header.clone());
+            result = blockchain.add_mined_block(block);
+            if !matches!(result, Ok(BlockStatus::SideBranch)) {
+                break;
+            }
+        }
+
+        assert!(headers.len() as u64 > invalid_height + 1);
+        assert!(matches!(result, Err(ValidationError::BadCoinbase(_))), "{:?}", result);
+        assert_eq!(blockchain.tip_hash(), tip);
+        assert_eq!(blockchain.len(), 4);
+        assert_eq!(blockchain.balance(&miner), 3 * params.subsidy(1));
+    }
+
+    #[test]
+    fn garbage_block_claiming_a_real_hash_does_not_poison_it() {
+        let miner = Wallet::generate().address;
+        let mut blockchain = Blockchain::new(test_params());
+        let real = mine_next(&blockchain, &miner);
+
+        let mut garbage = Block::new(1, blockchain.tip_hash(), vec![], blockchain.next_bits());
+        garbage.header.timestamp = 0;
+        garbage.hash = real.hash.clone();
+        assert!(matches!(
+            blockchain.add_mined_block(garbage),
+            Err(ValidationError::TimestampTooOld { .. })
+        ));
+
+        assert!(matches!(blockchain.add_mined_block(real), Ok(BlockStatus::Connected)));
+    }
+
+    #[test]
+    fn stripped_signature_changes_the_merkle_root() {
+        let wallet = Wallet::generate();
+        let mut blockchain = Blockchain::new(test_params());
+        let block = mine_next(&blockchain, &wallet.address);
+        blockchain.add_mined_block(block).unwrap();
+
+        let mut tx = blockchain
+            .create_transaction(&wallet.address, &Wallet::generate().address, 10, 1)
+            .unwrap();
+        tx.sign(&wallet.secret_key).unwrap();
+        let real = mine_with(&blockchain, blockchain.block_template(&[tx], &wallet.address));
+
+        let mut copy = real.clone();
+        copy.transactions[1].signature = None;
+        assert_ne!(copy.calculate_merkle_root(), real.header.merkle_root);
+        assert_eq!(
+            blockchain.add_mined_block(copy).err(),
+            Some(ValidationError::BadMerkleRoot)
+        );
+
+        assert!(matches!(blockchain.add_mined_block(real), Ok(BlockStatus::Connected)));
+    }
+
+    /// Chain paying its first block reward to `wallet`, and an unsigned
+    /// payment of 10 coins from it
+    fn funded_payment(wallet: &Wallet) -> (Blockchain, Transaction) {
+        let mut blockchain = Blockchain::new(test_params());
+        let block = mine_next(&blockchain, &wallet.address);
+        blockchain.add_mined_block(block).unwrap();
+        let tx = blockchain
+            .create_transaction(&wallet.address, &Wallet::generate().address, 10, 1)
+            .unwrap();
+        (blockchain, tx)
+    }
+
+    #[test]
+    fn unsigned_and_forged_transactions_are_refused_by_the_mempool() {
+        let wallet = Wallet::generate();
+        let (blockchain, tx) = funded_payment(&wallet);
+        let mut pending = PendingState::new(blockchain.params.ledger);
+        assert_eq!(
+            blockchain.validate_transaction(&tx, &mut pending),
+            Err(ValidationError::BadSignature(tx.hash()))
+        );
+
+        // Signed, then changed to pay someone else
+        let mut forged = tx.clone();
+        forged.sign(&wallet.secret_key).unwrap();
+        forged.outputs[0].address = Wallet::generate().address;
+        assert_eq!(
+            blockchain.validate_transaction(&forged, &mut pending),
+            Err(ValidationError::BadSignature(forged.hash()))
+        );
+
+        // Signed by a key that does not own the sender address
+        let thief = Wallet::generate();
+        let mut stolen = tx.clone();
+        stolen.signature = forged.signature.clone();
+        stolen.public_key = Some(thief.public_key.clone());
+        assert_eq!(
+            blockchain.validate_transaction(&stolen, &mut pending),
+            Err(ValidationError::BadSignature(stolen.hash()))
+        );
+
+        let mut signed = tx;
+        signed.sign(&wallet.secret_key).unwrap();
+        assert_eq!(blockchain.validate_transaction(&signed, &mut pending), Ok(()));
+    }
+
+    #[test]
+    fn blocks_with_unsigned_or_forged_transactions_are_rejected() {
+        let wallet = Wallet::generate();
+        let (mut blockchain, tx) = funded_payment(&wallet);
+        let mut forged = tx.clone();
+        forged.sign(&wallet.secret_key).unwrap();
+        forged.outputs[0].amount += 1;
+
+        for bad in [tx, forged] {
+            let transactions = [blockchain.block_template(&[], &wallet.address), vec![bad.clone()]].concat();
+            let block = mine_with(&blockchain, transactions);
+            assert_eq!(
+                blockchain.add_mined_block(block).err(),
+                Some(ValidationError::BadSignature(bad.hash()))
+            );
+        }
+        assert_eq!(blockchain.len(), 2);
+    }
+
+    #[test]
+    fn coinbase_rules_are_enforced() {
+        let miner = Wallet::generate().address;
+        let mut blockchain = Blockchain::new(test_params());
+        let subsidy = blockchain.params.subsidy(1);
+        let coinbase = |amount, height| Transaction::coinbase(miner.clone(), amount, height);
+        let rejected = |blockchain: &mut Blockchain, transactions| {
+            let block = mine_with(blockchain, transactions);
+            matches!(blockchain.add_mined_block(block), Err(ValidationError::BadCoinbase(_)))
+        };
+
+        assert!(rejected(&mut blockchain, vec![]));
+        assert!(rejected(&mut blockchain, vec![coinbase(subsidy + 1, 1)]));
+        assert!(rejected(&mut blockchain, vec![coinbase(subsidy, 2)]));
+        assert!(rejected(&mut blockchain, vec![coinbase(subsidy, 1), coinbase(0, 1)]));
+        let mut extra_output = coinbase(subsidy, 1);
+        extra_output.outputs.push(TxOutput::new(miner.clone(), 1));
+        assert!(rejected(&mut blockchain, vec![extra_output]));
+
+        // Claiming less than allowed is fine
+        let block = mine_with(&blockchain, vec![coinbase(subsidy - 1, 1)]);
+        assert!(matches!(blockchain.add_mined_block(block), Ok(BlockStatus::Connected)));
+        assert_eq!(blockchain.balance(&miner), subsidy - 1);
+    }
+
+    #[test]
+    fn coinbase_subsidy_follows_the_halving_schedule() {
+        let miner = Wallet::generate().address;
+        let mut blockchain = Blockchain::new(ChainParams {
+            halving_interval: 2,
+            ..test_params()
+        });
+        for height in 1..=4 {
+            let subsidy = blockchain.params.subsidy(height);
+            let greedy = mine_with(&blockchain, vec![Transaction::coinbase(miner.clone(), subsidy + 1, height)]);
+            assert!(blockchain.add_mined_block(greedy).is_err());
+            let block = mine_next(&blockchain, &miner);
+            blockchain.add_mined_block(block).unwrap();
+        }
+        assert_eq!(blockchain.balance(&miner), 50 + 25 + 25 + 12);
+    }
+
+    /// Signed transaction spending `input` (worth `value`) of `from` back to
+    /// itself, leaving `fee`
+    fn spend(from: &Wallet, input: OutPoint, value: u64, fee: u64) -> Transaction {
+        let outputs = vec![TxOutput::new(from.address.clone(), value - fee)];
+        let mut tx = Transaction::new(from.address.clone(), vec![input], outputs);
+        tx.sign(&from.secret_key).unwrap();
+        tx
+    }
+
+    /// Chain whose first block reward is split into `count` confirmed coins of
+    /// `wallet`, and the value of each coin
+    fn funded_coins(wallet: &Wallet, count: u64) -> (Blockchain, Vec<OutPoint>, u64) {
+        let mut blockchain = Blockchain::new(test_params());
+        let block = mine_next(&blockchain, &wallet.address);
+        let reward = OutPoint::new(block.transactions[0].hash(), 0);
+        blockchain.add_mined_block(block).unwrap();
+
+        let value = blockchain.params.subsidy(1) / count;
+        let outputs = (0..count).map(|_| TxOutput::new(wallet.address.clone(), value)).collect();
+        let mut split = Transaction::new(wallet.address.clone(), vec![reward], outputs);
+        split.sign(&wallet.secret_key).unwrap();
+        let block = mine_with(&blockchain, blockchain.block_template(&[split.clone()], &wallet.address));
+        blockchain.add_mined_block(block).unwrap();
+
+        let coins = (0..count as u32).map(|i| OutPoint::new(split.hash(), i)).collect();
+        (blockchain, coins, value)
+    }
+
+    #[test]
+    fn template_orders_by_fee_rate_and_collects_the_fees() {
+        let wallet = Wallet::generate();
+        let (mut blockchain, coins, value) = funded_coins(&wallet, 3);
+        let low = spend(&wallet, coins[0].clone(), value, 1);
+        let high = spend(&wallet, coins[1].clone(), value, 5);
+        let mid = spend(&wallet, coins[2].clone(), value, 3);
+        // Pays the most, but only fits once its parent is included
+        let child = spend(&wallet, OutPoint::new(low.hash(), 0), value - 1, 10);
+
+        let mempool = [child.clone(), low.clone(), high.clone(), mid.clone()];
+        let transactions = blockchain.block_template(&mempool, &wallet.address);
+        assert_eq!(transactions[1..], [high, mid, low, child]);
+        assert_eq!(transactions[0].outputs[0].amount, blockchain.params.subsidy(3) + 1 + 5 + 3 + 10);
+
+        let block = mine_with(&blockchain, transactions);
+        assert!(matches!(blockchain.add_mined_block(block), Ok(BlockStatus::Connected)));
+    }
+
+    #[test]
+    fn template_respects_the_size_limit() {
+        let wallet = Wallet::generate();
+        let (mut blockchain, coins, value) = funded_coins(&wallet, 3);
+        let mempool: Vec<Transaction> = coins
+            .iter()
+            .zip([1, 3, 2])
+            .map(|(coin, fee)| spend(&wallet, coin.clone(), value, fee))
+            .collect();
+
+        // Room for the coinbase and two of the three transactions
+        let coinbase_size = Transaction::coinbase(wallet.address.clone(), 0, 3).size();
+        let largest = mempool.iter().map(|tx| tx.size()).max().unwrap();
+        blockchain.params.max_block_size = coinbase_size + 2 * largest;
+        let transactions = blockchain.block_template(&mempool, &wallet.address);
+        assert_eq!(transactions[1..], [mempool[1].clone(), mempool[2].clone()]);
+        assert_eq!(transactions[0].outputs[0].amount, blockchain.params.subsidy(3) + 3 + 2);
+
+        let block = mine_with(&blockchain, transactions);
+        assert!(block.transactions_size() <= blockchain.params.max_block_size);
+        assert!(matches!(blockchain.add_mined_block(block), Ok(BlockStatus::Connected)));
+    }
+
+    #[test]
+    fn uppercase_merkle_root_is_rejected_without_poisoning() {
+        let miner = Wallet::generate().address;
+        let mut blockchain = Blockchain::new(test_params());
+        let real = mine_next(&blockchain, &miner);
+
+        let mut copy = real.clone();
+        copy.header.merkle_root = copy.header.merkle_root.to_uppercase();
+        assert_eq!(copy.calculate_hash(), real.hash);
+        assert_eq!(
+            blockchain.add_mined_block(copy).err(),
+            Some(ValidationError::NonCanonicalHeader)
+        );
+
+        assert!(matches!(blockchain.add_mined_block(real), Ok(BlockStatus::Connected)));
+    }
+
+    #[test]
+    fn block_with_a_bad_header_is_remembered_as_invalid() {
+        let miner = Wallet::generate().address;
+        let mut blockchain = Blockchain::new(test_params());
+        let mut block = mine_next(&blockchain, &miner);
+        block.header.timestamp = 0;
+        block.hash = block.calculate_hash();
+
+        assert!(matches!(
+            blockchain.add_mined_block(block.clone()),
+            Err(ValidationError::TimestampTooOld { .. })
+        ));
+        assert_eq!(
+            blockchain.add_mined_block(block.clone()).err(),
+            Some(ValidationError::KnownInvalid(block.hash))
+        );
+    }
+}
@@ -0,0 +1,295 @@
+//! Framing shared by nodes, seed nodes and clients.
+//!
+//! ```text
+//! magic    4 bytes  network magic (`Network::magic`)
+//! command  u8       message type (`Command`)
+//! length   u32      length of the payload
+//! checksum u32      CRC-32 of the payload
+//! payload  length bytes, bincode-encoded `Envelope`
+//! ```
+//!
+//! Integers are big-endian. The length is checked against the limit of the
+//! command before anything is allocated, the payload must arrive within
+//! `READ_TIMEOUT` of its header, and it is decoded with the same limit so a
+//! length field inside the payload cannot make the decoder allocate more.
+
+use std::time::Duration;
+
+use bincode::Options;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::message::{Command, Message, MAX_MESSAGE_SIZE};
+
+/// Size of a frame header
+pub const FRAME_HEADER_SIZE: usize = 13;
+
+/// Time allowed for the rest of a frame to arrive once its first byte has
+pub const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A message as sent over the wire
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Envelope {
+    /// Sender-chosen id, echoed in the response
+    pub id: u64,
+    /// Id of the request this message answers, if it is a response
+    pub reply_to: Option<u64>,
+    pub message: Message,
+}
+
+impl Envelope {
+    /// Envelope for a message that is not a response
+    pub fn new(id: u64, message: Message) -> Self {
+        Envelope {
+            id,
+            reply_to: None,
+            message,
+        }
+    }
+}
+
+/// Reason a frame could not be read or written
+#[derive(Debug)]
+pub enum FrameError {
+    /// The connection failed or was closed
+    Io(std::io::Error),
+    /// The rest of the frame did not arrive in time
+    Timeout,
+    /// The frame belongs to another network
+    WrongMagic([u8; 4]),
+    /// The frame header names no known command
+    UnknownCommand(u8),
+    /// The command is not accepted at this point of the connection
+    Unexpected(Command),
+    /// The payload is larger than its command allows
+    TooLarge { command: Command, size: usize, max: usize },
+    /// The payload does not match its checksum
+    BadChecksum,
+    /// The payload is not a valid envelope of the announced command
+    Malformed(String),
+}
+
+impl FrameError {
+    /// Check if the frame itself is invalid, rather than the connection failing
+    pub fn is_malformed(&self) -> bool {
+        !matches!(self, FrameError::Io(_) | FrameError::Timeout)
+    }
+}
+
+impl std::fmt::Display for FrameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FrameError::Io(e) => write!(f, "{}", e),
+            FrameError::Timeout => write!(f, "timed out reading a frame"),
+            FrameError::WrongMagic(magic) => {
+                write!(f, "frame from another network (magic {})", hex::encode(magic))
+            }
+            FrameError::UnknownCommand(byte) => write!(f, "unknown command {}", byte),
+            FrameError::Unexpected(command) => write!(f, "unexpected {:?} message", command),
+            FrameError::TooLarge { command, size, max } => {
+                write!(f, "{:?} message of {} bytes exceeds the limit of {}", command, size, max)
+            }
+            FrameError::BadChecksum => write!(f, "frame failed its checksum"),
+            FrameError::Malformed(e) => write!(f, "malformed message: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+impl From<std::io::Error> for FrameError {
+    fn from(e: std::io::Error) -> Self {
+        FrameError::Io(e)
+    }
+}
+
+/// Bincode settings bounded by the limit of a command
+fn bincode_options(max: usize) -> impl Options {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .with_limit(max as u64)
+}
+
+/// Read one frame, waiting as long as it takes for it to start
+pub async fn read_envelope<R: AsyncRead + Unpin>(reader: &mut R, magic: [u8; 4]) -> Result<Envelope, FrameError> {
+    read_envelope_of(reader, magic, &Command::ALL, MAX_MESSAGE_SIZE).await
+}
+
+/// Read one frame of one of the `allowed` commands, no larger than `max`
+/// (or the limit of its command, if lower). Other commands are rejected
+/// from the header, before their payload is read.
+pub async fn read_envelope_of<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    magic: [u8; 4],
+    allowed: &[Command],
+    max: usize,
+) -> Result<Envelope, FrameError> {
+    let mut first = [0u8; 1];
+    reader.read_exact(&mut first).await?;
+    tokio::time::timeout(READ_TIMEOUT, read_rest(reader, magic, first[0], allowed, max))
+        .await
+        .map_err(|_| FrameError::Timeout)?
+}
+
+async fn read_rest<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    magic: [u8; 4],
+    first: u8,
+    allowed: &[Command],
+    max: usize,
+) -> Result<Envelope, FrameError> {
+    let mut header = [0u8; FRAME_HEADER_SIZE];
+    header[0] = first;
+    reader.read_exact(&mut header[1..]).await?;
+
+    let frame_magic: [u8; 4] = header[0..4].try_into().unwrap();
+    if frame_magic != magic {
+        return Err(FrameError::WrongMagic(frame_magic));
+    }
+    let command = Command::from_byte(header[4]).ok_or(FrameError::UnknownCommand(header[4]))?;
+    if !allowed.contains(&command) {
+        return Err(FrameError::Unexpected(command));
+    }
+    let size = u32::from_be_bytes(header[5..9].try_into().unwrap()) as usize;
+    let checksum = u32::from_be_bytes(header[9..13].try_into().unwrap());
+    let max = command.max_size().min(max);
+    if size > max {
+        return Err(FrameError::TooLarge { command, size, max });
+    }
+
+    let mut payload = vec![0u8; size];
+    reader.read_exact(&mut payload).await?;
+    if crc32fast::hash(&payload) != checksum {
+        return Err(FrameError::BadChecksum);
+    }
+
+    let envelope: Envelope = bincode_options(max)
+        .deserialize(&payload)
+        .map_err(|e| FrameError::Malformed(e.to_string()))?;
+    if envelope.message.command() != command {
+        return Err(FrameError::Malformed(format!(
+            "{:?} frame carries a {:?} message",
+            command,
+            envelope.message.command()
+        )));
+    }
+    Ok(envelope)
+}
+
+/// Write one frame
+pub async fn write_envelope<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    magic: [u8; 4],
+    envelope: &Envelope,
+) -> Result<(), FrameError> {
+    let command = envelope.message.command();
+    let max = command.max_size();
+    let payload = bincode_options(MAX_MESSAGE_SIZE)
+        .serialize(envelope)
+        .map_err(|e| FrameError::Malformed(e.to_string()))?;
+    if payload.len() > max {
+        return Err(FrameError::TooLarge {
+            command,
+            size: payload.len(),
+            max,
+        });
+    }
+
+    let mut frame = Vec::with_capacity(FRAME_HEADER_SIZE + payload.len());
+    frame.extend_from_slice(&magic);
+    frame.push(command as u8);
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&crc32fast::hash(&payload).to_be_bytes());
+    frame.extend_from_slice(&payload);
+    writer.write_all(&frame).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::address::Wallet;
+    use crate::block::Block;
+    use crate::message::{MAX_BLOCK_SIZE_LIMIT, MAX_CONTROL_MESSAGE_SIZE};
+    use crate::transaction::Transaction;
+
+    const MAGIC: [u8; 4] = [0xc5, 0x70, 0x0c, 0xc3];
+
+    /// Encoded frame carrying `message`
+    async fn frame(message: Message) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        write_envelope(&mut bytes, MAGIC, &Envelope::new(7, message)).await.unwrap();
+        bytes
+    }
+
+    #[tokio::test]
+    async fn frames_round_trip() {
+        let bytes = frame(Message::GetPeers).await;
+        let envelope = read_envelope(&mut bytes.as_slice(), MAGIC).await.unwrap();
+        assert_eq!(envelope.id, 7);
+        assert!(matches!(envelope.message, Message::GetPeers));
+
+        let other_network = [0u8; 4];
+        let result = read_envelope(&mut bytes.as_slice(), other_network).await;
+        assert!(matches!(result, Err(FrameError::WrongMagic(magic)) if magic == MAGIC));
+    }
+
+    #[tokio::test]
+    async fn lengths_over_the_command_limit_are_rejected_from_the_header() {
+        // Only the header: the payload is never read
+        let mut bytes = frame(Message::GetPeers).await[..FRAME_HEADER_SIZE].to_vec();
+        bytes[5..9].copy_from_slice(&(MAX_CONTROL_MESSAGE_SIZE as u32 + 1).to_be_bytes());
+        let result = read_envelope(&mut bytes.as_slice(), MAGIC).await;
+        assert!(matches!(
+            result,
+            Err(FrameError::TooLarge { command: Command::GetPeers, size, max: MAX_CONTROL_MESSAGE_SIZE })
+                if size == MAX_CONTROL_MESSAGE_SIZE + 1
+        ));
+    }
+
+    #[tokio::test]
+    async fn corrupted_payloads_fail_the_checksum() {
+        let mut bytes = frame(Message::GetPeers).await;
+        *bytes.last_mut().unwrap() ^= 1;
+        let result = read_envelope(&mut bytes.as_slice(), MAGIC).await;
+        assert!(matches!(result, Err(FrameError::BadChecksum)));
+    }
+
+    #[tokio::test]
+    async fn payloads_of_another_command_are_rejected() {
+        let mut bytes = frame(Message::GetPeers).await;
+        bytes[4] = Command::GetStatus as u8;
+        let result = read_envelope(&mut bytes.as_slice(), MAGIC).await;
+        assert!(matches!(result, Err(FrameError::Malformed(e)) if e.contains("carries a GetPeers message")));
+
+        bytes[4] = u8::MAX;
+        let result = read_envelope(&mut bytes.as_slice(), MAGIC).await;
+        assert!(matches!(result, Err(FrameError::UnknownCommand(u8::MAX))));
+    }
+
+    #[test]
+    fn blocks_within_the_size_limit_fit_in_a_block_message() {
+        let coinbase = Transaction::coinbase(Wallet::generate().address, u64::MAX, u64::MAX);
+        let mut block = Block::new(u64::MAX, "ab".repeat(32), vec![coinbase], u32::MAX);
+        block.header.timestamp = u64::MAX;
+        let envelope = Envelope {
+            id: u64::MAX,
+            reply_to: Some(u64::MAX),
+            message: Message::Block(Some(block.clone())),
+        };
+        let size = bincode_options(MAX_MESSAGE_SIZE).serialized_size(&envelope).unwrap() as usize;
+        assert!(size - block.transactions_size() + MAX_BLOCK_SIZE_LIMIT <= Command::Block.max_size());
+    }
+
+    #[tokio::test]
+    async fn only_allowed_commands_are_read_within_the_given_limit() {
+        let bytes = frame(Message::GetPeers).await;
+        let result = read_envelope_of(&mut bytes.as_slice(), MAGIC, &[Command::GetStatus], MAX_MESSAGE_SIZE).await;
+        assert!(matches!(result, Err(FrameError::Unexpected(Command::GetPeers))));
+
+        let mut bytes = frame(Message::GetData(vec![])).await[..FRAME_HEADER_SIZE].to_vec();
+        bytes[5..9].copy_from_slice(&(MAX_CONTROL_MESSAGE_SIZE as u32 + 1).to_be_bytes());
+        let result = read_envelope_of(&mut bytes.as_slice(), MAGIC, &[Command::GetData], MAX_CONTROL_MESSAGE_SIZE).await;
+        assert!(matches!(result, Err(FrameError::TooLarge { command: Command::GetData, .. })));
+    }
+}
@@ -0,0 +1,196 @@
+use crate::block::BlockHeader;
+use crate::params::{ChainParams, RetargetAlgorithm};
+use crate::pow::{self, U256};
+
+/// Compute the compact target required for the block at `height`.
+///
+/// `ancestors` are the headers before it, oldest first and ending with its
+/// parent; only the last `difficulty_window + 1` are looked at.
+pub fn next_bits(params: &ChainParams, height: u64, ancestors: &[&BlockHeader]) -> u32 {
+    let parent = match ancestors.last() {
+        Some(parent) if height > 0 => parent,
+        _ => return params.genesis_bits,
+    };
+
+    let window = params.difficulty_window.max(1);
+    match params.retarget {
+        RetargetAlgorithm::Window => {
+            if !height.is_multiple_of(window) || ancestors.len() as u64 <= window {
+                return parent.bits;
+            }
+            window_retarget(params, ancestors, window)
+        }
+        RetargetAlgorithm::Lwma => {
+            if (ancestors.len() as u64) <= window {
+                return parent.bits;
+            }
+            lwma_retarget(params, ancestors, window)
+        }
+    }
+}
+
+/// Scale the parent's target by how long the last `window` blocks actually
+/// took compared to the expected time, limited to a factor of 4 either way
+fn window_retarget(params: &ChainParams, ancestors: &[&BlockHeader], window: u64) -> u32 {
+    let parent = ancestors[ancestors.len() - 1];
+    let first = ancestors[ancestors.len() - 1 - window as usize];
+
+    let expected = window * params.target_block_time.max(1);
+    let actual = parent
+        .timestamp
+        .saturating_sub(first.timestamp)
+        .clamp(expected / 4, expected * 4)
+        .max(1);
+
+    let target = pow::target_from_bits(parent.bits).unwrap_or_default();
+    let scaled = scale(target, actual, expected);
+    bits_within_limit(params, scaled)
+}
+
+/// Linearly weighted moving average (LWMA-1): average target of the window
+/// scaled by the solve times, weighting recent blocks more heavily
+fn lwma_retarget(params: &ChainParams, ancestors: &[&BlockHeader], window: u64) -> u32 {
+    let block_time = params.target_block_time.max(1);
+    let recent = &ancestors[ancestors.len() - 1 - window as usize..];
+
+    let mut weighted_time: u64 = 0;
+    let mut average_target = U256::zero();
+    for (i, pair) in recent.windows(2).enumerate() {
+        // Out-of-order timestamps count as a 1 second solve time, outliers are capped
+        let solve_time = pair[1]
+            .timestamp
+            .saturating_sub(pair[0].timestamp)
+            .clamp(1, 6 * block_time);
+        weighted_time += (i as u64 + 1) * solve_time;
+
+        let target = pow::target_from_bits(pair[1].bits).unwrap_or_default();
+        average_target = average_target.saturating_add(target / U256::from(window));
+    }
+
+    let expected = window * (window + 1) / 2 * block_time;
+    let scaled = scale(average_target, weighted_time, expected);
+    bits_within_limit(params, scaled)
+}
+
+/// target * numerator / denominator, avoiding overflow for very easy targets
+fn scale(target: U256, numerator: u64, denominator: u64) -> U256 {
+    let (numerator, denominator) = (U256::from(numerator), U256::from(denominator.max(1)));
+    match target.checked_mul(numerator) {
+        Some(product) => product / denominator,
+        None => (target / denominator).saturating_mul(numerator),
+    }
+}
+
+/// Encode a target, never easier than the proof-of-work limit
+fn bits_within_limit(params: &ChainParams, target: U256) -> u32 {
+    let limit = pow::target_from_bits(params.pow_limit_bits).unwrap_or(U256::MAX);
+    pow::bits_from_target(target.min(limit).max(U256::one()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BITS: u32 = 0x1e0fffff;
+
+    fn params(retarget: RetargetAlgorithm) -> ChainParams {
+        ChainParams {
+            genesis_bits: BITS,
+            retarget,
+            difficulty_window: 10,
+            target_block_time: 10,
+            ..ChainParams::default()
+        }
+    }
+
+    /// `count` headers with target `BITS`, `spacing` seconds apart
+    fn headers(count: usize, spacing: u64) -> Vec<BlockHeader> {
+        (0..count)
+            .map(|i| BlockHeader {
+                version: 1,
+                prev_hash: String::new(),
+                merkle_root: String::new(),
+                timestamp: 1_000_000 + i as u64 * spacing,
+                bits: BITS,
+                nonce: 0,
+            })
+            .collect()
+    }
+
+    fn next_target(params: &ChainParams, height: u64, headers: &[BlockHeader]) -> U256 {
+        let ancestors: Vec<&BlockHeader> = headers.iter().collect();
+        pow::target_from_bits(next_bits(params, height, &ancestors)).unwrap()
+    }
+
+    fn target() -> U256 {
+        pow::target_from_bits(BITS).unwrap()
+    }
+
+    /// Check `actual` is within 0.1% of `expected`
+    fn assert_close(actual: U256, expected: U256) {
+        let tolerance = expected / U256::from(1000);
+        let diff = if actual > expected { actual - expected } else { expected - actual };
+        assert!(diff <= tolerance, "{:x} is not close to {:x}", actual, expected);
+    }
+
+    #[test]
+    fn genesis_uses_the_genesis_target() {
+        let params = params(RetargetAlgorithm::Window);
+        assert_eq!(next_bits(&params, 0, &[]), BITS);
+    }
+
+    #[test]
+    fn window_keeps_the_target_between_retargets() {
+        let params = params(RetargetAlgorithm::Window);
+        assert_eq!(next_target(&params, 15, &headers(11, 1)), target());
+        // Not enough history for a full window yet
+        assert_eq!(next_target(&params, 10, &headers(10, 1)), target());
+    }
+
+    #[test]
+    fn window_retargets_by_the_observed_block_time() {
+        let params = params(RetargetAlgorithm::Window);
+        assert_eq!(next_target(&params, 20, &headers(11, 10)), target());
+        assert_close(next_target(&params, 20, &headers(11, 5)), target() / 2);
+        assert_close(next_target(&params, 20, &headers(11, 20)), target() * 2);
+    }
+
+    #[test]
+    fn window_limits_each_adjustment_to_a_factor_of_four() {
+        let params = params(RetargetAlgorithm::Window);
+        assert_close(next_target(&params, 20, &headers(11, 0)), target() / 4);
+        assert_close(next_target(&params, 20, &headers(11, 1000)), target() * 4);
+    }
+
+    #[test]
+    fn retargets_never_go_past_the_pow_limit() {
+        let params = ChainParams {
+            pow_limit_bits: BITS,
+            ..params(RetargetAlgorithm::Window)
+        };
+        assert_eq!(next_target(&params, 20, &headers(11, 40)), target());
+    }
+
+    #[test]
+    fn lwma_follows_the_weighted_block_time() {
+        let params = params(RetargetAlgorithm::Lwma);
+        assert_close(next_target(&params, 11, &headers(11, 10)), target());
+        assert_close(next_target(&params, 11, &headers(11, 5)), target() / 2);
+        assert_close(next_target(&params, 11, &headers(11, 20)), target() * 2);
+        // Solve times are capped at six block times
+        assert_close(next_target(&params, 11, &headers(11, 1000)), target() * 6);
+    }
+
+    #[test]
+    fn lwma_weights_recent_blocks_more() {
+        let params = params(RetargetAlgorithm::Lwma);
+        // Slow blocks followed by fast ones: the fast ones count more
+        let mut slow_then_fast = headers(6, 20);
+        let last = slow_then_fast[5].clone();
+        slow_then_fast.extend((1..=5).map(|i| BlockHeader {
+            timestamp: last.timestamp + i * 5,
+            ..last.clone()
+        }));
+        assert!(next_target(&params, 11, &slow_then_fast) < target());
+    }
+}
@@ -0,0 +1,105 @@
+/// Reason a block, chain or transaction was rejected
+#[derive(Debug, Clone, PartialEq)]
+pub enum ValidationError {
+    /// The chain has no blocks
+    EmptyChain,
+    /// Block height does not follow its parent
+    BadIndex { expected: u64, found: u64 },
+    /// Block does not reference the expected parent
+    BadPrevHash,
+    /// Block header hashes are not 64 lowercase hex digits
+    NonCanonicalHeader,
+    /// Block's parent is not known
+    UnknownParent(String),
+    /// Switching to the block's branch would disconnect pruned blocks
+    ReorgTooDeep { fork_height: Option<u64>, pruned_height: u64 },
+    /// Block or one of its ancestors already failed validation
+    KnownInvalid(String),
+    /// Block header has an unknown version
+    UnsupportedVersion(u32),
+    /// Block timestamp is not after the median time of the previous blocks
+    TimestampTooOld { timestamp: u64, median: u64 },
+    /// Block timestamp is too far ahead of network-adjusted time
+    TimestampTooNew { timestamp: u64, max: u64 },
+    /// Block header target differs from the required target
+    BadDifficulty,
+    /// Block hash is above the target
+    BadProofOfWork,
+    /// Block hash is not the hash of its header
+    BadHash,
+    /// Merkle root does not match the block's transactions
+    BadMerkleRoot,
+    /// Block transactions exceed the maximum block size
+    BlockTooLarge { size: usize, max: usize },
+    /// Block violates the coinbase rules
+    BadCoinbase(String),
+    /// Transaction signature is missing, malformed or does not match the sender
+    BadSignature(String),
+    /// Transaction spends coins its sender does not own
+    InvalidSpend(String),
+    /// Transaction is already in the mempool
+    DuplicateTransaction(String),
+}
+
+impl std::fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ValidationError::EmptyChain => write!(f, "chain is empty"),
+            ValidationError::BadIndex { expected, found } => {
+                write!(f, "expected block #{}, found #{}", expected, found)
+            }
+            ValidationError::BadPrevHash => write!(f, "previous hash does not match"),
+            ValidationError::NonCanonicalHeader => write!(f, "header hashes are not lowercase hex"),
+            ValidationError::UnknownParent(hash) => write!(f, "unknown parent block {}", hash),
+            ValidationError::ReorgTooDeep { fork_height, pruned_height } => match fork_height {
+                Some(height) => write!(
+                    f,
+                    "branch forks at block #{}, below the pruned height {}",
+                    height, pruned_height
+                ),
+                None => write!(f, "branch replaces the genesis block of a pruned chain"),
+            },
+            ValidationError::KnownInvalid(hash) => write!(f, "block {} is known to be invalid", hash),
+            ValidationError::UnsupportedVersion(version) => {
+                write!(f, "unsupported block version {}", version)
+            }
+            ValidationError::TimestampTooOld { timestamp, median } => {
+                write!(f, "timestamp {} is not after median time past {}", timestamp, median)
+            }
+            ValidationError::TimestampTooNew { timestamp, max } => {
+                write!(f, "timestamp {} is later than allowed {}", timestamp, max)
+            }
+            ValidationError::BadDifficulty => write!(f, "wrong proof-of-work target"),
+            ValidationError::BadProofOfWork => write!(f, "invalid proof of work"),
+            ValidationError::BadHash => write!(f, "block hash does not match its header"),
+            ValidationError::BadMerkleRoot => write!(f, "merkle root does not match transactions"),
+            ValidationError::BlockTooLarge { size, max } => {
+                write!(f, "block size {} exceeds maximum {}", size, max)
+            }
+            ValidationError::BadCoinbase(reason) => write!(f, "bad coinbase: {}", reason),
+            ValidationError::BadSignature(hash) => write!(f, "bad signature on transaction {}", hash),
+            ValidationError::InvalidSpend(reason) => write!(f, "invalid spend: {}", reason),
+            ValidationError::DuplicateTransaction(hash) => {
+                write!(f, "transaction {} already in mempool", hash)
+            }
+        }
+    }
+}
+
+impl ValidationError {
+    /// Check if the error is a verdict on the block header itself, so every
+    /// block with the same header hash fails the same way. Other failures
+    /// concern parts of a block a peer can change without changing its hash.
+    pub fn is_header_failure(&self) -> bool {
+        matches!(
+            self,
+            ValidationError::UnsupportedVersion(_)
+                | ValidationError::TimestampTooOld { .. }
+                | ValidationError::BadDifficulty
+                | ValidationError::BadProofOfWork
+                | ValidationError::KnownInvalid(_)
+        )
+    }
+}
+
+impl std::error::Error for ValidationError {}
@@ -0,0 +1,348 @@
+//! Chain export files, for snapshots, shared fixtures and offline bootstrapping.
+//!
+//! Two formats are written, both holding the active chain genesis first.
+//!
+//! Binary (`binary`), all integers little-endian:
+//!
+//! ```text
+//! magic    4 bytes  "SPCX"
+//! version  u32      EXPORT_VERSION
+//! count    u64      number of blocks
+//! count records of:
+//!   length u32      length of the encoded block
+//!   crc32  u32      CRC-32 of the encoded block
+//!   block  length bytes, bincode-encoded `Block`
+//! ```
+//!
+//! JSON lines (`json`): a header line
+//! `{"magic":"SPCX","version":1,"count":N}` followed by one JSON-encoded
+//! `Block` per line.
+//!
+//! Readers detect the format from the first byte and reject unknown versions.
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::block::Block;
+use crate::blockchain::{BlockStatus, Blockchain};
+use crate::store::ChainStore;
+
+/// First bytes of an export file
+pub const EXPORT_MAGIC: [u8; 4] = *b"SPCX";
+
+/// Version of the export format written by this build
+pub const EXPORT_VERSION: u32 = 1;
+
+/// Largest encoded block accepted from an export file
+const MAX_RECORD_SIZE: u32 = 64 * 1024 * 1024;
+
+/// Encoding of an export file
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// Length-prefixed, checksummed bincode records
+    #[default]
+    Binary,
+    /// One JSON object per line
+    JsonLines,
+}
+
+impl std::str::FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "binary" => Ok(ExportFormat::Binary),
+            "json" => Ok(ExportFormat::JsonLines),
+            _ => Err(format!("unknown export format '{}' (expected binary or json)", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for ExportFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExportFormat::Binary => write!(f, "binary"),
+            ExportFormat::JsonLines => write!(f, "json"),
+        }
+    }
+}
+
+/// Header line of a JSON lines export
+#[derive(Debug, Serialize, Deserialize)]
+struct JsonHeader {
+    magic: String,
+    version: u32,
+    count: u64,
+}
+
+/// Outcome of an import
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ImportSummary {
+    /// Blocks added to the block tree
+    pub added: u64,
+    /// Blocks that were already known
+    pub known: u64,
+}
+
+/// Write the active chain of a store to `path`. Returns the number of blocks written.
+pub fn export_chain<S: ChainStore>(store: &S, path: &Path, format: ExportFormat) -> Result<u64, String> {
+    if store.pruned_height() > 0 {
+        return Err(format!(
+            "Blocks below #{} have been pruned, the chain cannot be exported",
+            store.pruned_height()
+        ));
+    }
+
+    let file = File::create(path).map_err(|e| format!("Cannot create {}: {}", path.display(), e))?;
+    let mut writer = BufWriter::new(file);
+    let count = store.len();
+
+    match format {
+        ExportFormat::Binary => {
+            writer.write_all(&EXPORT_MAGIC).map_err(|e| e.to_string())?;
+            writer.write_all(&EXPORT_VERSION.to_le_bytes()).map_err(|e| e.to_string())?;
+            writer.write_all(&count.to_le_bytes()).map_err(|e| e.to_string())?;
+        }
+        ExportFormat::JsonLines => {
+            let header = JsonHeader {
+                magic: String::from_utf8_lossy(&EXPORT_MAGIC).into_owned(),
+                version: EXPORT_VERSION,
+                count,
+            };
+            serde_json::to_writer(&mut writer, &header).map_err(|e| e.to_string())?;
+            writer.write_all(b"\n").map_err(|e| e.to_string())?;
+        }
+    }
+
+    for height in 0..count {
+        let block = store
+            .block(height)
+            .ok_or_else(|| format!("Block #{} is missing from the store", height))?;
+        match format {
+            ExportFormat::Binary => {
+                let data = bincode::serialize(&block).map_err(|e| e.to_string())?;
+                writer.write_all(&(data.len() as u32).to_le_bytes()).map_err(|e| e.to_string())?;
+                writer.write_all(&crc32fast::hash(&data).to_le_bytes()).map_err(|e| e.to_string())?;
+                writer.write_all(&data).map_err(|e| e.to_string())?;
+            }
+            ExportFormat::JsonLines => {
+                serde_json::to_writer(&mut writer, &block).map_err(|e| e.to_string())?;
+                writer.write_all(b"\n").map_err(|e| e.to_string())?;
+            }
+        }
+    }
+
+    writer.flush().map_err(|e| e.to_string())?;
+    writer.get_ref().sync_all().map_err(|e| e.to_string())?;
+    Ok(count)
+}
+
+/// Blocks read one at a time from an export file
+pub struct ExportReader {
+    reader: BufReader<File>,
+    format: ExportFormat,
+    /// Number of blocks the header announced
+    count: u64,
+    read: u64,
+}
+
+impl ExportReader {
+    /// Open an export file, detecting its format and checking its header
+    pub fn open(path: &Path) -> Result<Self, String> {
+        let file = File::open(path).map_err(|e| format!("Cannot open {}: {}", path.display(), e))?;
+        let mut reader = BufReader::new(file);
+
+        let first = reader.fill_buf().map_err(|e| e.to_string())?.first().copied();
+        let (format, version, count) = match first {
+            Some(b'{') => {
+                let mut line = String::new();
+                reader.read_line(&mut line).map_err(|e| e.to_string())?;
+                let header: JsonHeader =
+                    serde_json::from_str(&line).map_err(|e| format!("Bad export header: {}", e))?;
+                if header.magic.as_bytes() != EXPORT_MAGIC {
+                    return Err(String::from("Not a chain export file"));
+                }
+                (ExportFormat::JsonLines, header.version, header.count)
+            }
+            _ => {
+                let mut header = [0u8; 16];
+                reader
+                    .read_exact(&mut header)
+                    .map_err(|_| String::from("Not a chain export file"))?;
+                if header[0..4] != EXPORT_MAGIC {
+                    return Err(String::from("Not a chain export file"));
+                }
+                let version = u32::from_le_bytes(header[4..8].try_into().unwrap());
+                let count = u64::from_le_bytes(header[8..16].try_into().unwrap());
+                (ExportFormat::Binary, version, count)
+            }
+        };
+
+        if version != EXPORT_VERSION {
+            return Err(format!(
+                "Unsupported export version {} (expected {})",
+                version, EXPORT_VERSION
+            ));
+        }
+
+        Ok(ExportReader {
+            reader,
+            format,
+            count,
+            read: 0,
+        })
+    }
+
+    /// Format of the file
+    pub fn format(&self) -> ExportFormat {
+        self.format
+    }
+
+    /// Number of blocks in the file
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Read the next block record
+    fn read_block(&mut self) -> Result<Block, String> {
+        match self.format {
+            ExportFormat::Binary => {
+                let mut header = [0u8; 8];
+                self.reader.read_exact(&mut header).map_err(|e| e.to_string())?;
+                let len = u32::from_le_bytes(header[0..4].try_into().unwrap());
+                let checksum = u32::from_le_bytes(header[4..8].try_into().unwrap());
+                if len > MAX_RECORD_SIZE {
+                    return Err(format!("Block record of {} bytes is too large", len));
+                }
+
+                let mut data = vec![0u8; len as usize];
+                self.reader.read_exact(&mut data).map_err(|e| e.to_string())?;
+                if crc32fast::hash(&data) != checksum {
+                    return Err(String::from("Block record failed its checksum"));
+                }
+                bincode::deserialize(&data).map_err(|e| e.to_string())
+            }
+            ExportFormat::JsonLines => {
+                let mut line = String::new();
+                if self.reader.read_line(&mut line).map_err(|e| e.to_string())? == 0 {
+                    return Err(String::from("unexpected end of file"));
+                }
+                serde_json::from_str(&line).map_err(|e| e.to_string())
+            }
+        }
+    }
+}
+
+impl Iterator for ExportReader {
+    type Item = Result<Block, String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.read >= self.count {
+            return None;
+        }
+        let height = self.read;
+        self.read += 1;
+        Some(
+            self.read_block()
+                .map_err(|e| format!("Cannot read block #{} of the export: {}", height, e)),
+        )
+    }
+}
+
+/// Add the blocks of an export file to a blockchain, validating each one
+/// exactly as if it had been received from a peer. Stops at the first
+/// invalid block.
+pub fn import_chain<S: ChainStore>(blockchain: &mut Blockchain<S>, path: &Path) -> Result<ImportSummary, String> {
+    let mut summary = ImportSummary::default();
+    for block in ExportReader::open(path)? {
+        let block = block?;
+        let index = block.index;
+        match blockchain.add_mined_block(block) {
+            Ok(BlockStatus::AlreadyKnown) => summary.known += 1,
+            Ok(_) => summary.added += 1,
+            Err(e) => return Err(format!("Block #{} of the export is invalid: {}", index, e)),
+        }
+    }
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::address::Wallet;
+    use crate::storage::{test_dir, test_params};
+
+    /// Chain with `len` blocks after the genesis block
+    fn mined_chain(len: u64) -> Blockchain {
+        let mut blockchain = Blockchain::new(test_params());
+        let miner = Wallet::generate().address;
+        for _ in 0..len {
+            blockchain.add_block(blockchain.block_template(&[], &miner));
+        }
+        blockchain
+    }
+
+    #[test]
+    fn exported_chain_imports_into_an_empty_one() {
+        let dir = test_dir("export");
+        std::fs::create_dir_all(&dir).unwrap();
+        let source = mined_chain(3);
+
+        for format in [ExportFormat::Binary, ExportFormat::JsonLines] {
+            let path = dir.join(format!("chain.{}", format));
+            assert_eq!(export_chain(source.store(), &path, format).unwrap(), 4);
+            let reader = ExportReader::open(&path).unwrap();
+            assert_eq!((reader.format(), reader.count()), (format, 4));
+
+            let mut imported = Blockchain::empty(test_params());
+            let summary = import_chain(&mut imported, &path).unwrap();
+            assert_eq!((summary.added, summary.known), (4, 0));
+            assert_eq!(imported.tip_hash(), source.tip_hash());
+
+            let summary = import_chain(&mut imported, &path).unwrap();
+            assert_eq!((summary.added, summary.known), (0, 4));
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn corrupt_exports_are_rejected() {
+        let dir = test_dir("export-corrupt");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("chain.bin");
+        export_chain(mined_chain(1).store(), &path, ExportFormat::Binary).unwrap();
+        let good = std::fs::read(&path).unwrap();
+
+        // A flipped byte in the last block record
+        let mut bytes = good.clone();
+        *bytes.last_mut().unwrap() ^= 0xff;
+        std::fs::write(&path, &bytes).unwrap();
+        let error = import_chain(&mut Blockchain::empty(test_params()), &path).unwrap_err();
+        assert!(error.contains("failed its checksum"), "{}", error);
+
+        // A version this build does not know
+        let mut bytes = good.clone();
+        bytes[4..8].copy_from_slice(&(EXPORT_VERSION + 1).to_le_bytes());
+        std::fs::write(&path, &bytes).unwrap();
+        let error = ExportReader::open(&path).err().unwrap();
+        assert!(error.contains("Unsupported export version"), "{}", error);
+
+        let mut bytes = good;
+        bytes[0] = b'X';
+        std::fs::write(&path, &bytes).unwrap();
+        assert_eq!(ExportReader::open(&path).err().unwrap(), "Not a chain export file");
+
+        // The JSON header is checked the same way
+        let path = dir.join("chain.json");
+        export_chain(mined_chain(1).store(), &path, ExportFormat::JsonLines).unwrap();
+        let text = std::fs::read_to_string(&path).unwrap().replacen("\"version\":1", "\"version\":9", 1);
+        std::fs::write(&path, text).unwrap();
+        assert!(ExportReader::open(&path).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,191 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::block::Block;
+use crate::transaction::Transaction;
+
+/// Where a transaction sits in the active chain
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TxLocation {
+    pub block_hash: String,
+    pub height: u64,
+    /// Position of the transaction within the block (0 is the coinbase)
+    pub position: u32,
+}
+
+/// Transaction and address lookups over the active chain, updated block by
+/// block as blocks are connected and disconnected
+#[derive(Debug, Default)]
+pub struct ChainIndex {
+    /// Location of every indexed transaction, by hash
+    transactions: HashMap<String, TxLocation>,
+    /// Hashes of the transactions sending from or paying to each address, oldest first
+    addresses: HashMap<String, Vec<String>>,
+}
+
+impl ChainIndex {
+    /// Create an empty index
+    pub fn new() -> Self {
+        ChainIndex::default()
+    }
+
+    /// Number of indexed transactions
+    pub fn len(&self) -> usize {
+        self.transactions.len()
+    }
+
+    /// Check if no transactions are indexed
+    pub fn is_empty(&self) -> bool {
+        self.transactions.is_empty()
+    }
+
+    /// Add the transactions of a block connected to the active chain
+    pub fn connect_block(&mut self, block: &Block) {
+        for (position, tx) in block.transactions.iter().enumerate() {
+            let hash = tx.hash();
+            for address in touched_addresses(tx) {
+                self.addresses.entry(address.to_string()).or_default().push(hash.clone());
+            }
+            self.transactions.insert(
+                hash,
+                TxLocation {
+                    block_hash: block.hash.clone(),
+                    height: block.index,
+                    position: position as u32,
+                },
+            );
+        }
+    }
+
+    /// Remove the transactions of a block disconnected from the active chain tip
+    pub fn disconnect_block(&mut self, block: &Block) {
+        for tx in block.transactions.iter().rev() {
+            let hash = tx.hash();
+            match self.transactions.get(&hash) {
+                Some(location) if location.block_hash == block.hash => {}
+                _ => continue,
+            }
+            self.transactions.remove(&hash);
+
+            for address in touched_addresses(tx) {
+                if let Some(history) = self.addresses.get_mut(address) {
+                    if let Some(i) = history.iter().rposition(|h| *h == hash) {
+                        history.remove(i);
+                    }
+                    if history.is_empty() {
+                        self.addresses.remove(address);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Location of a transaction in the active chain
+    pub fn transaction(&self, hash: &str) -> Option<&TxLocation> {
+        self.transactions.get(hash)
+    }
+
+    /// Transactions sending from or paying to an address, oldest first
+    pub fn address_history(&self, address: &str) -> Vec<(String, TxLocation)> {
+        self.addresses
+            .get(address)
+            .into_iter()
+            .flatten()
+            .filter_map(|hash| Some((hash.clone(), self.transactions.get(hash)?.clone())))
+            .collect()
+    }
+}
+
+/// Addresses a transaction sends from or pays to, each listed once
+fn touched_addresses(tx: &Transaction) -> Vec<&str> {
+    let mut addresses: Vec<&str> = Vec::new();
+    if !tx.is_coinbase() {
+        addresses.push(&tx.from);
+    }
+    for output in &tx.outputs {
+        if !addresses.contains(&output.address.as_str()) {
+            addresses.push(&output.address);
+        }
+    }
+    addresses
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::address::Wallet;
+    use crate::blockchain::{BlockStatus, Blockchain};
+    use crate::storage::test_params;
+
+    /// Indexed chain whose first block pays `wallet`, with a second block
+    /// confirming a payment from it to `recipient`; returns the payment
+    fn chain_with_payment(wallet: &Wallet, recipient: &str) -> (Blockchain, Transaction) {
+        let mut blockchain = Blockchain::new(test_params());
+        blockchain.set_indexing(true);
+        blockchain.add_block(blockchain.block_template(&[], &wallet.address));
+
+        let mut tx = blockchain.create_transaction(&wallet.address, recipient, 10, 1).unwrap();
+        tx.sign(&wallet.secret_key).unwrap();
+        let transactions = blockchain.block_template(std::slice::from_ref(&tx), &wallet.address);
+        blockchain.add_block(transactions);
+        (blockchain, tx)
+    }
+
+    #[test]
+    fn connected_blocks_are_indexed() {
+        let wallet = Wallet::generate();
+        let recipient = Wallet::generate().address;
+        let (mut blockchain, tx) = chain_with_payment(&wallet, &recipient);
+        let block = blockchain.block_at(2).unwrap();
+
+        let location = blockchain.transaction_location(&tx.hash()).unwrap();
+        assert_eq!(location, TxLocation { block_hash: block.hash.clone(), height: 2, position: 1 });
+        assert_eq!(blockchain.get_transaction(&tx.hash()).unwrap().0, tx);
+
+        // Both coinbases and the payment touch the sender, in chain order
+        let history: Vec<String> = blockchain.address_history(&wallet.address).into_iter().map(|(h, _)| h).collect();
+        let coinbases = [blockchain.block_at(1).unwrap().transactions[0].hash(), block.transactions[0].hash()];
+        assert_eq!(history, [coinbases[0].clone(), coinbases[1].clone(), tx.hash()]);
+        assert_eq!(blockchain.address_history(&recipient), [(tx.hash(), location)]);
+
+        // Turning the index on later covers the stored blocks
+        blockchain.set_indexing(false);
+        assert!(blockchain.transaction_location(&tx.hash()).is_none());
+        blockchain.set_indexing(true);
+        assert_eq!(blockchain.index().unwrap().len(), 3);
+        assert!(blockchain.transaction_location(&tx.hash()).is_some());
+    }
+
+    #[test]
+    fn reorganization_unwinds_the_index() {
+        let wallet = Wallet::generate();
+        let recipient = Wallet::generate().address;
+        let (mut blockchain, tx) = chain_with_payment(&wallet, &recipient);
+        let replaced = blockchain.block_at(2).unwrap();
+
+        // A longer branch from block 1 without the payment
+        let miner = Wallet::generate().address;
+        let mut parent = blockchain.block_at(1).unwrap();
+        let mut status = None;
+        for index in 2..=3 {
+            let coinbase = Transaction::coinbase(miner.clone(), 0, index);
+            let mut block = Block::new(index, parent.hash.clone(), vec![coinbase], blockchain.next_bits());
+            block.header.timestamp = replaced.header.timestamp + index;
+            block.mine();
+            status = Some(blockchain.add_mined_block(block.clone()).unwrap());
+            parent = block;
+        }
+        assert!(matches!(status, Some(BlockStatus::Reorganized(_))));
+
+        assert!(blockchain.transaction_location(&tx.hash()).is_none());
+        assert!(blockchain.transaction_location(&replaced.transactions[0].hash()).is_none());
+        assert!(blockchain.address_history(&recipient).is_empty());
+        assert_eq!(blockchain.address_history(&wallet.address).len(), 1);
+
+        let history = blockchain.address_history(&miner);
+        let heights: Vec<u64> = history.iter().map(|(_, location)| location.height).collect();
+        assert_eq!(heights, [2, 3]);
+        assert_eq!(history[1].1.block_hash, parent.hash);
+    }
+}
@@ -0,0 +1,117 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use serde::{Deserialize, Serialize};
+
+/// Most hashes remembered per peer as known to it
+pub const MAX_KNOWN_INVENTORY: usize = 1000;
+
+/// Most hashes remembered as already received and validated, so an item is
+/// processed and relayed once no matter how many peers announce it
+pub const MAX_SEEN_ITEMS: usize = 10_000;
+
+/// An item announced by hash in an `Inv` message
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Inventory {
+    Block(String),
+    Transaction(String),
+}
+
+impl Inventory {
+    /// Hash of the announced item
+    pub fn hash(&self) -> &str {
+        match self {
+            Inventory::Block(hash) | Inventory::Transaction(hash) => hash,
+        }
+    }
+}
+
+impl std::fmt::Display for Inventory {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Inventory::Block(hash) => write!(f, "block {}", hash),
+            Inventory::Transaction(hash) => write!(f, "transaction {}", hash),
+        }
+    }
+}
+
+/// Set of the most recently inserted hashes, forgetting the oldest first
+#[derive(Debug)]
+pub struct RecentHashes {
+    hashes: HashSet<String>,
+    order: VecDeque<String>,
+    capacity: usize,
+}
+
+impl RecentHashes {
+    /// Create a set holding at most `capacity` hashes
+    pub fn new(capacity: usize) -> Self {
+        RecentHashes {
+            hashes: HashSet::new(),
+            order: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    /// Check if a hash is remembered
+    pub fn contains(&self, hash: &str) -> bool {
+        self.hashes.contains(hash)
+    }
+
+    /// Remember a hash, returning false if it already was
+    pub fn insert(&mut self, hash: &str) -> bool {
+        if self.capacity == 0 || self.hashes.contains(hash) {
+            return false;
+        }
+        if self.order.len() >= self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.hashes.remove(&oldest);
+            }
+        }
+        self.hashes.insert(hash.to_string());
+        self.order.push_back(hash.to_string());
+        true
+    }
+
+    /// Number of remembered hashes
+    pub fn len(&self) -> usize {
+        self.order.len()
+    }
+
+    /// Check if no hashes are remembered
+    pub fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
+}
+
+/// Inventory each peer is known to have, because it announced or sent it to
+/// us or we announced it to them, so it is not announced to them again
+#[derive(Debug, Default)]
+pub struct PeerInventory {
+    peers: HashMap<String, RecentHashes>,
+}
+
+impl PeerInventory {
+    /// Create an empty tracker
+    pub fn new() -> Self {
+        PeerInventory::default()
+    }
+
+    /// Record that `peer` has the item with `hash`, returning false if this
+    /// was already known
+    pub fn mark_known(&mut self, peer: &str, hash: &str) -> bool {
+        self.peers
+            .entry(peer.to_string())
+            .or_insert_with(|| RecentHashes::new(MAX_KNOWN_INVENTORY))
+            .insert(hash)
+    }
+
+    /// Check if `peer` is known to have the item with `hash`
+    pub fn knows(&self, peer: &str, hash: &str) -> bool {
+        self.peers.get(peer).is_some_and(|known| known.contains(hash))
+    }
+
+    /// Forget everything about a peer
+    pub fn remove_peer(&mut self, peer: &str) {
+        self.peers.remove(peer);
+    }
+}
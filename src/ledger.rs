@@ -0,0 +1,177 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::account::{AccountPending, AccountState, AccountUndo};
+use crate::block::Block;
+use crate::transaction::{OutPoint, Transaction, TxOutput};
+use crate::utxo::{UtxoPending, UtxoSet, UtxoUndo};
+
+/// How coin ownership is tracked by the chain
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LedgerModel {
+    /// Transactions spend outputs of previous transactions
+    #[default]
+    Utxo,
+    /// Transactions debit a per-address balance, ordered by nonce
+    Account,
+}
+
+impl std::str::FromStr for LedgerModel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "utxo" => Ok(LedgerModel::Utxo),
+            "account" => Ok(LedgerModel::Account),
+            _ => Err(format!("unknown ledger model '{}' (expected utxo or account)", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for LedgerModel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LedgerModel::Utxo => write!(f, "utxo"),
+            LedgerModel::Account => write!(f, "account"),
+        }
+    }
+}
+
+/// Data needed to revert a block's effect on the ledger state
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum LedgerUndo {
+    Utxo(UtxoUndo),
+    Account(AccountUndo),
+}
+
+/// Changes made by transactions that are not in a block yet, on top of a `LedgerState`
+#[derive(Debug, Clone)]
+pub enum PendingState {
+    Utxo(UtxoPending),
+    Account(AccountPending),
+}
+
+impl PendingState {
+    /// Create an empty overlay for the given ledger model
+    pub fn new(model: LedgerModel) -> Self {
+        match model {
+            LedgerModel::Utxo => PendingState::Utxo(UtxoPending::default()),
+            LedgerModel::Account => PendingState::Account(AccountPending::default()),
+        }
+    }
+}
+
+/// Ledger state derived from the blocks of the chain
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum LedgerState {
+    Utxo(UtxoSet),
+    Account(AccountState),
+}
+
+impl Default for LedgerState {
+    fn default() -> Self {
+        LedgerState::new(LedgerModel::default())
+    }
+}
+
+impl LedgerState {
+    /// Create an empty state for the given ledger model
+    pub fn new(model: LedgerModel) -> Self {
+        match model {
+            LedgerModel::Utxo => LedgerState::Utxo(UtxoSet::new()),
+            LedgerModel::Account => LedgerState::Account(AccountState::new()),
+        }
+    }
+
+    /// Ledger model of this state
+    pub fn model(&self) -> LedgerModel {
+        match self {
+            LedgerState::Utxo(_) => LedgerModel::Utxo,
+            LedgerState::Account(_) => LedgerModel::Account,
+        }
+    }
+
+    /// Check that a list of transactions can be applied in order on top of this state,
+    /// returning the total fees they pay
+    pub fn check_transactions(&self, transactions: &[Transaction]) -> Result<u64, String> {
+        match self {
+            LedgerState::Utxo(utxos) => utxos.check_transactions(transactions),
+            LedgerState::Account(accounts) => accounts.check_transactions(transactions),
+        }
+    }
+
+    /// Check that a transaction can be applied on top of this state and the
+    /// `pending` transactions, and add it to `pending`, returning its fee
+    pub fn add_pending(&self, pending: &mut PendingState, tx: &Transaction) -> Result<u64, String> {
+        match (self, pending) {
+            (LedgerState::Utxo(utxos), PendingState::Utxo(pending)) => utxos.add_pending(pending, tx),
+            (LedgerState::Account(accounts), PendingState::Account(pending)) => accounts.add_pending(pending, tx),
+            _ => panic!("pending state does not match the ledger model"),
+        }
+    }
+
+    /// Apply a block's transactions to the state, returning how to undo them
+    pub fn apply_block(&mut self, block: &Block) -> LedgerUndo {
+        match self {
+            LedgerState::Utxo(utxos) => LedgerUndo::Utxo(utxos.apply_block(block)),
+            LedgerState::Account(accounts) => LedgerUndo::Account(accounts.apply_block(block)),
+        }
+    }
+
+    /// Revert a block previously applied with `apply_block`
+    pub fn undo_block(&mut self, block: &Block, undo: LedgerUndo) {
+        match (self, undo) {
+            (LedgerState::Utxo(utxos), LedgerUndo::Utxo(undo)) => utxos.undo_block(block, undo),
+            (LedgerState::Account(accounts), LedgerUndo::Account(undo)) => accounts.undo_block(undo),
+            _ => panic!("undo data does not match the ledger model"),
+        }
+    }
+
+    /// Confirmed balance of an address
+    pub fn balance(&self, address: &str) -> u64 {
+        match self {
+            LedgerState::Utxo(utxos) => utxos.balance(address),
+            LedgerState::Account(accounts) => accounts.balance(address),
+        }
+    }
+
+    /// Fee paid by a transaction, which may depend on `pending` transactions
+    /// (by hash) it spends from. `None` if the fee cannot be determined.
+    pub fn fee(&self, tx: &Transaction, pending: &HashMap<String, &Transaction>) -> Option<u64> {
+        match self {
+            LedgerState::Utxo(utxos) => utxos.fee(tx, pending),
+            LedgerState::Account(_) => Some(tx.fee),
+        }
+    }
+
+    /// Build an unsigned transaction paying `amount` from `from` to `to` with `fee`.
+    /// In UTXO mode the fee is left unclaimed and any change is returned to the
+    /// sender; in account mode the transaction carries the sender's next nonce.
+    pub fn create_transaction(&self, from: &str, to: &str, amount: u64, fee: u64) -> Result<Transaction, String> {
+        let insufficient = || format!("Insufficient funds: {} has {}", from, self.balance(from));
+        let cost = amount.checked_add(fee).ok_or_else(insufficient)?;
+
+        match self {
+            LedgerState::Utxo(utxos) => {
+                let (inputs, total): (Vec<OutPoint>, u64) =
+                    utxos.select(from, cost).ok_or_else(insufficient)?;
+
+                let mut outputs = vec![TxOutput::new(to.to_string(), amount)];
+                if total > cost {
+                    outputs.push(TxOutput::new(from.to_string(), total - cost));
+                }
+
+                Ok(Transaction::new(from.to_string(), inputs, outputs))
+            }
+            LedgerState::Account(accounts) => {
+                if accounts.balance(from) < cost {
+                    return Err(insufficient());
+                }
+
+                let outputs = vec![TxOutput::new(to.to_string(), amount)];
+                Ok(Transaction::transfer(from.to_string(), accounts.nonce(from), fee, outputs))
+            }
+        }
+    }
+}
@@ -0,0 +1,27 @@
+pub mod account;
+pub mod address;
+pub mod ban;
+pub mod block;
+pub mod blockchain;
+pub mod codec;
+pub mod difficulty;
+pub mod error;
+pub mod export;
+pub mod index;
+pub mod inventory;
+pub mod ledger;
+pub mod mempool;
+pub mod merkle;
+pub mod message;
+pub mod network;
+pub mod network_time;
+pub mod node;
+pub mod orphan;
+pub mod params;
+pub mod peer;
+pub mod pow;
+pub mod seed;
+pub mod storage;
+pub mod store;
+pub mod transaction;
+pub mod utxo;
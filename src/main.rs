@@ -1,31 +1,133 @@
-mod block;
-mod blockchain;
-mod message;
-mod node;
-mod seed;
-mod transaction;
-
-use blockchain::Blockchain;
 use clap::{Parser, Subcommand};
-use node::Node;
-use seed::SeedNode;
+use simple_pow_chain::address::{self, Wallet};
+use simple_pow_chain::ban::{self, BanManager};
+use simple_pow_chain::blockchain::Blockchain;
+use simple_pow_chain::export::{self, ExportFormat};
+use simple_pow_chain::ledger::LedgerModel;
+use simple_pow_chain::message::MAX_BLOCK_SIZE_LIMIT;
+use simple_pow_chain::network::{self, Network};
+use simple_pow_chain::network_time;
+use simple_pow_chain::node::{self, Node};
+use simple_pow_chain::params::{ChainParams, RetargetAlgorithm};
+use simple_pow_chain::pow;
+use simple_pow_chain::seed::{self, SeedNode};
+use simple_pow_chain::storage::DiskStore;
+use simple_pow_chain::store::ChainStore;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 #[derive(Parser)]
 #[command(name = "simple-pow-chain")]
 #[command(about = "A simple Bitcoin-like PoW blockchain")]
 struct Args {
+    /// Network to join: main, test or regtest
+    #[arg(long, global = true, default_value = "main")]
+    network: Network,
+
     #[command(subcommand)]
     command: Commands,
 }
 
+/// Consensus rules, which must match across every node of a network
+#[derive(clap::Args)]
+struct ChainArgs {
+    /// Initial mining difficulty (number of leading zeros) [default: 4, test 3, regtest 1]
+    #[arg(short, long)]
+    difficulty: Option<usize>,
+
+    /// Target seconds between blocks
+    #[arg(long, default_value = "10")]
+    block_time: u64,
+
+    /// Difficulty adjustment algorithm: window or lwma
+    #[arg(long, default_value = "window")]
+    retarget: RetargetAlgorithm,
+
+    /// Number of blocks the difficulty adjustment looks back over
+    #[arg(long, default_value = "20")]
+    retarget_window: u64,
+
+    /// Ledger model: utxo or account
+    #[arg(short, long, default_value = "utxo")]
+    ledger: LedgerModel,
+
+    /// Blocks between mining reward halvings [default: 210000, regtest 150]
+    #[arg(long)]
+    halving_interval: Option<u64>,
+
+    /// Maximum number of coins that can ever be mined
+    #[arg(long, default_value = "21000000")]
+    max_supply: u64,
+
+    /// Maximum total size of a block's transactions in bytes
+    #[arg(long, default_value = "1000000", value_parser = parse_max_block_size)]
+    max_block_size: usize,
+}
+
+/// Parse `--max-block-size`: larger blocks would not fit in a block message
+fn parse_max_block_size(arg: &str) -> Result<usize, String> {
+    let size: usize = arg.parse().map_err(|e| format!("{}", e))?;
+    if size > MAX_BLOCK_SIZE_LIMIT {
+        return Err(format!("blocks of more than {} bytes cannot be relayed", MAX_BLOCK_SIZE_LIMIT));
+    }
+    Ok(size)
+}
+
+impl ChainArgs {
+    fn difficulty(&self, network: Network) -> usize {
+        self.difficulty.unwrap_or(network.default_difficulty())
+    }
+
+    /// The network's consensus rules with the options given on the command line
+    fn params(&self, network: Network) -> ChainParams {
+        let defaults = network.params();
+        let bits = pow::bits_for_leading_zeros(self.difficulty(network));
+        ChainParams {
+            ledger: self.ledger,
+            halving_interval: self.halving_interval.unwrap_or(defaults.halving_interval),
+            max_supply: self.max_supply,
+            max_block_size: self.max_block_size,
+            genesis_bits: bits,
+            pow_limit_bits: bits,
+            target_block_time: self.block_time,
+            retarget: self.retarget,
+            difficulty_window: self.retarget_window,
+            ..defaults
+        }
+    }
+}
+
+/// Access to a running node's ban management
+#[derive(clap::Args)]
+struct AdminArgs {
+    /// Data directory of the node, to read its admin token from
+    #[arg(long)]
+    data_dir: Option<PathBuf>,
+
+    /// Admin token the node printed (if it runs without a data directory)
+    #[arg(long)]
+    token: Option<String>,
+}
+
+impl AdminArgs {
+    /// The node's admin token, given directly or read from its data directory
+    fn token(&self) -> Result<String, String> {
+        match (&self.token, &self.data_dir) {
+            (Some(token), _) => Ok(token.clone()),
+            (None, Some(dir)) => ban::read_admin_token(&dir.join(ban::ADMIN_TOKEN_FILE)),
+            (None, None) => Err(String::from("Give the node's --data-dir or its admin --token")),
+        }
+    }
+}
+
 #[derive(Subcommand)]
 enum Commands {
     /// Run a blockchain node
     Node {
-        /// Port to listen on
-        #[arg(short, long, default_value = "8080")]
-        port: u16,
+        /// Port to listen on [default: 8080, test 18080, regtest 28080]
+        #[arg(short, long)]
+        port: Option<u16>,
 
         /// Seed node address to discover peers (e.g., 127.0.0.1:9000)
         #[arg(short, long)]
@@ -35,13 +137,33 @@ enum Commands {
         #[arg(short = 'e', long)]
         peer: Option<String>,
 
-        /// Mining difficulty (number of leading zeros)
-        #[arg(short, long, default_value = "4")]
-        difficulty: usize,
+        #[command(flatten)]
+        chain: ChainArgs,
+
+        /// Miner address for rewards (a new wallet is generated if omitted)
+        #[arg(short, long)]
+        miner: Option<String>,
+
+        /// Directory to store the blockchain in (kept in memory only if omitted)
+        #[arg(long)]
+        data_dir: Option<PathBuf>,
+
+        /// Keep only the bodies of the last N blocks (headers and state are always kept)
+        #[arg(long, value_name = "N")]
+        prune: Option<u64>,
 
-        /// Miner address for rewards
-        #[arg(short, long, default_value = "miner")]
-        miner: String,
+        /// Index transactions and addresses so peers can look them up
+        #[arg(long)]
+        index: bool,
+
+        /// Seconds a misbehaving peer stays banned
+        #[arg(long, value_name = "SECS", default_value_t = ban::DEFAULT_BAN_TIME)]
+        ban_time: u64,
+
+        /// IP address whose peers are never banned, e.g. 127.0.0.1 when
+        /// running several nodes on this machine (repeatable)
+        #[arg(long, value_name = "IP")]
+        whitelist: Vec<IpAddr>,
 
         /// Disable auto-mining
         #[arg(long)]
@@ -49,44 +171,206 @@ enum Commands {
     },
     /// Run a seed node for peer discovery
     Seed {
-        /// Port to listen on
-        #[arg(short, long, default_value = "9000")]
-        port: u16,
+        /// Port to listen on [default: 9000, test 19000, regtest 29000]
+        #[arg(short, long)]
+        port: Option<u16>,
+    },
+    /// Look up a confirmed transaction on a running node (needs `--index`)
+    Tx {
+        /// Transaction hash
+        hash: String,
+
+        /// Node to ask [default: 127.0.0.1 on the network's default port]
+        #[arg(short, long)]
+        node: Option<String>,
+    },
+    /// List the confirmed transactions of an address on a running node (needs `--index`)
+    History {
+        /// Address to look up
+        address: String,
+
+        /// Node to ask [default: 127.0.0.1 on the network's default port]
+        #[arg(short, long)]
+        node: Option<String>,
+    },
+    /// List the peers a running node has banned
+    Bans {
+        /// Node to ask [default: 127.0.0.1 on the network's default port]
+        #[arg(short, long)]
+        node: Option<String>,
+
+        #[command(flatten)]
+        admin: AdminArgs,
+    },
+    /// Lift the ban of a peer on a running node, or every ban if no peer is given
+    Unban {
+        /// IP address of the peer to unban
+        peer: Option<IpAddr>,
+
+        /// Node to ask [default: 127.0.0.1 on the network's default port]
+        #[arg(short, long)]
+        node: Option<String>,
+
+        #[command(flatten)]
+        admin: AdminArgs,
+    },
+    /// Write the stored chain to an export file
+    Export {
+        /// Directory the chain is stored in
+        #[arg(long)]
+        data_dir: PathBuf,
+
+        /// File to write
+        #[arg(short, long)]
+        out: PathBuf,
+
+        /// Export format: binary or json (JSON lines)
+        #[arg(short, long, default_value = "binary")]
+        format: ExportFormat,
+    },
+    /// Validate the blocks of an export file and add them to the stored chain
+    Import {
+        /// Directory the chain is stored in (created if missing)
+        #[arg(long)]
+        data_dir: PathBuf,
+
+        /// Export file to read (binary or JSON lines, detected automatically)
+        #[arg(short, long = "in")]
+        input: PathBuf,
+
+        #[command(flatten)]
+        chain: ChainArgs,
+    },
+    /// Generate a new key pair and address
+    Wallet {
+        /// Show the address of an existing hex-encoded secret key instead
+        #[arg(short, long)]
+        secret: Option<String>,
     },
 }
 
 #[tokio::main]
 async fn main() {
     let args = Args::parse();
+    let network = args.network;
+    let local_node = || format!("127.0.0.1:{}", network.default_port());
 
     match args.command {
         Commands::Node {
             port,
             seed,
             peer,
-            difficulty,
+            chain,
             miner,
+            data_dir,
+            prune,
+            index,
+            ban_time,
+            whitelist,
             no_mine,
         } => {
-            run_node(port, seed, peer, difficulty, miner, !no_mine).await;
+            let options = NodeOptions {
+                network,
+                port: port.unwrap_or(network.default_port()),
+                seed,
+                peer,
+                difficulty: chain.difficulty(network),
+                miner,
+                data_dir,
+                prune,
+                index,
+                ban_time,
+                whitelist,
+                mine: !no_mine,
+            };
+            run_node(options, chain.params(network)).await;
         }
         Commands::Seed { port } => {
-            run_seed(port).await;
+            run_seed(network, port.unwrap_or(network.default_seed_port())).await;
+        }
+        Commands::Tx { hash, node } => {
+            run_tx_query(&node.unwrap_or_else(local_node), network, &hash).await;
+        }
+        Commands::History { address, node } => {
+            run_history_query(&node.unwrap_or_else(local_node), network, &address).await;
+        }
+        Commands::Bans { node, admin } => {
+            run_bans_query(&node.unwrap_or_else(local_node), network, &admin).await;
+        }
+        Commands::Unban { peer, node, admin } => {
+            run_unban(&node.unwrap_or_else(local_node), network, &admin, peer).await;
+        }
+        Commands::Export { data_dir, out, format } => {
+            run_export(&data_dir, &out, format);
+        }
+        Commands::Import { data_dir, input, chain } => {
+            run_import(&data_dir, &input, chain.params(network));
+        }
+        Commands::Wallet { secret } => {
+            run_wallet(secret);
         }
     }
 }
 
-async fn run_node(
+/// Node settings that are local to this node (not consensus rules)
+struct NodeOptions {
+    network: Network,
     port: u16,
-    seed_addr: Option<String>,
+    seed: Option<String>,
     peer: Option<String>,
     difficulty: usize,
-    miner: String,
+    miner: Option<String>,
+    data_dir: Option<PathBuf>,
+    prune: Option<u64>,
+    index: bool,
+    ban_time: u64,
+    whitelist: Vec<IpAddr>,
     mine: bool,
-) {
+}
+
+async fn run_node(options: NodeOptions, params: ChainParams) {
+    let NodeOptions {
+        network,
+        port,
+        seed: seed_addr,
+        peer,
+        difficulty,
+        miner,
+        data_dir,
+        prune,
+        index,
+        ban_time,
+        whitelist,
+        mine,
+    } = options;
+
     println!("=== Simple PoW Chain ===");
+    println!("Network: {} (chain id {})", network, network::chain_id(network, &params));
     println!("Port: {}", port);
-    println!("Difficulty: {}", difficulty);
+    println!("Difficulty: {} (bits {:08x})", difficulty, params.genesis_bits);
+    println!(
+        "Retarget: {} over {} blocks, {}s block time",
+        params.retarget, params.difficulty_window, params.target_block_time
+    );
+    println!("Ledger: {}", params.ledger);
+    println!("Halving interval: {} blocks", params.halving_interval);
+
+    // Resolve miner reward address
+    let miner = match miner {
+        Some(addr) => {
+            if let Err(e) = address::decode(&addr) {
+                eprintln!("{}", e);
+                return;
+            }
+            addr
+        }
+        None => {
+            let wallet = Wallet::generate();
+            println!("Generated miner wallet (secret key: {})", wallet.secret_key);
+            wallet.address
+        }
+    };
+    println!("Miner: {}", miner);
 
     // Setup node address
     let addr = format!("127.0.0.1:{}", port);
@@ -99,7 +383,7 @@ async fn run_node(
         println!("Connecting to seed node: {}", seed);
 
         // Get peer list from seed first (before registering)
-        match seed::get_peers_from_seed(seed).await {
+        match seed::get_peers_from_seed(seed, network).await {
             Ok(discovered) => {
                 println!("Discovered {} peers from seed", discovered.len());
                 for p in discovered {
@@ -112,7 +396,7 @@ async fn run_node(
         }
 
         // Then register ourselves with seed
-        if let Err(e) = seed::register_with_seed(seed, &addr).await {
+        if let Err(e) = seed::register_with_seed(seed, network, &addr).await {
             eprintln!("Failed to register with seed: {}", e);
         } else {
             println!("Registered with seed node");
@@ -123,12 +407,81 @@ async fn run_node(
         println!("Peers: {:?}", peers);
     }
 
-    // Start with empty blockchain, will sync or create genesis as needed
-    let blockchain = Blockchain::empty(difficulty);
-    let node = Arc::new(Node::new(blockchain, addr.clone(), peers.clone()));
+    // Ban management is opened with a fresh token each run, kept in the
+    // data directory for the `bans` and `unban` commands
+    let admin_token = ban::generate_admin_token();
+
+    // Load the stored chain and ban list or start empty, then sync or create genesis as needed
+    match data_dir {
+        Some(dir) => {
+            let blockchain = match Blockchain::open(params, &dir) {
+                Ok(blockchain) => blockchain,
+                Err(e) => {
+                    eprintln!("Failed to open blockchain in {}: {}", dir.display(), e);
+                    return;
+                }
+            };
+            println!("Loaded {} blocks from {}", blockchain.len(), dir.display());
+            let mut bans = match BanManager::open(&dir.join(ban::BAN_FILE), ban_time) {
+                Ok(bans) => bans,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    return;
+                }
+            };
+            whitelist.iter().for_each(|ip| bans.whitelist(*ip));
+            let token_path = dir.join(ban::ADMIN_TOKEN_FILE);
+            if let Err(e) = ban::write_admin_token(&token_path, &admin_token) {
+                eprintln!("{}", e);
+                return;
+            }
+            println!("Admin token written to {}", token_path.display());
+            start_node(blockchain, bans, admin_token, network, addr, peers, miner, prune, index, mine).await;
+        }
+        None => {
+            let mut bans = BanManager::new(ban_time);
+            whitelist.iter().for_each(|ip| bans.whitelist(*ip));
+            println!("Admin token: {}", admin_token);
+            let blockchain = Blockchain::empty(params);
+            start_node(blockchain, bans, admin_token, network, addr, peers, miner, prune, index, mine).await
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn start_node<S: ChainStore + 'static>(
+    mut blockchain: Blockchain<S>,
+    bans: BanManager,
+    admin_token: String,
+    network: Network,
+    addr: String,
+    peers: Vec<String>,
+    miner: String,
+    prune: Option<u64>,
+    index: bool,
+    mine: bool,
+) {
+    if let Err(e) = blockchain.set_prune_depth(prune) {
+        eprintln!("{}", e);
+        return;
+    }
+    if let Some(depth) = prune {
+        println!("Pruning: keeping the last {} blocks", depth);
+    }
+    if index {
+        blockchain.set_indexing(true);
+        println!("Transaction index enabled");
+    }
+
+    let node = Arc::new(Node::new(blockchain, network, addr.clone(), peers.clone()));
+    let banned = bans.bans().len();
+    if banned > 0 {
+        println!("{} peers banned", banned);
+    }
+    node.set_bans(bans);
+    node.set_admin_token(admin_token);
 
     // Try to sync from peers
-    let mut synced = false;
     if !peers.is_empty() {
         println!("Syncing from peers...");
         tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
@@ -138,17 +491,18 @@ async fn run_node(
             let chain_len = node.blockchain.read().unwrap().len();
             if chain_len > 0 {
                 println!("Synced blockchain with {} blocks", chain_len);
-                synced = true;
             }
         }
     }
 
-    // If no sync happened, create genesis block
-    if !synced {
-        println!("No peers to sync from, creating genesis block...");
+    // If there is still no chain, create genesis block
+    {
         let mut bc = node.blockchain.write().unwrap();
-        *bc = Blockchain::new(difficulty);
-        println!("Genesis block created");
+        if bc.is_empty() {
+            println!("No chain to continue, creating genesis block...");
+            bc.create_genesis();
+            println!("Genesis block created");
+        }
     }
 
     // Start mining in background if enabled
@@ -173,12 +527,131 @@ async fn run_node(
     }
 }
 
-async fn run_seed(port: u16) {
-    println!("=== Seed Node ===");
+async fn run_seed(network: Network, port: u16) {
+    println!("=== Seed Node ({}) ===", network);
     let addr = format!("127.0.0.1:{}", port);
-    let seed = SeedNode::new(addr);
+    let seed = SeedNode::new(addr, network);
 
     if let Err(e) = seed.start().await {
         eprintln!("Seed node error: {}", e);
     }
 }
+
+async fn run_tx_query(node_addr: &str, network: Network, hash: &str) {
+    match node::query_transaction(node_addr, network, hash).await {
+        Ok(Some((tx, location))) => {
+            println!("Block:    #{} {}", location.height, location.block_hash);
+            println!("Position: {}", location.position);
+            println!("{}", tx);
+        }
+        Ok(None) => println!("Transaction {} not found", hash),
+        Err(e) => eprintln!("Failed to query {}: {}", node_addr, e),
+    }
+}
+
+async fn run_history_query(node_addr: &str, network: Network, address: &str) {
+    match node::query_address_history(node_addr, network, address).await {
+        Ok(history) => {
+            println!("{} transactions for {}", history.len(), address);
+            for (hash, location) in history {
+                println!("  #{} [{}] {}", location.height, location.position, hash);
+            }
+        }
+        Err(e) => eprintln!("Failed to query {}: {}", node_addr, e),
+    }
+}
+
+async fn run_bans_query(node_addr: &str, network: Network, admin: &AdminArgs) {
+    let token = match admin.token() {
+        Ok(token) => token,
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    };
+    match node::query_bans(node_addr, network, &token).await {
+        Ok(bans) => {
+            println!("{} banned peers", bans.len());
+            let now = network_time::local_time();
+            for ban in bans {
+                println!(
+                    "  {} for {}s more ({})",
+                    ban.ip,
+                    ban.until.saturating_sub(now),
+                    ban.reason
+                );
+            }
+        }
+        Err(e) => eprintln!("Failed to query {}: {}", node_addr, e),
+    }
+}
+
+async fn run_unban(node_addr: &str, network: Network, admin: &AdminArgs, peer: Option<IpAddr>) {
+    let token = match admin.token() {
+        Ok(token) => token,
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    };
+    match node::clear_bans(node_addr, network, &token, peer).await {
+        Ok(count) => match peer {
+            Some(peer) if count == 0 => println!("{} was not banned", peer),
+            Some(peer) => println!("Unbanned {}", peer),
+            None => println!("Lifted {} bans", count),
+        },
+        Err(e) => eprintln!("Failed to reach {}: {}", node_addr, e),
+    }
+}
+
+fn run_export(data_dir: &Path, out: &Path, format: ExportFormat) {
+    let store = match DiskStore::open(data_dir) {
+        Ok(store) => store,
+        Err(e) => {
+            eprintln!("Failed to open {}: {}", data_dir.display(), e);
+            return;
+        }
+    };
+
+    match export::export_chain(&store, out, format) {
+        Ok(count) => println!("Exported {} blocks to {} ({})", count, out.display(), format),
+        Err(e) => eprintln!("Export failed: {}", e),
+    }
+}
+
+fn run_import(data_dir: &Path, input: &Path, params: ChainParams) {
+    let mut blockchain = match Blockchain::open(params, data_dir) {
+        Ok(blockchain) => blockchain,
+        Err(e) => {
+            eprintln!("Failed to open blockchain in {}: {}", data_dir.display(), e);
+            return;
+        }
+    };
+
+    match export::import_chain(&mut blockchain, input) {
+        Ok(summary) => println!(
+            "Imported {} blocks ({} already known), chain now at {} blocks",
+            summary.added,
+            summary.known,
+            blockchain.len()
+        ),
+        Err(e) => eprintln!("Import failed: {} (chain at {} blocks)", e, blockchain.len()),
+    }
+}
+
+fn run_wallet(secret: Option<String>) {
+    let wallet = match secret {
+        Some(secret) => match Wallet::from_secret_key(&secret) {
+            Ok(wallet) => wallet,
+            Err(e) => {
+                eprintln!("Invalid secret key: {}", e);
+                return;
+            }
+        },
+        None => Wallet::generate(),
+    };
+
+    println!("Address:    {}", wallet.address);
+    println!("Public key: {}", wallet.public_key);
+    println!("Secret key: {}", wallet.secret_key);
+}
@@ -0,0 +1,176 @@
+use std::collections::HashMap;
+
+use crate::blockchain::Blockchain;
+use crate::error::ValidationError;
+use crate::ledger::{LedgerModel, PendingState};
+use crate::store::ChainStore;
+use crate::transaction::Transaction;
+
+/// Transactions waiting to be mined, in arrival order, together with the
+/// ledger changes they make on top of the active chain. A new transaction is
+/// checked against those changes, so admitting it never replays the pool.
+#[derive(Debug)]
+pub struct Mempool {
+    transactions: Vec<Transaction>,
+    /// Position of each transaction in `transactions`, by hash
+    positions: HashMap<String, usize>,
+    pending: PendingState,
+}
+
+impl Mempool {
+    /// Create an empty mempool for a chain with the given ledger model
+    pub fn new(model: LedgerModel) -> Self {
+        Mempool {
+            transactions: Vec::new(),
+            positions: HashMap::new(),
+            pending: PendingState::new(model),
+        }
+    }
+
+    /// Pending transactions, oldest first
+    pub fn transactions(&self) -> &[Transaction] {
+        &self.transactions
+    }
+
+    /// Number of pending transactions
+    pub fn len(&self) -> usize {
+        self.transactions.len()
+    }
+
+    /// Check if no transactions are pending
+    pub fn is_empty(&self) -> bool {
+        self.transactions.is_empty()
+    }
+
+    /// Check if a transaction is pending
+    pub fn contains(&self, hash: &str) -> bool {
+        self.positions.contains_key(hash)
+    }
+
+    /// Look up a pending transaction
+    pub fn get(&self, hash: &str) -> Option<&Transaction> {
+        self.positions.get(hash).map(|&position| &self.transactions[position])
+    }
+
+    /// Verify a transaction against the chain and the pending transactions
+    /// and add it
+    pub fn add<S: ChainStore>(&mut self, blockchain: &Blockchain<S>, tx: Transaction) -> Result<(), ValidationError> {
+        let hash = tx.hash();
+        if self.contains(&hash) {
+            return Err(ValidationError::DuplicateTransaction(hash));
+        }
+
+        blockchain.validate_transaction(&tx, &mut self.pending)?;
+        self.push(hash, tx);
+        Ok(())
+    }
+
+    /// Recheck the pending transactions after the active chain changed,
+    /// dropping those that were confirmed or are no longer valid. `returned`
+    /// transactions (from disconnected blocks) go first, since pending ones
+    /// may spend from them. Each transaction is checked once; signatures were
+    /// already checked when it was first accepted.
+    pub fn refresh<S: ChainStore>(&mut self, blockchain: &Blockchain<S>, returned: Vec<Transaction>) {
+        let previous = std::mem::take(&mut self.transactions);
+        self.positions.clear();
+        self.pending = PendingState::new(blockchain.params.ledger);
+
+        let state = blockchain.state();
+        for tx in returned.into_iter().chain(previous) {
+            let hash = tx.hash();
+            if !self.contains(&hash) && state.add_pending(&mut self.pending, &tx).is_ok() {
+                self.push(hash, tx);
+            }
+        }
+    }
+
+    fn push(&mut self, hash: String, tx: Transaction) {
+        self.positions.insert(hash, self.transactions.len());
+        self.transactions.push(tx);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::address::Wallet;
+    use crate::storage::test_params;
+    use crate::transaction::{OutPoint, TxOutput};
+
+    /// Chain whose only mined block pays its reward to `wallet`, and that reward
+    fn funded(wallet: &Wallet) -> (Blockchain, OutPoint, u64) {
+        let mut blockchain = Blockchain::new(test_params());
+        let block = blockchain.add_block(blockchain.block_template(&[], &wallet.address));
+        let coinbase = &block.transactions[0];
+        (blockchain, OutPoint::new(coinbase.hash(), 0), coinbase.outputs[0].amount)
+    }
+
+    /// Signed transaction spending `input` (worth `value`) of `from`, paying
+    /// `amount` to a new address and the rest back as change (output 1)
+    fn payment(from: &Wallet, input: &OutPoint, value: u64, amount: u64) -> Transaction {
+        let outputs = vec![
+            TxOutput::new(Wallet::generate().address, amount),
+            TxOutput::new(from.address.clone(), value - amount),
+        ];
+        let mut tx = Transaction::new(from.address.clone(), vec![input.clone()], outputs);
+        tx.sign(&from.secret_key).unwrap();
+        tx
+    }
+
+    #[test]
+    fn transactions_are_checked_against_the_pending_ones() {
+        let wallet = Wallet::generate();
+        let (blockchain, coin, value) = funded(&wallet);
+        let mut mempool = Mempool::new(blockchain.params.ledger);
+
+        let parent = payment(&wallet, &coin, value, 10);
+        mempool.add(&blockchain, parent.clone()).unwrap();
+        assert_eq!(
+            mempool.add(&blockchain, parent.clone()),
+            Err(ValidationError::DuplicateTransaction(parent.hash()))
+        );
+
+        // Spending the change of a pending transaction is fine, spending
+        // its input again is not
+        let child = payment(&wallet, &OutPoint::new(parent.hash(), 1), value - 10, 10);
+        mempool.add(&blockchain, child.clone()).unwrap();
+        assert!(matches!(
+            mempool.add(&blockchain, payment(&wallet, &coin, value, 5)),
+            Err(ValidationError::InvalidSpend(_))
+        ));
+        assert_eq!(mempool.transactions(), [parent, child.clone()]);
+        assert_eq!(mempool.get(&child.hash()), Some(&child));
+    }
+
+    #[test]
+    fn refresh_drops_confirmed_transactions_and_keeps_their_children() {
+        let wallet = Wallet::generate();
+        let (mut blockchain, coin, value) = funded(&wallet);
+        let mut mempool = Mempool::new(blockchain.params.ledger);
+        let parent = payment(&wallet, &coin, value, 10);
+        let child = payment(&wallet, &OutPoint::new(parent.hash(), 1), value - 10, 10);
+        mempool.add(&blockchain, parent.clone()).unwrap();
+        mempool.add(&blockchain, child.clone()).unwrap();
+
+        blockchain.add_block(blockchain.block_template(std::slice::from_ref(&parent), &wallet.address));
+        mempool.refresh(&blockchain, vec![]);
+        assert!(!mempool.contains(&parent.hash()));
+        assert_eq!(mempool.transactions(), std::slice::from_ref(&child));
+    }
+
+    #[test]
+    fn returned_transactions_go_ahead_of_pending_ones() {
+        let wallet = Wallet::generate();
+        let (blockchain, coin, value) = funded(&wallet);
+        let parent = payment(&wallet, &coin, value, 10);
+        let child = payment(&wallet, &OutPoint::new(parent.hash(), 1), value - 10, 10);
+
+        // As after a reorganization disconnected the block confirming `parent`
+        let mut mempool = Mempool::new(blockchain.params.ledger);
+        mempool.refresh(&blockchain, vec![parent.clone()]);
+        mempool.add(&blockchain, child.clone()).unwrap();
+        mempool.refresh(&blockchain, vec![]);
+        assert_eq!(mempool.transactions(), [parent, child]);
+    }
+}
@@ -0,0 +1,176 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::transaction::Transaction;
+
+/// Root of a tree with no leaves
+pub fn empty_root() -> String {
+    "0".repeat(64)
+}
+
+/// Hash two child nodes into their parent
+fn hash_pair(left: &str, right: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(hex::decode(left).unwrap_or_default());
+    hasher.update(hex::decode(right).unwrap_or_default());
+    hex::encode(hasher.finalize())
+}
+
+/// Compute the next level of the tree. A node without a sibling is carried
+/// up unchanged, so a list and the same list with its last entry repeated
+/// never share a root.
+fn next_level(level: &[String]) -> Vec<String> {
+    level
+        .chunks(2)
+        .map(|pair| match pair {
+            [left, right] => hash_pair(left, right),
+            [single] => single.clone(),
+            _ => unreachable!(),
+        })
+        .collect()
+}
+
+/// Merkle root over a list of transaction leaf hashes
+pub fn merkle_root(hashes: &[String]) -> String {
+    if hashes.is_empty() {
+        return empty_root();
+    }
+
+    let mut level = hashes.to_vec();
+    while level.len() > 1 {
+        level = next_level(&level);
+    }
+    level.remove(0)
+}
+
+/// One step of an inclusion proof: the sibling hash and which side it is on
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MerkleStep {
+    pub hash: String,
+    pub is_left: bool,
+}
+
+/// Proof that a transaction is included under a Merkle root
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MerkleProof {
+    /// Leaf hash of the transaction (`Transaction::leaf_hash`)
+    pub leaf: String,
+    pub steps: Vec<MerkleStep>,
+}
+
+impl MerkleProof {
+    /// Build the inclusion proof for the leaf at `index`
+    pub fn new(hashes: &[String], index: usize) -> Option<Self> {
+        let leaf = hashes.get(index)?.clone();
+        let mut steps = Vec::new();
+        let mut level = hashes.to_vec();
+        let mut position = index;
+
+        while level.len() > 1 {
+            let sibling = position ^ 1;
+            if let Some(hash) = level.get(sibling) {
+                steps.push(MerkleStep {
+                    hash: hash.clone(),
+                    is_left: sibling < position,
+                });
+            }
+            level = next_level(&level);
+            position /= 2;
+        }
+
+        Some(MerkleProof { leaf, steps })
+    }
+
+    /// Recompute the root from the proof
+    pub fn root(&self) -> String {
+        self.steps.iter().fold(self.leaf.clone(), |node, step| {
+            if step.is_left {
+                hash_pair(&step.hash, &node)
+            } else {
+                hash_pair(&node, &step.hash)
+            }
+        })
+    }
+
+    /// Check that the proof connects its leaf to `merkle_root`. This says
+    /// nothing about which transaction the leaf is: see `verify_transaction`.
+    pub fn verify(&self, merkle_root: &str) -> bool {
+        self.root() == merkle_root
+    }
+
+    /// Check that the proof is for `tx` and connects it to `merkle_root`
+    pub fn verify_transaction(&self, tx: &Transaction, merkle_root: &str) -> bool {
+        self.leaf == tx.leaf_hash() && self.verify(merkle_root)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaves(count: usize) -> Vec<String> {
+        (0..count)
+            .map(|i| hex::encode(Sha256::digest(i.to_le_bytes())))
+            .collect()
+    }
+
+    #[test]
+    fn single_leaf_is_its_own_root() {
+        let hashes = leaves(1);
+        assert_eq!(merkle_root(&hashes), hashes[0]);
+        assert_eq!(merkle_root(&[]), empty_root());
+    }
+
+    #[test]
+    fn every_leaf_of_odd_and_even_trees_has_a_proof() {
+        for count in 1..=9 {
+            let hashes = leaves(count);
+            let root = merkle_root(&hashes);
+            for index in 0..count {
+                let proof = MerkleProof::new(&hashes, index).unwrap();
+                assert_eq!(proof.leaf, hashes[index]);
+                assert!(proof.verify(&root), "leaf {} of {}", index, count);
+                assert!(!proof.verify(&empty_root()));
+            }
+            assert!(MerkleProof::new(&hashes, count).is_none());
+        }
+    }
+
+    #[test]
+    fn tampered_proofs_fail() {
+        let hashes = leaves(5);
+        let root = merkle_root(&hashes);
+        let proof = MerkleProof::new(&hashes, 2).unwrap();
+
+        let mut wrong_leaf = proof.clone();
+        wrong_leaf.leaf = hashes[3].clone();
+        assert!(!wrong_leaf.verify(&root));
+
+        let mut wrong_side = proof.clone();
+        wrong_side.steps[0].is_left = !wrong_side.steps[0].is_left;
+        assert!(!wrong_side.verify(&root));
+    }
+
+    #[test]
+    fn proofs_are_tied_to_their_transaction() {
+        let transactions: Vec<Transaction> =
+            (0..3).map(|height| Transaction::coinbase(String::from("miner"), 50, height)).collect();
+        let hashes: Vec<String> = transactions.iter().map(Transaction::leaf_hash).collect();
+        let root = merkle_root(&hashes);
+
+        let proof = MerkleProof::new(&hashes, 1).unwrap();
+        assert!(proof.verify_transaction(&transactions[1], &root));
+        // A valid proof, but for another leaf
+        assert!(proof.verify(&root));
+        assert!(!proof.verify_transaction(&transactions[2], &root));
+        assert!(!proof.verify_transaction(&transactions[1], &empty_root()));
+    }
+
+    #[test]
+    fn repeating_the_last_leaf_changes_the_root() {
+        let hashes = leaves(3);
+        let mut repeated = hashes.clone();
+        repeated.push(hashes[2].clone());
+        assert_ne!(merkle_root(&hashes), merkle_root(&repeated));
+    }
+}
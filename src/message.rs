@@ -1,24 +1,239 @@
+use std::net::IpAddr;
+
 use serde::{Deserialize, Serialize};
 
-use crate::block::Block;
-use crate::blockchain::Blockchain;
+use crate::ban::Ban;
+use crate::block::{Block, BlockHeader};
+use crate::index::TxLocation;
+use crate::inventory::Inventory;
 use crate::transaction::Transaction;
 
+/// Most headers sent in one `Headers` message
+pub const MAX_HEADERS_PER_MESSAGE: usize = 2000;
+
+/// Most blocks requested or sent in one `GetBlocks`/`Blocks` exchange
+pub const MAX_BLOCKS_PER_MESSAGE: usize = 16;
+
+/// Most items announced or requested in one `Inv` or `GetData` message
+pub const MAX_INVENTORY_PER_MESSAGE: usize = 500;
+
+/// Largest encoded message of any type
+pub const MAX_MESSAGE_SIZE: usize = 32 * 1024 * 1024;
+
+/// Largest encoded message carrying a single block
+pub const MAX_BLOCK_MESSAGE_SIZE: usize = 4 * 1024 * 1024;
+
+/// Largest `max_block_size` a chain may use, leaving room for the header
+/// and envelope of a block message
+pub const MAX_BLOCK_SIZE_LIMIT: usize = MAX_BLOCK_MESSAGE_SIZE - 1024;
+
+/// Largest encoded control message (handshake, status, single lookups)
+pub const MAX_CONTROL_MESSAGE_SIZE: usize = 4 * 1024;
+
+/// Handshake announcement of a node
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Version {
+    /// Peer protocol version
+    pub version: u32,
+    /// Network the node belongs to; nodes on different networks do not talk
+    pub network: String,
+    /// Chain id (`network::chain_id`): the network and its consensus rules
+    pub chain_id: String,
+    /// Hash of the node's genesis block, if it has one yet
+    pub genesis: Option<String>,
+    /// Length of the node's active chain
+    pub best_height: u64,
+    /// Service flags (`peer::SERVICE_*`)
+    pub services: u64,
+    /// Listening address of the node (empty for clients that do not listen)
+    pub addr: String,
+    /// The node's clock, in seconds since the Unix epoch
+    pub timestamp: u64,
+}
+
 /// Network messages for P2P communication
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Message {
-    /// Broadcast a newly mined block
-    NewBlock(Block),
-    /// Broadcast a new transaction
+    /// Open a session, announcing who we are
+    Version(Version),
+    /// Accept the other side's `Version`
+    Verack,
+    /// Announce blocks and transactions by hash
+    Inv(Vec<Inventory>),
+    /// Request announced items
+    GetData(Vec<Inventory>),
+    /// Response with the requested items the peer has
+    Data { blocks: Vec<Block>, transactions: Vec<Transaction> },
+    /// Submit a transaction directly (e.g. from a wallet)
     NewTransaction(Transaction),
-    /// Request the full blockchain
-    GetBlocks,
-    /// Response with the full blockchain
-    Blocks(Blockchain),
+    /// Request the active chain headers after the first hash of a block
+    /// locator the peer knows
+    GetHeaders(Vec<String>),
+    /// Response with consecutive active chain headers, oldest first
+    Headers(Vec<BlockHeader>),
+    /// Request blocks by hash
+    GetBlocks(Vec<String>),
+    /// Response with the requested blocks the peer has (pruned bodies are left out)
+    Blocks(Vec<Block>),
+    /// Request a single block by hash
+    GetBlock(String),
+    /// Response with the requested block, if known and not pruned
+    Block(Option<Block>),
+    /// Look up a confirmed transaction by hash
+    GetTransaction(String),
+    /// Response with the transaction and where it was confirmed, if indexed
+    TransactionInfo(Option<(Transaction, TxLocation)>),
+    /// Request the confirmed transactions touching an address
+    GetAddressHistory(String),
+    /// Response with the hashes and locations of the address's transactions, oldest first
+    AddressHistory(Vec<(String, TxLocation)>),
+    /// Request the peer's chain status
+    GetStatus,
+    /// Response with the length of the peer's active chain and the lowest
+    /// height it still serves block bodies for (non-zero if it prunes)
+    Status { height: u64, pruned_height: u64 },
     /// Register with seed node (send our address)
     Register(String),
     /// Request peer list from seed node
     GetPeers,
     /// Response with peer list
     Peers(Vec<String>),
+    /// Request the node's ban list, with the node's admin token
+    GetBans(String),
+    /// Response with the bans that have not expired
+    Bans(Vec<Ban>),
+    /// Lift the ban of a peer IP, or every ban if none is given, with the
+    /// node's admin token
+    ClearBans(String, Option<IpAddr>),
+    /// Response with the number of bans lifted
+    BansCleared(u64),
+}
+
+/// Type of a message, sent in the frame header so its size limit can be
+/// checked before the message is read
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Command {
+    Version,
+    Verack,
+    Inv,
+    GetData,
+    Data,
+    NewTransaction,
+    GetHeaders,
+    Headers,
+    GetBlocks,
+    Blocks,
+    GetBlock,
+    Block,
+    GetTransaction,
+    TransactionInfo,
+    GetAddressHistory,
+    AddressHistory,
+    GetStatus,
+    Status,
+    Register,
+    GetPeers,
+    Peers,
+    GetBans,
+    Bans,
+    ClearBans,
+    BansCleared,
+}
+
+impl Command {
+    /// Every command, in the order of their frame header bytes
+    pub const ALL: [Command; 25] = [
+        Command::Version,
+        Command::Verack,
+        Command::Inv,
+        Command::GetData,
+        Command::Data,
+        Command::NewTransaction,
+        Command::GetHeaders,
+        Command::Headers,
+        Command::GetBlocks,
+        Command::Blocks,
+        Command::GetBlock,
+        Command::Block,
+        Command::GetTransaction,
+        Command::TransactionInfo,
+        Command::GetAddressHistory,
+        Command::AddressHistory,
+        Command::GetStatus,
+        Command::Status,
+        Command::Register,
+        Command::GetPeers,
+        Command::Peers,
+        Command::GetBans,
+        Command::Bans,
+        Command::ClearBans,
+        Command::BansCleared,
+    ];
+
+    /// Command with the given frame header byte
+    pub fn from_byte(byte: u8) -> Option<Command> {
+        Command::ALL.get(byte as usize).copied()
+    }
+
+    /// Largest encoded message of this type a peer may send
+    pub fn max_size(self) -> usize {
+        match self {
+            Command::Version
+            | Command::Verack
+            | Command::GetBlock
+            | Command::GetTransaction
+            | Command::GetAddressHistory
+            | Command::GetStatus
+            | Command::Status
+            | Command::Register
+            | Command::GetPeers
+            | Command::GetBans
+            | Command::ClearBans
+            | Command::BansCleared => MAX_CONTROL_MESSAGE_SIZE,
+            // Block locators and hash lists
+            Command::GetHeaders | Command::GetBlocks | Command::Inv | Command::GetData | Command::Peers => {
+                64 * 1024
+            }
+            Command::Headers => 1024 * 1024,
+            Command::NewTransaction | Command::TransactionInfo => 1024 * 1024,
+            Command::Block => MAX_BLOCK_MESSAGE_SIZE,
+            Command::AddressHistory => 8 * 1024 * 1024,
+            Command::Bans => 1024 * 1024,
+            Command::Blocks | Command::Data => MAX_MESSAGE_SIZE,
+        }
+    }
+}
+
+impl Message {
+    /// Type of the message
+    pub fn command(&self) -> Command {
+        match self {
+            Message::Version(_) => Command::Version,
+            Message::Verack => Command::Verack,
+            Message::Inv(_) => Command::Inv,
+            Message::GetData(_) => Command::GetData,
+            Message::Data { .. } => Command::Data,
+            Message::NewTransaction(_) => Command::NewTransaction,
+            Message::GetHeaders(_) => Command::GetHeaders,
+            Message::Headers(_) => Command::Headers,
+            Message::GetBlocks(_) => Command::GetBlocks,
+            Message::Blocks(_) => Command::Blocks,
+            Message::GetBlock(_) => Command::GetBlock,
+            Message::Block(_) => Command::Block,
+            Message::GetTransaction(_) => Command::GetTransaction,
+            Message::TransactionInfo(_) => Command::TransactionInfo,
+            Message::GetAddressHistory(_) => Command::GetAddressHistory,
+            Message::AddressHistory(_) => Command::AddressHistory,
+            Message::GetStatus => Command::GetStatus,
+            Message::Status { .. } => Command::Status,
+            Message::Register(_) => Command::Register,
+            Message::GetPeers => Command::GetPeers,
+            Message::Peers(_) => Command::Peers,
+            Message::GetBans(_) => Command::GetBans,
+            Message::Bans(_) => Command::Bans,
+            Message::ClearBans(..) => Command::ClearBans,
+            Message::BansCleared(_) => Command::BansCleared,
+        }
+    }
 }
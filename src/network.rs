@@ -0,0 +1,143 @@
+//! Named networks. Each network has its own frame magic, default ports and
+//! default consensus rules, so nodes of different networks cannot exchange
+//! messages even when they run on the same host.
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::params::ChainParams;
+use crate::pow;
+
+/// A named network
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Network {
+    /// The main network
+    #[default]
+    Main,
+    /// Public test network with an easier target
+    Test,
+    /// Local testing: minimal proof of work and fast halvings
+    Regtest,
+}
+
+impl Network {
+    /// Name used on the command line and in handshakes
+    pub fn name(&self) -> &'static str {
+        match self {
+            Network::Main => "main",
+            Network::Test => "test",
+            Network::Regtest => "regtest",
+        }
+    }
+
+    /// Bytes every frame on this network starts with: three shared by this
+    /// project's networks, then one naming the network
+    pub fn magic(&self) -> [u8; 4] {
+        match self {
+            Network::Main => [0xc5, 0x70, 0x0c, 0xa1],
+            Network::Test => [0xc5, 0x70, 0x0c, 0xb2],
+            Network::Regtest => [0xc5, 0x70, 0x0c, 0xc3],
+        }
+    }
+
+    /// Default listening port of a node
+    pub fn default_port(&self) -> u16 {
+        match self {
+            Network::Main => 8080,
+            Network::Test => 18080,
+            Network::Regtest => 28080,
+        }
+    }
+
+    /// Default listening port of a seed node
+    pub fn default_seed_port(&self) -> u16 {
+        match self {
+            Network::Main => 9000,
+            Network::Test => 19000,
+            Network::Regtest => 29000,
+        }
+    }
+
+    /// Default initial difficulty (number of leading zeros)
+    pub fn default_difficulty(&self) -> usize {
+        match self {
+            Network::Main => 4,
+            Network::Test => 3,
+            Network::Regtest => 1,
+        }
+    }
+
+    /// Default consensus rules of the network. The difficulty never drops
+    /// below the starting one, so every block's proof of work has a cost
+    /// that can be checked from its header alone.
+    pub fn params(&self) -> ChainParams {
+        let bits = pow::bits_for_leading_zeros(self.default_difficulty());
+        let params = ChainParams {
+            genesis_bits: bits,
+            pow_limit_bits: bits,
+            ..ChainParams::default()
+        };
+        match self {
+            Network::Main | Network::Test => params,
+            Network::Regtest => ChainParams {
+                halving_interval: 150,
+                ..params
+            },
+        }
+    }
+}
+
+impl std::str::FromStr for Network {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "main" | "mainnet" => Ok(Network::Main),
+            "test" | "testnet" => Ok(Network::Test),
+            "regtest" => Ok(Network::Regtest),
+            _ => Err(format!("unknown network '{}' (expected main, test or regtest)", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for Network {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// Identifier of a chain: the network together with its consensus rules.
+/// Nodes of the same network started with different rules (e.g. another
+/// `--difficulty`) have different chain ids.
+pub fn chain_id(network: Network, params: &ChainParams) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(network.magic());
+    hasher.update(bincode::serialize(params).expect("chain parameters always serialize"));
+    hex::encode(&hasher.finalize()[..8])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn networks_are_kept_apart() {
+        let networks = [Network::Main, Network::Test, Network::Regtest];
+        for (i, a) in networks.iter().enumerate() {
+            assert_eq!(a.name().parse::<Network>(), Ok(*a));
+            for b in &networks[i + 1..] {
+                assert_ne!(a.magic(), b.magic());
+                assert_ne!(chain_id(*a, &a.params()), chain_id(*b, &b.params()));
+            }
+        }
+    }
+
+    #[test]
+    fn every_network_has_a_real_pow_limit() {
+        for network in [Network::Main, Network::Test, Network::Regtest] {
+            let params = network.params();
+            assert_eq!(params.pow_limit_bits, params.genesis_bits);
+            assert_ne!(params.pow_limit_bits, pow::bits_for_leading_zeros(0));
+        }
+    }
+}
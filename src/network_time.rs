@@ -0,0 +1,154 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+/// Largest correction applied to the local clock, in seconds. If peers
+/// disagree with us by more than this, our clock is trusted instead.
+pub const MAX_TIME_ADJUSTMENT: i64 = 70 * 60;
+
+/// Fewest peer samples needed before the local clock is adjusted, so a
+/// handful of peers cannot move it
+pub const MIN_TIME_SAMPLES: usize = 5;
+
+/// Most hosts sampled; later hosts do not change the offset
+pub const MAX_TIME_SAMPLES: usize = 200;
+
+/// Network-adjusted time: the local clock corrected by the median of the
+/// clock offsets peers report in their handshakes. Samples are kept per IP
+/// address the connection comes from, since a host can claim any number of
+/// listening addresses but not IPs.
+#[derive(Debug, Default)]
+pub struct NetworkTime {
+    /// Peer clock minus local clock, in seconds, one sample per IP
+    offsets: HashMap<IpAddr, i64>,
+}
+
+impl NetworkTime {
+    /// Create a time source with no peer samples
+    pub fn new() -> Self {
+        NetworkTime::default()
+    }
+
+    /// Record the time reported by a peer on `ip`, returning its offset from
+    /// our clock (saturated to the range of `i64`). Hosts beyond
+    /// `MAX_TIME_SAMPLES` are not recorded.
+    pub fn add_sample(&mut self, ip: IpAddr, peer_time: u64) -> i64 {
+        let offset = (peer_time as i128 - local_time() as i128).clamp(i64::MIN as i128, i64::MAX as i128) as i64;
+        if self.offsets.len() < MAX_TIME_SAMPLES || self.offsets.contains_key(&ip) {
+            self.offsets.insert(ip, offset);
+        }
+        offset
+    }
+
+    /// Number of hosts that reported their time
+    pub fn sample_count(&self) -> usize {
+        self.offsets.len()
+    }
+
+    /// Median offset over the peer samples and our own clock, or zero if
+    /// there are fewer than `MIN_TIME_SAMPLES` samples or it exceeds
+    /// `MAX_TIME_ADJUSTMENT`
+    pub fn offset(&self) -> i64 {
+        if self.offsets.len() < MIN_TIME_SAMPLES {
+            return 0;
+        }
+        let mut offsets: Vec<i64> = self.offsets.values().copied().collect();
+        offsets.push(0);
+        offsets.sort_unstable();
+
+        let middle = offsets.len() / 2;
+        let median = if offsets.len().is_multiple_of(2) {
+            ((offsets[middle - 1] as i128 + offsets[middle] as i128) / 2) as i64
+        } else {
+            offsets[middle]
+        };
+        if median.unsigned_abs() > MAX_TIME_ADJUSTMENT as u64 {
+            0
+        } else {
+            median
+        }
+    }
+}
+
+/// Seconds since the Unix epoch on the local clock
+pub fn local_time() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Time source with peers on separate hosts whose clocks are off by
+    /// `offsets` seconds
+    fn with_offsets(offsets: &[i64]) -> NetworkTime {
+        let mut time = NetworkTime::new();
+        let now = local_time() as i64;
+        for (i, offset) in offsets.iter().enumerate() {
+            time.add_sample(host(i as u8), (now + offset) as u64);
+        }
+        time
+    }
+
+    /// Address of the `n`th test host
+    fn host(n: u8) -> IpAddr {
+        IpAddr::from([10, 0, 0, n])
+    }
+
+    #[test]
+    fn few_samples_do_not_move_the_clock() {
+        assert_eq!(with_offsets(&[600]).offset(), 0);
+        assert_eq!(with_offsets(&[600, 600, 600, 600]).offset(), 0);
+    }
+
+    #[test]
+    fn offset_is_the_median_with_our_own_clock() {
+        // Samples 0 (ours), 100, 200, 300, 400, 500: between 200 and 300
+        let offset = with_offsets(&[100, 200, 300, 400, 500]).offset();
+        assert!((249..=251).contains(&offset), "offset {}", offset);
+
+        // One peer far off does not move the median
+        let offset = with_offsets(&[0, 0, 0, 0, 0, 3000]).offset();
+        assert!(offset.abs() <= 1, "offset {}", offset);
+    }
+
+    #[test]
+    fn a_host_counts_once() {
+        let mut time = with_offsets(&[600, 600, 600, 600]);
+        time.add_sample(host(0), local_time() + 600);
+        assert_eq!(time.sample_count(), 4);
+        assert_eq!(time.offset(), 0);
+
+        time.add_sample(host(4), local_time() + 600);
+        assert_eq!(time.sample_count(), 5);
+        assert!((599..=601).contains(&time.offset()));
+    }
+
+    #[test]
+    fn extreme_times_do_not_overflow() {
+        let mut time = NetworkTime::new();
+        for n in 0..3 {
+            assert_eq!(time.add_sample(host(n), u64::MAX), i64::MAX);
+        }
+        for n in 3..6 {
+            time.add_sample(host(n), 0);
+        }
+        // Median of i64::MAX and a large negative offset, both far off
+        assert_eq!(time.offset(), 0);
+
+        // Even count with our own clock: the middle pair is i64::MAX twice
+        let mut time = NetworkTime::new();
+        for n in 0..5 {
+            time.add_sample(host(n), u64::MAX);
+        }
+        assert_eq!(time.offset(), 0);
+    }
+
+    #[test]
+    fn large_offsets_are_ignored() {
+        let far = MAX_TIME_ADJUSTMENT + 600;
+        assert_eq!(with_offsets(&[far, far, far, far, far]).offset(), 0);
+    }
+}
@@ -1,31 +1,136 @@
-use std::sync::{Arc, RwLock};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, RwLock};
+use tokio::net::tcp::OwnedReadHalf;
 use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast;
+use tokio::task::JoinSet;
 
-use crate::block::Block;
-use crate::blockchain::Blockchain;
-use crate::message::Message;
+use crate::block::{Block, BlockHeader, ZERO_HASH};
+use crate::blockchain::{BlockStatus, Blockchain, HeaderCheck};
+use crate::store::{ChainStore, MemoryStore};
+use crate::error::ValidationError;
+use crate::index::TxLocation;
+use crate::inventory::{Inventory, PeerInventory, RecentHashes, MAX_SEEN_ITEMS};
+use crate::mempool::Mempool;
+use crate::message::{
+    Command, Message, Version, MAX_BLOCKS_PER_MESSAGE, MAX_HEADERS_PER_MESSAGE,
+    MAX_INVENTORY_PER_MESSAGE,
+};
+use crate::network::{self, Network};
+use crate::network_time::{self, NetworkTime};
+use crate::orphan::OrphanPool;
+use crate::ban::{Ban, BanManager, Offence};
+use crate::codec::{self, Envelope};
+use crate::peer::{self, Session, PROTOCOL_VERSION, SERVICE_BLOCKS, SERVICE_INDEX};
+use crate::pow::{self, U256};
 use crate::transaction::Transaction;
 
-/// A P2P node in the blockchain network
-pub struct Node {
-    pub blockchain: Arc<RwLock<Blockchain>>,
-    pub mempool: Arc<RwLock<Vec<Transaction>>>,
+/// Number of blocks downloaded before they are added to the chain during sync
+const DOWNLOAD_WINDOW: usize = 128;
+
+/// Change to the active chain, published to subscribers
+#[derive(Debug, Clone)]
+pub enum ChainEvent {
+    /// A block was appended to the active chain
+    BlockConnected { height: u64, hash: String },
+    /// The active chain switched to a branch with more work
+    Reorg {
+        /// Height of the last block both branches share (`None` if not even the genesis)
+        fork_height: Option<u64>,
+        /// Hashes of the blocks removed, tip first
+        disconnected: Vec<String>,
+        /// Hashes of the blocks added, oldest first
+        connected: Vec<String>,
+    },
+}
+
+/// A P2P node in the blockchain network, generic over where its chain is
+/// stored. Clones share the same state.
+pub struct Node<S: ChainStore = MemoryStore> {
+    pub blockchain: Arc<RwLock<Blockchain<S>>>,
+    pub mempool: Arc<RwLock<Mempool>>,
+    pub orphans: Arc<RwLock<OrphanPool>>,
+    pub time: Arc<RwLock<NetworkTime>>,
     pub addr: String,
     pub peers: Arc<RwLock<Vec<String>>>,
+    /// Items each peer is known to have
+    pub known_inventory: Arc<RwLock<PeerInventory>>,
+    /// Hashes of the items currently being fetched from a peer
+    pub requested: Arc<RwLock<HashSet<String>>>,
+    /// Hashes of items already received that passed validation
+    pub seen: Arc<RwLock<RecentHashes>>,
+    /// Open peer sessions: outbound ones by the address we connected to,
+    /// inbound ones by the listening address the peer reports if it is on
+    /// the IP the connection comes from (so a peer has one identity in the
+    /// peer list, sessions and known inventory), else by its socket address
+    pub sessions: Arc<RwLock<HashMap<String, Arc<Session>>>>,
+    /// Lock per peer held while opening an outbound session to it, so each
+    /// peer is connected once without waiting on connections to other peers
+    connecting: Arc<Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>>,
+    /// Network the node is on
+    pub network: Network,
+    /// Misbehaviour scores and banned peers
+    pub bans: Arc<RwLock<BanManager>>,
+    /// Token clients must present to list or lift bans; ban management is
+    /// closed while there is none
+    admin_token: Arc<RwLock<Option<String>>>,
+    pub events: broadcast::Sender<ChainEvent>,
 }
 
-impl Node {
-    /// Create a new node
-    pub fn new(blockchain: Blockchain, addr: String, peers: Vec<String>) -> Self {
+impl<S: ChainStore> Clone for Node<S> {
+    fn clone(&self) -> Self {
+        Node {
+            blockchain: self.blockchain.clone(),
+            mempool: self.mempool.clone(),
+            orphans: self.orphans.clone(),
+            time: self.time.clone(),
+            addr: self.addr.clone(),
+            peers: self.peers.clone(),
+            known_inventory: self.known_inventory.clone(),
+            requested: self.requested.clone(),
+            seen: self.seen.clone(),
+            sessions: self.sessions.clone(),
+            connecting: self.connecting.clone(),
+            network: self.network,
+            bans: self.bans.clone(),
+            admin_token: self.admin_token.clone(),
+            events: self.events.clone(),
+        }
+    }
+}
+
+impl<S: ChainStore + 'static> Node<S> {
+    /// Create a new node on `network`
+    pub fn new(blockchain: Blockchain<S>, network: Network, addr: String, peers: Vec<String>) -> Self {
+        let (events, _) = broadcast::channel(64);
+        let mempool = Mempool::new(blockchain.params.ledger);
         Node {
             blockchain: Arc::new(RwLock::new(blockchain)),
-            mempool: Arc::new(RwLock::new(Vec::new())),
+            mempool: Arc::new(RwLock::new(mempool)),
+            orphans: Arc::new(RwLock::new(OrphanPool::default())),
+            time: Arc::new(RwLock::new(NetworkTime::new())),
             addr,
             peers: Arc::new(RwLock::new(peers)),
+            known_inventory: Arc::new(RwLock::new(PeerInventory::new())),
+            requested: Arc::new(RwLock::new(HashSet::new())),
+            seen: Arc::new(RwLock::new(RecentHashes::new(MAX_SEEN_ITEMS))),
+            sessions: Arc::new(RwLock::new(HashMap::new())),
+            connecting: Arc::new(Mutex::new(HashMap::new())),
+            network,
+            bans: Arc::new(RwLock::new(BanManager::default())),
+            admin_token: Arc::new(RwLock::new(None)),
+            events,
         }
     }
 
+    /// Receive chain events (new blocks and reorganizations)
+    pub fn subscribe(&self) -> broadcast::Receiver<ChainEvent> {
+        self.events.subscribe()
+    }
+
     /// Add a peer to the list
     pub fn add_peer(&self, peer: String) {
         let mut peers = self.peers.write().unwrap();
@@ -40,207 +145,1495 @@ impl Node {
         self.peers.read().unwrap().clone()
     }
 
+    /// Replace the ban manager, e.g. with one loaded from disk
+    pub fn set_bans(&self, bans: BanManager) {
+        *self.bans.write().unwrap() = bans;
+    }
+
+    /// Open ban management to clients presenting `token`
+    pub fn set_admin_token(&self, token: String) {
+        *self.admin_token.write().unwrap() = Some(token);
+    }
+
+    /// Check if a client's token is the admin token
+    fn is_admin(&self, token: &str) -> bool {
+        self.admin_token.read().unwrap().as_deref() == Some(token)
+    }
+
+    /// IP address a peer connects from: that of its session, or the one in
+    /// its address if there is no session
+    fn peer_ip(&self, peer: &str) -> Option<IpAddr> {
+        match self.sessions.read().unwrap().get(peer) {
+            Some(session) => Some(session.remote.ip()),
+            None => peer.parse::<SocketAddr>().ok().map(|addr| addr.ip()),
+        }
+    }
+
+    /// Score an offence of a peer against its IP address. Once the IP reaches
+    /// the ban threshold, every peer on it is disconnected, dropped from the
+    /// peer list and refused until the ban ends.
+    pub fn misbehaving(&self, peer: &str, offence: Offence) {
+        let Some(ip) = self.peer_ip(peer) else {
+            eprintln!("Peer {} misbehaved: {} (address unknown, not scored)", peer, offence);
+            return;
+        };
+        let banned = {
+            let mut bans = self.bans.write().unwrap();
+            if bans.is_whitelisted(ip) {
+                eprintln!("Peer {} ({}) misbehaved: {} (whitelisted, not scored)", peer, ip, offence);
+                return;
+            }
+            let score = bans.score(ip) + offence.score();
+            eprintln!("Peer {} ({}) misbehaved: {} (score {})", peer, ip, offence, score);
+            bans.misbehaving(ip, offence)
+        };
+        if !banned {
+            return;
+        }
+
+        println!("Banning {} for {}", ip, offence);
+        let dropped: Vec<String> = self
+            .get_peers()
+            .into_iter()
+            .filter(|p| self.peer_ip(p) == Some(ip))
+            .collect();
+        self.peers.write().unwrap().retain(|p| !dropped.contains(p));
+        let closed: Vec<Arc<Session>> = {
+            let mut sessions = self.sessions.write().unwrap();
+            let keys: Vec<String> = sessions
+                .values()
+                .filter(|session| session.remote.ip() == ip)
+                .map(|session| session.addr.clone())
+                .collect();
+            keys.iter().filter_map(|key| sessions.remove(key)).collect()
+        };
+        let mut known = self.known_inventory.write().unwrap();
+        for session in closed {
+            known.remove_peer(&session.addr);
+            session.close();
+        }
+        for peer in &dropped {
+            known.remove_peer(peer);
+        }
+    }
+
+    /// Check if an IP address is banned
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        self.bans.read().unwrap().is_banned(ip)
+    }
+
     /// Start listening for connections
     pub async fn start(&self) -> Result<(), Box<dyn std::error::Error>> {
         let listener = TcpListener::bind(&self.addr).await?;
         println!("Node listening on {}", self.addr);
+        self.serve(listener).await
+    }
 
+    /// Accept connections from a bound listener
+    pub async fn serve(&self, listener: TcpListener) -> Result<(), Box<dyn std::error::Error>> {
         loop {
             let (stream, addr) = listener.accept().await?;
             println!("Connection from {}", addr);
 
-            let blockchain = self.blockchain.clone();
-            let mempool = self.mempool.clone();
-
+            let node = self.clone();
             tokio::spawn(async move {
-                if let Err(e) = handle_connection(stream, blockchain, mempool).await {
+                if let Err(e) = handle_connection(stream, addr, node).await {
                     eprintln!("Connection error: {}", e);
                 }
             });
         }
     }
 
-    /// Send a message to a peer
-    async fn send_message(peer: &str, msg: &Message) -> Result<Option<Message>, Box<dyn std::error::Error + Send + Sync>> {
-        let mut stream = TcpStream::connect(peer).await?;
-        let data = bincode::serialize(msg)?;
-        let len = (data.len() as u32).to_be_bytes();
+    /// What we announce about ourselves in a handshake
+    pub fn version(&self) -> Version {
+        let bc = self.blockchain.read().unwrap();
+        let mut services = 0;
+        if !bc.is_pruned() {
+            services |= SERVICE_BLOCKS;
+        }
+        if bc.index().is_some() {
+            services |= SERVICE_INDEX;
+        }
+        Version {
+            version: PROTOCOL_VERSION,
+            network: self.network.to_string(),
+            chain_id: network::chain_id(self.network, &bc.params),
+            genesis: bc.store().hash_at(0),
+            best_height: bc.len() as u64,
+            services,
+            addr: self.addr.clone(),
+            timestamp: network_time::local_time(),
+        }
+    }
 
-        stream.write_all(&len).await?;
-        stream.write_all(&data).await?;
+    /// Session with a peer, connecting and completing the handshake first if
+    /// there is none yet
+    pub async fn session(&self, peer: &str) -> Result<Arc<Session>, String> {
+        if self.peer_ip(peer).is_some_and(|ip| self.is_banned(ip)) {
+            return Err(format!("{} is banned", peer));
+        }
+        if let Some(session) = self.sessions.read().unwrap().get(peer) {
+            return Ok(session.clone());
+        }
 
-        // Read response if expected
-        let mut len_buf = [0u8; 4];
-        if stream.read_exact(&mut len_buf).await.is_ok() {
-            let len = u32::from_be_bytes(len_buf) as usize;
-            let mut buf = vec![0u8; len];
-            stream.read_exact(&mut buf).await?;
-            let response: Message = bincode::deserialize(&buf)?;
-            return Ok(Some(response));
+        let lock = self.connecting.lock().unwrap().entry(peer.to_string()).or_default().clone();
+        let result = {
+            let _connecting = lock.lock().await;
+            let existing = self.sessions.read().unwrap().get(peer).cloned();
+            match existing {
+                Some(session) => Ok(session),
+                None => self.connect(peer).await,
+            }
+        };
+
+        // Forget the lock once nobody else is waiting on it
+        let mut connecting = self.connecting.lock().unwrap();
+        if Arc::strong_count(&lock) == 2 {
+            connecting.remove(peer);
         }
+        result
+    }
 
-        Ok(None)
+    /// Open an outbound session: connect and complete the handshake
+    async fn connect(&self, peer: &str) -> Result<Arc<Session>, String> {
+        let stream = tokio::time::timeout(peer::CONNECT_TIMEOUT, TcpStream::connect(peer))
+            .await
+            .map_err(|_| format!("Cannot connect to {}: timed out", peer))?
+            .map_err(|e| format!("Cannot connect to {}: {}", peer, e))?;
+        let remote = stream
+            .peer_addr()
+            .map_err(|e| format!("Cannot connect to {}: {}", peer, e))?;
+        if self.is_banned(remote.ip()) {
+            return Err(format!("{} is banned", peer));
+        }
+        let (mut reader, mut writer) = stream.into_split();
+        let version = peer::connect_handshake(&mut reader, &mut writer, self.network.magic(), &self.version())
+            .await
+            .map_err(|e| format!("Handshake with {} failed: {}", peer, e))?;
+
+        let session = Session::start(
+            peer.to_string(),
+            remote,
+            version,
+            true,
+            self.network.magic(),
+            writer,
+        );
+        Ok(self.register_session(session, reader))
     }
 
-    /// Broadcast a block to all peers
-    pub async fn broadcast_block(&self, block: &Block) {
-        let msg = Message::NewBlock(block.clone());
-        let peers = self.get_peers();
-        for peer in peers {
-            if let Err(e) = Self::send_message(&peer, &msg).await {
-                eprintln!("Failed to send to {}: {}", peer, e);
+    /// Send a message to a peer, expecting no response
+    pub async fn send(&self, peer: &str, message: Message) -> Result<(), String> {
+        self.session(peer).await?.send(message)
+    }
+
+    /// Send a request to a peer and wait for its response
+    pub async fn request(&self, peer: &str, message: Message) -> Result<Message, String> {
+        self.session(peer).await?.request(message).await
+    }
+
+    /// Add an established session and start reading from it. The listening
+    /// address the peer reports is added to the peer list only if it is on
+    /// the IP the connection comes from.
+    fn register_session(&self, session: Session, reader: OwnedReadHalf) -> Arc<Session> {
+        let session = Arc::new(session);
+        {
+            let mut sessions = self.sessions.write().unwrap();
+            if let Some(existing) = sessions.get(&session.addr) {
+                session.close();
+                return existing.clone();
             }
+            sessions.insert(session.addr.clone(), session.clone());
+        }
+
+        println!(
+            "Connected to {} ({}, protocol {}, height {}, services {:#x})",
+            session.addr,
+            if session.outbound { "outbound" } else { "inbound" },
+            session.version.version,
+            session.version.best_height,
+            session.version.services
+        );
+        self.sample_time(&session);
+        if reports_own_addr(&session.version, session.remote) {
+            self.add_peer(session.version.addr.clone());
+        }
+
+        tokio::spawn(run_session(self.clone(), session.clone(), reader));
+        session
+    }
+
+    /// Record the clock a peer reported in its handshake, one sample per IP
+    /// the connections come from (a host can claim any number of listening
+    /// addresses), and adjust the chain's notion of current time
+    fn sample_time(&self, session: &Session) {
+        let (sample, before, offset, samples) = {
+            let mut time = self.time.write().unwrap();
+            let before = time.offset();
+            let sample = time.add_sample(session.remote.ip(), session.version.timestamp);
+            (sample, before, time.offset(), time.sample_count())
+        };
+        println!("Clock offset of {}: {}s", session.addr, sample);
+        if offset != before {
+            println!("Network-adjusted time offset: {}s over {} peers", offset, samples);
+            self.blockchain.write().unwrap().set_time_offset(offset);
         }
     }
 
-    /// Broadcast a transaction to all peers
+    /// Handle a message from a peer, returning the response for requests
+    async fn handle_message(&self, peer: &str, message: Message) -> Option<Message> {
+        match message {
+            Message::Inv(items) => {
+                self.receive_inventory(items, peer).await;
+                None
+            }
+
+            Message::GetData(items) => {
+                // Leave room for the envelope around the items, so the
+                // response is never too large to send
+                let (blocks, transactions) = self.get_data(&items, Command::Data.max_size() - 1024);
+                Some(Message::Data { blocks, transactions })
+            }
+
+            Message::NewTransaction(tx) => {
+                println!("Received transaction: {}", tx);
+                self.receive_transaction(tx, peer).await;
+                None
+            }
+
+            Message::GetHeaders(locator) => {
+                let bc = self.blockchain.read().unwrap();
+                Some(Message::Headers(bc.locate_headers(&locator, MAX_HEADERS_PER_MESSAGE)))
+            }
+
+            Message::GetBlocks(hashes) => {
+                // Capped like `GetData`: a full batch of blocks at the block
+                // size limit would not fit in one message
+                let items: Vec<Inventory> =
+                    hashes.into_iter().take(MAX_BLOCKS_PER_MESSAGE).map(Inventory::Block).collect();
+                let (blocks, _) = self.get_data(&items, Command::Blocks.max_size() - 1024);
+                Some(Message::Blocks(blocks))
+            }
+
+            Message::GetBlock(hash) => Some(Message::Block(self.blockchain.read().unwrap().get_block(&hash))),
+
+            Message::GetTransaction(hash) => Some(Message::TransactionInfo(
+                self.blockchain.read().unwrap().get_transaction(&hash),
+            )),
+
+            Message::GetAddressHistory(address) => Some(Message::AddressHistory(
+                self.blockchain.read().unwrap().address_history(&address),
+            )),
+
+            Message::GetStatus => {
+                let bc = self.blockchain.read().unwrap();
+                Some(Message::Status {
+                    height: bc.len() as u64,
+                    pruned_height: bc.pruned_height(),
+                })
+            }
+
+            Message::Version(_) | Message::Verack => {
+                eprintln!("Unexpected handshake message from {}", peer);
+                None
+            }
+
+            Message::Data { .. }
+            | Message::Headers(_)
+            | Message::Blocks(_)
+            | Message::Block(_)
+            | Message::TransactionInfo(_)
+            | Message::AddressHistory(_)
+            | Message::Status { .. }
+            | Message::Bans(_)
+            | Message::BansCleared(_) => {
+                eprintln!("Unsolicited response from {}", peer);
+                self.misbehaving(peer, Offence::UnsolicitedData);
+                None
+            }
+
+            Message::GetBans(_) | Message::ClearBans(..) => {
+                eprintln!("Ignoring ban management request from peer {}", peer);
+                None
+            }
+
+            Message::Register(_) | Message::GetPeers | Message::Peers(_) => {
+                // Handled by seed node
+                None
+            }
+        }
+    }
+
+    /// Announce a block to all peers
+    pub async fn broadcast_block(&self, block: &Block) {
+        self.seen.write().unwrap().insert(&block.hash);
+        self.announce(Inventory::Block(block.hash.clone())).await;
+    }
+
+    /// Announce a transaction to all peers
     pub async fn broadcast_transaction(&self, tx: &Transaction) {
-        let msg = Message::NewTransaction(tx.clone());
-        let peers = self.get_peers();
+        self.seen.write().unwrap().insert(&tx.hash());
+        self.announce(Inventory::Transaction(tx.hash())).await;
+    }
+
+    /// Send an `Inv` for an item to every peer not known to have it yet;
+    /// peers fetch it with `GetData` if they want it
+    pub async fn announce(&self, item: Inventory) {
+        let peers: Vec<String> = {
+            let mut known = self.known_inventory.write().unwrap();
+            self.get_peers()
+                .into_iter()
+                .filter(|peer| known.mark_known(peer, item.hash()))
+                .collect()
+        };
+
         for peer in peers {
-            if let Err(e) = Self::send_message(&peer, &msg).await {
+            if let Err(e) = self.send(&peer, Message::Inv(vec![item.clone()])).await {
                 eprintln!("Failed to send to {}: {}", peer, e);
             }
         }
     }
 
-    /// Sync blockchain from peers (longest chain rule)
-    pub async fn sync(&self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        let current_len = {
-            let bc = self.blockchain.read().unwrap();
-            bc.len()
+    /// Handle an `Inv` from `sender`: request the items we have not seen
+    /// and are not already fetching, and add what it sends back. Items that
+    /// pass validation are announced to our other peers in turn.
+    pub async fn receive_inventory(&self, items: Vec<Inventory>, sender: &str) {
+        let wanted: Vec<Inventory> = {
+            let mut known = self.known_inventory.write().unwrap();
+            let mut requested = self.requested.write().unwrap();
+            let seen = self.seen.read().unwrap();
+            items
+                .into_iter()
+                .take(MAX_INVENTORY_PER_MESSAGE)
+                .inspect(|item| {
+                    known.mark_known(sender, item.hash());
+                })
+                .filter(|item| {
+                    !seen.contains(item.hash()) && !self.has_item(item) && requested.insert(item.hash().to_string())
+                })
+                .collect()
+        };
+        if wanted.is_empty() {
+            return;
+        }
+
+        let response = self.request(sender, Message::GetData(wanted.clone())).await;
+        let (blocks, transactions) = match response {
+            Ok(Message::Data { blocks, transactions }) => (blocks, transactions),
+            Ok(_) => (vec![], vec![]),
+            Err(e) => {
+                eprintln!("Failed to get data from {}: {}", sender, e);
+                (vec![], vec![])
+            }
         };
 
-        let mut best_chain: Option<Blockchain> = None;
-        let mut best_len = current_len;
+        // A block's hash field is unchecked: drop blocks whose header does not
+        // hash to it before the hash is used for anything
+        let (blocks, forged): (Vec<Block>, Vec<Block>) =
+            blocks.into_iter().partition(|block| block.hash == block.header.hash());
+        if !forged.is_empty() {
+            self.misbehaving(sender, Offence::InvalidBlock);
+        }
+
+        let unsolicited = transactions
+            .iter()
+            .any(|tx| !wanted.contains(&Inventory::Transaction(tx.hash())))
+            || blocks
+                .iter()
+                .any(|block| !wanted.contains(&Inventory::Block(block.hash.clone())));
+        if unsolicited {
+            self.misbehaving(sender, Offence::UnsolicitedData);
+        }
+
+        for tx in transactions {
+            let hash = tx.hash();
+            if !wanted.contains(&Inventory::Transaction(hash.clone())) || self.seen.read().unwrap().contains(&hash) {
+                continue;
+            }
+            println!("Received transaction: {}", tx);
+            self.receive_transaction(tx, sender).await;
+        }
+        for block in blocks {
+            if !wanted.contains(&Inventory::Block(block.hash.clone())) || self.seen.read().unwrap().contains(&block.hash) {
+                continue;
+            }
+            println!("Received block #{} from {}", block.index, sender);
+            self.receive_block(block, sender).await;
+        }
 
+        let mut requested = self.requested.write().unwrap();
+        for item in &wanted {
+            requested.remove(item.hash());
+        }
+    }
+
+    /// Check if we already have an announced item
+    fn has_item(&self, item: &Inventory) -> bool {
+        match item {
+            Inventory::Block(hash) => {
+                self.blockchain.read().unwrap().contains_block(hash) || self.orphans.read().unwrap().contains(hash)
+            }
+            Inventory::Transaction(hash) => self.mempool.read().unwrap().contains(hash),
+        }
+    }
+
+    /// Look up the items a peer asked for, in order, stopping before the
+    /// first one that would take the encoded items past `max_size` bytes
+    /// (the rest can be asked for again)
+    fn get_data(&self, items: &[Inventory], max_size: usize) -> (Vec<Block>, Vec<Transaction>) {
+        let bc = self.blockchain.read().unwrap();
+        let mempool = self.mempool.read().unwrap();
+        let mut blocks = Vec::new();
+        let mut transactions = Vec::new();
+        let mut size = 0usize;
+        for item in items.iter().take(MAX_INVENTORY_PER_MESSAGE) {
+            match item {
+                Inventory::Block(hash) => {
+                    let Some(block) = bc.get_block(hash) else { continue };
+                    size = size.saturating_add(bincode::serialized_size(&block).unwrap_or(u64::MAX) as usize);
+                    if size > max_size {
+                        break;
+                    }
+                    blocks.push(block);
+                }
+                Inventory::Transaction(hash) => {
+                    let Some(tx) = mempool.get(hash) else { continue };
+                    size = size.saturating_add(tx.size());
+                    if size > max_size {
+                        break;
+                    }
+                    transactions.push(tx.clone());
+                }
+            }
+        }
+        (blocks, transactions)
+    }
+
+    /// Sync from peers headers-first: fetch each peer's headers after our
+    /// block locator and check them as they arrive, then, if they lead to
+    /// more work than the active chain, download the missing blocks from all
+    /// peers in parallel and add them to the block tree in order
+    pub async fn sync(&self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         let peers = self.get_peers();
-        for peer in peers {
-            println!("Requesting blockchain from {}", peer);
-
-            match Self::send_message(&peer, &Message::GetBlocks).await {
-                Ok(Some(Message::Blocks(chain))) => {
-                    if chain.is_valid() && chain.len() > best_len {
-                        println!("Found longer valid chain from {} ({} blocks)", peer, chain.len());
-                        best_len = chain.len();
-                        best_chain = Some(chain);
+        for peer in &peers {
+            println!("Requesting headers from {}", peer);
+            let (headers, work) = match self.fetch_headers(peer).await {
+                Ok(fetched) => fetched,
+                Err(e) => {
+                    eprintln!("Failed to get headers from {}: {}", peer, e);
+                    continue;
+                }
+            };
+            if headers.is_empty() || work <= self.blockchain.read().unwrap().total_work() {
+                continue;
+            }
+            println!("Found chain with more work from {} ({} new blocks)", peer, headers.len());
+
+            self.download_blocks(&headers, peer, &peers).await;
+            println!("Blockchain at {} blocks", self.blockchain.read().unwrap().len());
+        }
+
+        Ok(())
+    }
+
+    /// Fetch a peer's active chain headers after the last block we share,
+    /// leaving out headers of blocks we already know. Each batch is checked
+    /// with our own consensus rules as it arrives, and no more headers are
+    /// fetched than the height the peer announced (plus one batch for blocks
+    /// found since). Returns the new headers and the work of their branch.
+    async fn fetch_headers(
+        &self,
+        peer: &str,
+    ) -> Result<(Vec<BlockHeader>, U256), Box<dyn std::error::Error + Send + Sync>> {
+        let best_height = self.session(peer).await?.version.best_height;
+        let limit = (best_height as usize).saturating_add(MAX_HEADERS_PER_MESSAGE);
+
+        let mut locator = self.blockchain.read().unwrap().block_locator();
+        let mut headers: Vec<BlockHeader> = Vec::new();
+        let mut check: Option<HeaderCheck> = None;
+        let mut last_hash: Option<String> = None;
+        let mut received = 0;
+        loop {
+            let batch = match self.request(peer, Message::GetHeaders(locator)).await? {
+                Message::Headers(batch) => batch,
+                _ => return Err("unexpected response".into()),
+            };
+            if batch.len() > MAX_HEADERS_PER_MESSAGE {
+                return Err(format!("{} headers in one message", batch.len()).into());
+            }
+            if let (Some(last), Some(first)) = (&last_hash, batch.first()) {
+                if first.prev_hash != *last {
+                    return Err("headers do not connect".into());
+                }
+            }
+            received += batch.len();
+            let done = batch.len() < MAX_HEADERS_PER_MESSAGE || received >= limit;
+            let Some(last) = batch.last().map(BlockHeader::hash) else {
+                break;
+            };
+            last_hash = Some(last.clone());
+
+            {
+                let bc = self.blockchain.read().unwrap();
+                let mut batch = batch.as_slice();
+                if check.is_none() {
+                    // Skip the blocks we already know
+                    let known = batch.iter().take_while(|h| bc.contains_block(&h.hash())).count();
+                    batch = &batch[known..];
+                    if let Some(first) = batch.first() {
+                        check = Some(bc.start_headers(&first.prev_hash).map_err(|e| self.invalid_headers(peer, e))?);
                     }
                 }
+                if let Some(check) = &mut check {
+                    bc.continue_headers(check, batch).map_err(|e| self.invalid_headers(peer, e))?;
+                }
+                headers.extend_from_slice(batch);
+            }
+            if done {
+                break;
+            }
+            // The peer continues after the last header it sent
+            locator = vec![last];
+        }
+
+        let work = check.map(|check| check.work()).unwrap_or_default();
+        Ok((headers, work))
+    }
+
+    /// Score a peer that sent headers failing `error`, returning the error to report
+    fn invalid_headers(&self, peer: &str, error: ValidationError) -> String {
+        if let Some(offence) = Offence::for_block(&error) {
+            self.misbehaving(peer, offence);
+        }
+        format!("invalid headers: {}", error)
+    }
+
+    /// Peers serving every block body, connecting to each first. Pruned
+    /// peers advertise without `SERVICE_BLOCKS`.
+    async fn block_peers(&self, peers: &[String]) -> Vec<String> {
+        let mut serving = Vec::new();
+        for peer in peers {
+            match self.session(peer).await {
+                Ok(session) if session.has_service(SERVICE_BLOCKS) => serving.push(peer.clone()),
                 Ok(_) => {}
-                Err(e) => eprintln!("Failed to sync from {}: {}", peer, e),
+                Err(e) => eprintln!("Not downloading from {}: {}", peer, e),
             }
         }
+        serving
+    }
 
-        if let Some(chain) = best_chain {
-            let mut bc = self.blockchain.write().unwrap();
-            *bc = chain;
-            println!("Blockchain updated to {} blocks", bc.len());
+    /// Download the blocks of a checked header chain a window at a time,
+    /// spreading the requests over the peers serving block bodies (`source`
+    /// alone if none do), and add them in chain order. Blocks other peers did
+    /// not deliver are requested from `source`.
+    async fn download_blocks(&self, headers: &[BlockHeader], source: &str, peers: &[String]) {
+        let mut peers = self.block_peers(peers).await;
+        if peers.is_empty() {
+            peers.push(source.to_string());
         }
+        for window in headers.chunks(DOWNLOAD_WINDOW) {
+            let hashes: Vec<String> = window.iter().map(|h| h.hash()).collect();
+            let mut blocks: HashMap<String, (Block, String)> = HashMap::new();
 
-        Ok(())
+            let mut downloads = JoinSet::new();
+            for (i, chunk) in hashes.chunks(MAX_BLOCKS_PER_MESSAGE).enumerate() {
+                let peer = peers[i % peers.len()].clone();
+                let request = Message::GetBlocks(chunk.to_vec());
+                let node = self.clone();
+                downloads.spawn(async move {
+                    let response = node.request(&peer, request).await;
+                    (peer, response)
+                });
+            }
+            while let Some(result) = downloads.join_next().await {
+                if let Ok((peer, Ok(Message::Blocks(received)))) = result {
+                    collect_blocks(&mut blocks, &hashes, &peer, received);
+                }
+            }
+
+            // Responses are capped by size, so ask again for what is still
+            // missing for as long as the source keeps delivering
+            loop {
+                let missing: Vec<String> = hashes
+                    .iter()
+                    .filter(|h| !blocks.contains_key(*h))
+                    .take(MAX_BLOCKS_PER_MESSAGE)
+                    .cloned()
+                    .collect();
+                if missing.is_empty() {
+                    break;
+                }
+                let Ok(Message::Blocks(received)) = self.request(source, Message::GetBlocks(missing)).await else {
+                    break;
+                };
+                let known = blocks.len();
+                collect_blocks(&mut blocks, &hashes, source, received);
+                if blocks.len() == known {
+                    break;
+                }
+            }
+
+            for hash in &hashes {
+                let Some((block, sender)) = blocks.remove(hash) else {
+                    eprintln!("Could not download block {}", hash);
+                    return;
+                };
+                let index = block.index;
+                if let Err(e) = self.accept_block(block) {
+                    eprintln!("Rejected block #{} from {}: {}", index, sender, e);
+                    if let Some(offence) = Offence::for_block(&e) {
+                        self.misbehaving(&sender, offence);
+                    }
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Add a block received from `sender`. A block whose parent is unknown is
+    /// held in the orphan pool and its missing ancestors are requested from
+    /// the sender one by one until the chain connects. Blocks that join the
+    /// active chain are then relayed to the other peers.
+    pub async fn receive_block(&self, block: Block, sender: &str) {
+        let mut next = Some(block);
+        let mut requests = 0;
+        let mut connected = Vec::new();
+        while let Some(block) = next.take() {
+            let index = block.index;
+            let missing = match self.accept_block(block) {
+                Ok(accepted) => {
+                    connected.extend(accepted.connected);
+                    match accepted.missing {
+                        Some(missing) => missing,
+                        None => break,
+                    }
+                }
+                Err(e) => {
+                    eprintln!("Rejected block #{} from {}: {}", index, sender, e);
+                    if let Some(offence) = Offence::for_block(&e) {
+                        self.misbehaving(sender, offence);
+                    }
+                    break;
+                }
+            };
+
+            // Never fetch more ancestors than the orphan pool can hold
+            requests += 1;
+            if requests > crate::orphan::DEFAULT_MAX_ORPHANS {
+                break;
+            }
+
+            println!("Requesting missing block {} from {}", missing, sender);
+            match self.request(sender, Message::GetBlock(missing.clone())).await {
+                Ok(Message::Block(Some(block))) if block.hash == missing => next = Some(block),
+                Ok(_) => eprintln!("Peer {} does not have block {}", sender, missing),
+                Err(e) => eprintln!("Failed to request block from {}: {}", sender, e),
+            }
+        }
+
+        for hash in connected {
+            self.seen.write().unwrap().insert(&hash);
+            self.announce(Inventory::Block(hash)).await;
+        }
+    }
+
+    /// Add a block to the block tree, or to the orphan pool if its parent is
+    /// unknown, it is within the block size limit and its header hash meets
+    /// its own target, then connect any orphans waiting for it
+    fn accept_block(&self, block: Block) -> Result<Accepted, ValidationError> {
+        let mut bc = self.blockchain.write().unwrap();
+        let mut orphans = self.orphans.write().unwrap();
+
+        let parent = block.header.prev_hash.clone();
+        if parent != ZERO_HASH && !bc.contains_block(&parent) {
+            // Only the header's own proof of work can be checked without the
+            // parent, but it keeps free garbage and forged hashes out of the pool.
+            // The target must not be easier than the limit, or that work is free.
+            if block.hash != block.header.hash() {
+                return Err(ValidationError::BadHash);
+            }
+            // Held blocks must fit the limit a connected block has to meet
+            let size = block.transactions_size();
+            if size > bc.params.max_block_size {
+                return Err(ValidationError::BlockTooLarge {
+                    size,
+                    max: bc.params.max_block_size,
+                });
+            }
+            if !pow::bits_within_limit(block.header.bits, bc.params.pow_limit_bits) {
+                return Err(ValidationError::BadDifficulty);
+            }
+            if !block.is_valid_pow() {
+                return Err(ValidationError::BadProofOfWork);
+            }
+            let mut accepted = Accepted::default();
+            if !orphans.insert(block) {
+                // Already waiting for its ancestors
+                return Ok(accepted);
+            }
+            println!("Holding orphan block, {} held", orphans.len());
+            accepted.missing = Some(orphans.missing_ancestor(&parent));
+            return Ok(accepted);
+        }
+
+        let mut mempool = self.mempool.write().unwrap();
+        let mut accepted = Accepted::default();
+        let hash = block.hash.clone();
+        let status = process_block(&mut bc, &mut mempool, &self.events, block)?;
+        if matches!(status, BlockStatus::Connected | BlockStatus::Reorganized(_)) {
+            accepted.connected.push(hash.clone());
+        }
+
+        let mut parents = vec![hash];
+        while let Some(parent) = parents.pop() {
+            for child in orphans.take_children(&parent) {
+                let (index, hash) = (child.index, child.hash.clone());
+                match process_block(&mut bc, &mut mempool, &self.events, child) {
+                    Ok(status) => {
+                        if matches!(status, BlockStatus::Connected | BlockStatus::Reorganized(_)) {
+                            accepted.connected.push(hash.clone());
+                        }
+                        parents.push(hash);
+                    }
+                    Err(e) => eprintln!("Rejected orphan block #{}: {}", index, e),
+                }
+            }
+        }
+
+        Ok(accepted)
     }
 
     /// Mine a new block
     pub async fn mine(&self, miner_address: &str) -> Option<Block> {
-        let (index, prev_hash, difficulty, transactions) = {
+        let (index, prev_hash, bits, timestamp, transactions) = {
             let bc = self.blockchain.read().unwrap();
-            let last = bc.last_block();
-            let index = last.map(|b| b.index + 1).unwrap_or(0);
-            let prev_hash = last.map(|b| b.hash.clone()).unwrap_or_else(|| String::from("0"));
-            let difficulty = bc.difficulty;
-
-            let mut mempool = self.mempool.write().unwrap();
-            let mut txs: Vec<Transaction> = mempool.drain(..).collect();
+            let index = bc.len() as u64;
+            let prev_hash = bc.tip_hash();
+            let bits = bc.next_bits();
+            let timestamp = bc.next_timestamp();
 
-            // Add coinbase transaction
-            txs.insert(0, Transaction::coinbase(miner_address.to_string(), 50));
-            (index, prev_hash, difficulty, txs)
+            // Pick the best paying mempool transactions that fit in the block
+            let mempool = self.mempool.read().unwrap();
+            let txs = bc.block_template(mempool.transactions(), miner_address);
+            (index, prev_hash, bits, timestamp, txs)
         };
 
-        let mut block = Block::new(index, prev_hash, transactions, difficulty);
+        let mut block = Block::new(index, prev_hash, transactions, bits);
+        block.header.timestamp = timestamp;
         block.mine();
 
-        // Add to blockchain
-        {
-            let mut bc = self.blockchain.write().unwrap();
-            if bc.add_mined_block(block.clone()) {
-                println!("Block #{} added to chain", block.index);
-                return Some(block);
+        // Add to blockchain (the tip may have moved while mining)
+        let mut bc = self.blockchain.write().unwrap();
+        let mut mempool = self.mempool.write().unwrap();
+        match process_block(&mut bc, &mut mempool, &self.events, block.clone()) {
+            Ok(BlockStatus::Connected) | Ok(BlockStatus::Reorganized(_)) => Some(block),
+            Ok(_) => None,
+            Err(e) => {
+                eprintln!("Mined block #{} rejected: {}", block.index, e);
+                None
+            }
+        }
+    }
+
+    /// Add a transaction received from `sender` to the mempool and relay it
+    /// to the other peers, or score the sender if it is invalid through its fault
+    async fn receive_transaction(&self, tx: Transaction, sender: &str) {
+        let hash = tx.hash();
+        match self.add_transaction(tx) {
+            Ok(()) => {
+                self.seen.write().unwrap().insert(&hash);
+                self.announce(Inventory::Transaction(hash)).await
+            }
+            Err(ValidationError::DuplicateTransaction(_)) => {}
+            Err(e) => {
+                eprintln!("Rejected transaction from {}: {}", sender, e);
+                if let Some(offence) = Offence::for_transaction(&e) {
+                    self.misbehaving(sender, offence);
+                }
             }
         }
+    }
 
-        None
+    /// Add transaction to mempool after verifying it
+    pub fn add_transaction(&self, tx: Transaction) -> Result<(), ValidationError> {
+        let bc = self.blockchain.read().unwrap();
+        self.mempool.write().unwrap().add(&bc, tx)
     }
+}
 
-    /// Add transaction to mempool
-    pub fn add_transaction(&self, tx: Transaction) {
-        let mut mempool = self.mempool.write().unwrap();
-        if !mempool.iter().any(|t| t.hash() == tx.hash()) {
-            mempool.push(tx);
+/// Outcome of adding a received block
+#[derive(Debug, Default)]
+struct Accepted {
+    /// Hashes of the blocks that joined the active chain (the block and any
+    /// orphans it connected), in the order they were added
+    connected: Vec<String>,
+    /// Ancestor still needed to connect the block, if it was held as an orphan
+    missing: Option<String>,
+}
+
+/// Add a block to the block tree, updating the mempool and publishing
+/// events when the active chain changes
+fn process_block<S: ChainStore>(
+    blockchain: &mut Blockchain<S>,
+    mempool: &mut Mempool,
+    events: &broadcast::Sender<ChainEvent>,
+    block: Block,
+) -> Result<BlockStatus, ValidationError> {
+    let (index, hash) = (block.index, block.hash.clone());
+    let status = blockchain.add_mined_block(block)?;
+
+    match &status {
+        BlockStatus::Connected => {
+            println!("Block #{} added to chain", index);
+            // Remove included and conflicting transactions from mempool
+            mempool.refresh(blockchain, vec![]);
+            let _ = events.send(ChainEvent::BlockConnected { height: index, hash });
         }
+        BlockStatus::Reorganized(reorg) => {
+            println!(
+                "Reorganized to block #{}: {} blocks disconnected, {} connected",
+                index,
+                reorg.disconnected.len(),
+                reorg.connected.len()
+            );
+            // Transactions of disconnected blocks go back to the mempool, ahead
+            // of pending ones since they may be their parents
+            let returned: Vec<Transaction> = reorg
+                .disconnected
+                .iter()
+                .rev()
+                .flat_map(|b| b.transactions.iter().filter(|tx| !tx.is_coinbase()).cloned())
+                .collect();
+            mempool.refresh(blockchain, returned);
+
+            let _ = events.send(ChainEvent::Reorg {
+                fork_height: reorg.fork_height,
+                disconnected: reorg.disconnected.iter().map(|b| b.hash.clone()).collect(),
+                connected: reorg.connected.iter().map(|b| b.hash.clone()).collect(),
+            });
+        }
+        BlockStatus::SideBranch => println!("Block #{} stored on a side branch", index),
+        BlockStatus::AlreadyKnown => {}
     }
+
+    Ok(status)
 }
 
-/// Handle incoming connection
-async fn handle_connection(
-    mut stream: TcpStream,
-    blockchain: Arc<RwLock<Blockchain>>,
-    mempool: Arc<RwLock<Vec<Transaction>>>,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    // Read message length
-    let mut len_buf = [0u8; 4];
-    stream.read_exact(&mut len_buf).await?;
-    let len = u32::from_be_bytes(len_buf) as usize;
+/// Keep only requested blocks whose hash matches their header
+fn collect_blocks(
+    blocks: &mut HashMap<String, (Block, String)>,
+    requested: &[String],
+    sender: &str,
+    received: Vec<Block>,
+) {
+    for block in received {
+        if block.hash == block.header.hash() && requested.contains(&block.hash) {
+            blocks.insert(block.hash.clone(), (block, sender.to_string()));
+        }
+    }
+}
+
+/// Client functions to query a node's transaction and address index
+pub async fn query_transaction(
+    node_addr: &str,
+    network: Network,
+    tx_hash: &str,
+) -> Result<Option<(Transaction, TxLocation)>, Box<dyn std::error::Error + Send + Sync>> {
+    match send_message(node_addr, network, Message::GetTransaction(tx_hash.to_string())).await? {
+        Message::TransactionInfo(info) => Ok(info),
+        _ => Err("unexpected response".into()),
+    }
+}
+
+pub async fn query_address_history(
+    node_addr: &str,
+    network: Network,
+    address: &str,
+) -> Result<Vec<(String, TxLocation)>, Box<dyn std::error::Error + Send + Sync>> {
+    match send_message(node_addr, network, Message::GetAddressHistory(address.to_string())).await? {
+        Message::AddressHistory(history) => Ok(history),
+        _ => Err("unexpected response".into()),
+    }
+}
+
+/// Client functions to manage a node's ban list, authenticated by the
+/// node's admin token
+pub async fn query_bans(
+    node_addr: &str,
+    network: Network,
+    token: &str,
+) -> Result<Vec<Ban>, Box<dyn std::error::Error + Send + Sync>> {
+    match send_message(node_addr, network, Message::GetBans(token.to_string())).await? {
+        Message::Bans(bans) => Ok(bans),
+        _ => Err("unexpected response".into()),
+    }
+}
 
-    // Read message
-    let mut buf = vec![0u8; len];
-    stream.read_exact(&mut buf).await?;
+pub async fn clear_bans(
+    node_addr: &str,
+    network: Network,
+    token: &str,
+    peer: Option<IpAddr>,
+) -> Result<u64, Box<dyn std::error::Error + Send + Sync>> {
+    match send_message(node_addr, network, Message::ClearBans(token.to_string(), peer)).await? {
+        Message::BansCleared(count) => Ok(count),
+        _ => Err("unexpected response".into()),
+    }
+}
 
-    let msg: Message = bincode::deserialize(&buf)?;
+/// Send a single request to a node without a session (e.g. from a client)
+async fn send_message(
+    node_addr: &str,
+    network: Network,
+    message: Message,
+) -> Result<Message, Box<dyn std::error::Error + Send + Sync>> {
+    let stream = tokio::time::timeout(peer::CONNECT_TIMEOUT, TcpStream::connect(node_addr))
+        .await
+        .map_err(|_| "connection timed out")??;
+    let (mut reader, mut writer) = stream.into_split();
+    codec::write_envelope(&mut writer, network.magic(), &Envelope::new(1, message)).await?;
+
+    let response = tokio::time::timeout(peer::REQUEST_TIMEOUT, codec::read_envelope(&mut reader, network.magic()))
+        .await
+        .map_err(|_| "no response")??;
+    Ok(response.message)
+}
+
+/// Commands an incoming connection may open with: a peer's `Version` or a
+/// client's single query
+const FIRST_COMMANDS: [Command; 7] = [
+    Command::Version,
+    Command::GetStatus,
+    Command::GetTransaction,
+    Command::GetAddressHistory,
+    Command::NewTransaction,
+    Command::GetBans,
+    Command::ClearBans,
+];
+
+/// Handle an incoming connection: a peer opening a session with `Version`,
+/// or a client sending a single query
+async fn handle_connection<S: ChainStore + 'static>(
+    stream: TcpStream,
+    socket: std::net::SocketAddr,
+    node: Node<S>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let (mut reader, mut writer) = stream.into_split();
+    let magic = node.network.magic();
+    // Nothing is known about the connection yet: only the commands it may
+    // open with, each within its own limit (a control message for all but a
+    // wallet's `NewTransaction`)
+    let first = tokio::time::timeout(
+        peer::HANDSHAKE_TIMEOUT,
+        codec::read_envelope_of(&mut reader, magic, &FIRST_COMMANDS, Command::NewTransaction.max_size()),
+    )
+    .await
+    .map_err(|_| "no message received")??;
 
-    match msg {
-        Message::NewBlock(block) => {
-            println!("Received block #{}", block.index);
-            let mut bc = blockchain.write().unwrap();
-            if bc.add_mined_block(block.clone()) {
-                println!("Block #{} added", block.index);
-                // Remove included transactions from mempool
-                let mut pool = mempool.write().unwrap();
-                let block_tx_hashes: std::collections::HashSet<_> =
-                    block.transactions.iter().map(|t| t.hash()).collect();
-                pool.retain(|t| !block_tx_hashes.contains(&t.hash()));
+    // Ban management needs the admin token, and then works from a banned IP
+    // too, so the operator can lift a ban of their own machine
+    let admin = match &first.message {
+        Message::GetBans(token) | Message::ClearBans(token, _) => node.is_admin(token),
+        _ => false,
+    };
+    if node.is_banned(socket.ip()) && !admin {
+        eprintln!("Refusing banned peer {}", socket);
+        return Ok(());
+    }
+
+    match first.message {
+        Message::Version(theirs) => {
+            if let Err(e) = peer::accept_handshake(&mut reader, &mut writer, magic, &node.version(), &theirs).await {
+                eprintln!("Rejected peer {}: {}", socket, e);
+                return Ok(());
             }
+            // Keyed like the peer list, so the peer is not announced its own
+            // items or dialled again; the socket address if the claim is off
+            let key = if reports_own_addr(&theirs, socket) {
+                theirs.addr.clone()
+            } else {
+                socket.to_string()
+            };
+            node.register_session(Session::start(key, socket, theirs, false, magic, writer), reader);
         }
 
-        Message::NewTransaction(tx) => {
-            println!("Received transaction: {}", tx);
-            let mut pool = mempool.write().unwrap();
-            if !pool.iter().any(|t| t.hash() == tx.hash()) {
-                pool.push(tx);
+        message @ (Message::GetStatus
+        | Message::GetTransaction(_)
+        | Message::GetAddressHistory(_)
+        | Message::NewTransaction(_)) => {
+            if let Some(response) = node.handle_message(&socket.to_string(), message).await {
+                let envelope = Envelope {
+                    id: 0,
+                    reply_to: Some(first.id),
+                    message: response,
+                };
+                codec::write_envelope(&mut writer, magic, &envelope).await?;
             }
         }
 
-        Message::GetBlocks => {
-            println!("Received GetBlocks request");
-            let (data, len) = {
-                let bc = blockchain.read().unwrap();
-                let response = Message::Blocks(bc.clone());
-                let data = bincode::serialize(&response)?;
-                let len = (data.len() as u32).to_be_bytes();
-                (data, len)
+        message @ (Message::GetBans(_) | Message::ClearBans(..)) if admin => {
+            let response = match message {
+                Message::ClearBans(_, Some(ip)) => Message::BansCleared(node.bans.write().unwrap().unban(ip) as u64),
+                Message::ClearBans(_, None) => Message::BansCleared(node.bans.write().unwrap().clear() as u64),
+                _ => Message::Bans(node.bans.read().unwrap().bans()),
+            };
+            let envelope = Envelope {
+                id: 0,
+                reply_to: Some(first.id),
+                message: response,
             };
-            stream.write_all(&len).await?;
-            stream.write_all(&data).await?;
+            codec::write_envelope(&mut writer, magic, &envelope).await?;
         }
 
-        Message::Blocks(_) => {
-            // Handled by sync()
+        Message::GetBans(_) | Message::ClearBans(..) => {
+            eprintln!("Refusing ban management request from {}: wrong admin token", socket)
         }
 
-        Message::Register(_) | Message::GetPeers | Message::Peers(_) => {
-            // Handled by seed node
-        }
+        _ => eprintln!("{} sent a message without a handshake", socket),
     }
 
     Ok(())
 }
+
+/// Check if the listening address a peer reports is on the IP its
+/// connection comes from
+fn reports_own_addr(version: &Version, remote: SocketAddr) -> bool {
+    version.addr.parse::<SocketAddr>().is_ok_and(|addr| addr.ip() == remote.ip())
+}
+
+/// Read messages of a session until it closes: responses go to the waiting
+/// requests, everything else is handled in its own task
+fn run_session<S: ChainStore + 'static>(
+    node: Node<S>,
+    session: Arc<Session>,
+    mut reader: OwnedReadHalf,
+) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+    Box::pin(async move {
+        loop {
+            let result = tokio::select! {
+                result = codec::read_envelope(&mut reader, session.magic) => result,
+                _ = session.closed() => break,
+            };
+            let envelope = match result {
+                Ok(envelope) => envelope,
+                Err(e) => {
+                    if e.is_malformed() {
+                        eprintln!("Dropping {}: {}", session.addr, e);
+                        node.misbehaving(&session.addr, Offence::MalformedMessage);
+                    }
+                    break;
+                }
+            };
+
+            // A response to a request that timed out is dropped unpunished
+            if let Some(reply_to) = envelope.reply_to {
+                if !session.complete(reply_to, envelope.message) && !session.issued(reply_to) {
+                    eprintln!("Unsolicited response from {}", session.addr);
+                    node.misbehaving(&session.addr, Offence::UnsolicitedData);
+                }
+                continue;
+            }
+
+            let node = node.clone();
+            let session = session.clone();
+            tokio::spawn(async move {
+                if let Some(response) = node.handle_message(&session.addr, envelope.message).await {
+                    let _ = session.respond(envelope.id, response);
+                }
+            });
+        }
+
+        session.close();
+        let mut sessions = node.sessions.write().unwrap();
+        if sessions.get(&session.addr).is_some_and(|s| Arc::ptr_eq(s, &session)) {
+            sessions.remove(&session.addr);
+            node.known_inventory.write().unwrap().remove_peer(&session.addr);
+            println!("Disconnected from {}", session.addr);
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::address::Wallet;
+    use crate::params::ChainParams;
+    use crate::storage::test_params;
+    use crate::transaction::{OutPoint, TxOutput};
+
+    /// Node that is not listening
+    fn test_node() -> Node {
+        Node::new(Blockchain::new(test_params()), Network::Regtest, "127.0.0.1:0".to_string(), vec![])
+    }
+
+    /// Node on `blockchain` listening on a free local port
+    async fn listening(blockchain: Blockchain, peers: Vec<String>) -> Node {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let node = Node::new(blockchain, Network::Regtest, addr, peers);
+        let server = node.clone();
+        tokio::spawn(async move {
+            let _ = server.serve(listener).await;
+        });
+        node
+    }
+
+    /// Empty chain holding copies of `blocks`
+    fn chain_of(blocks: &[Block]) -> Blockchain {
+        let mut blockchain = Blockchain::empty(test_params());
+        for block in blocks {
+            blockchain.add_mined_block(block.clone()).unwrap();
+        }
+        blockchain
+    }
+
+    /// Blocks of a freshly mined chain with `len` blocks after the genesis block
+    fn mined_blocks(len: u64) -> Vec<Block> {
+        let mut blockchain = Blockchain::new(test_params());
+        let miner = Wallet::generate().address;
+        for _ in 0..len {
+            blockchain.add_block(blockchain.block_template(&[], &miner));
+        }
+        (0..=len).map(|height| blockchain.block_at(height).unwrap()).collect()
+    }
+
+    /// Blocks of a freshly mined chain whose one block after the genesis
+    /// block pays its reward to `wallet`
+    fn funded_blocks(wallet: &Wallet) -> Vec<Block> {
+        let mut blockchain = Blockchain::new(test_params());
+        blockchain.add_block(blockchain.block_template(&[], &wallet.address));
+        vec![blockchain.block_at(0).unwrap(), blockchain.block_at(1).unwrap()]
+    }
+
+    /// Transaction of `wallet` spending the reward of `block` back to itself
+    fn spend_reward(wallet: &Wallet, block: &Block) -> Transaction {
+        let reward = &block.transactions[0];
+        let input = OutPoint::new(reward.hash(), 0);
+        let outputs = vec![TxOutput::new(wallet.address.clone(), reward.outputs[0].amount - 1)];
+        let mut tx = Transaction::new(wallet.address.clone(), vec![input], outputs);
+        tx.sign(&wallet.secret_key).unwrap();
+        tx
+    }
+
+    /// Connection to `node` past the handshake, from a peer claiming to
+    /// listen on `addr`, whose messages the test reads and writes itself
+    async fn raw_peer(node: &Node, addr: &str) -> (OwnedReadHalf, tokio::net::tcp::OwnedWriteHalf) {
+        let stream = TcpStream::connect(&node.addr).await.unwrap();
+        let (mut reader, mut writer) = stream.into_split();
+        let version = Version {
+            addr: addr.to_string(),
+            ..node.version()
+        };
+        peer::connect_handshake(&mut reader, &mut writer, node.network.magic(), &version)
+            .await
+            .unwrap();
+        eventually(|| node.sessions.read().unwrap().contains_key(addr)).await;
+        (reader, writer)
+    }
+
+    /// Next message a raw peer receives, if one arrives within half a second
+    async fn next_message(reader: &mut OwnedReadHalf) -> Option<Envelope> {
+        let read = codec::read_envelope(reader, Network::Regtest.magic());
+        tokio::time::timeout(std::time::Duration::from_millis(500), read)
+            .await
+            .ok()
+            .map(Result::unwrap)
+    }
+
+    /// Announce `tx` from a raw peer and hand it over when asked for it
+    async fn relay_transaction(
+        reader: &mut OwnedReadHalf,
+        writer: &mut tokio::net::tcp::OwnedWriteHalf,
+        tx: &Transaction,
+    ) {
+        let magic = Network::Regtest.magic();
+        let inv = Message::Inv(vec![Inventory::Transaction(tx.hash())]);
+        codec::write_envelope(writer, magic, &Envelope::new(1, inv)).await.unwrap();
+        let request = next_message(reader).await.expect("transaction was not requested");
+        assert!(matches!(request.message, Message::GetData(ref items) if items == &[Inventory::Transaction(tx.hash())]));
+        let data = Message::Data {
+            blocks: vec![],
+            transactions: vec![tx.clone()],
+        };
+        let response = Envelope {
+            id: 2,
+            reply_to: Some(request.id),
+            message: data,
+        };
+        codec::write_envelope(writer, magic, &response).await.unwrap();
+    }
+
+    /// Wait up to five seconds for `check` to hold
+    async fn eventually(check: impl Fn() -> bool) {
+        for _ in 0..250 {
+            if check() {
+                return;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        panic!("condition not met in time");
+    }
+
+    /// Mine a block at `index` on top of `parent` (timestamped at `time`) with `bits`
+    fn mine_on(parent: &str, index: u64, time: u64, bits: u32) -> Block {
+        let coinbase = Transaction::coinbase(Wallet::generate().address, 0, index);
+        let mut block = Block::new(index, parent.to_string(), vec![coinbase], bits);
+        block.header.timestamp = time + 1;
+        block.mine();
+        block
+    }
+
+    #[test]
+    fn orphans_connect_once_their_parent_arrives() {
+        let node = test_node();
+        let (tip, time, bits) = {
+            let bc = node.blockchain.read().unwrap();
+            (bc.tip_hash(), bc.next_timestamp(), bc.next_bits())
+        };
+        let first = mine_on(&tip, 1, time, bits);
+        let second = mine_on(&first.hash, 2, first.header.timestamp, bits);
+        let third = mine_on(&second.hash, 3, second.header.timestamp, bits);
+
+        let accepted = node.accept_block(third.clone()).unwrap();
+        assert_eq!(accepted.missing, Some(second.hash.clone()));
+        let accepted = node.accept_block(second.clone()).unwrap();
+        assert_eq!(accepted.missing, Some(first.hash.clone()));
+        assert_eq!(node.orphans.read().unwrap().len(), 2);
+
+        let accepted = node.accept_block(first.clone()).unwrap();
+        assert_eq!(accepted.connected, [first.hash, second.hash, third.hash.clone()]);
+        assert!(node.orphans.read().unwrap().is_empty());
+        assert_eq!(node.blockchain.read().unwrap().tip_hash(), third.hash);
+    }
+
+    #[test]
+    fn orphans_easier_than_the_pow_limit_are_rejected() {
+        let node = test_node();
+        let easy = mine_on(&"ab".repeat(32), 5, 0, pow::bits_for_leading_zeros(0));
+        assert_eq!(node.accept_block(easy).err(), Some(ValidationError::BadDifficulty));
+
+        // Overflowing target, which no hash can be checked against
+        let forged = Block::new(5, "ab".repeat(32), vec![], 0x2300ffff);
+        assert_eq!(node.accept_block(forged).err(), Some(ValidationError::BadDifficulty));
+        assert!(node.orphans.read().unwrap().is_empty());
+
+        let hard = mine_on(&"ab".repeat(32), 5, 0, pow::bits_for_leading_zeros(2));
+        assert!(node.accept_block(hard).unwrap().missing.is_some());
+    }
+
+    #[test]
+    fn orphans_over_the_block_size_limit_are_rejected() {
+        let mut big = mine_on(&"ab".repeat(32), 5, 0, pow::bits_for_leading_zeros(1));
+        let max_block_size = big.transactions_size() - 1;
+        let blockchain = Blockchain::new(ChainParams {
+            max_block_size,
+            ..test_params()
+        });
+        let node = Node::new(blockchain, Network::Regtest, "127.0.0.1:0".to_string(), vec![]);
+        assert_eq!(
+            node.accept_block(big.clone()).err(),
+            Some(ValidationError::BlockTooLarge {
+                size: max_block_size + 1,
+                max: max_block_size
+            })
+        );
+        assert!(node.orphans.read().unwrap().is_empty());
+
+        big.transactions.clear();
+        big.header.merkle_root = big.calculate_merkle_root();
+        big.mine();
+        assert!(node.accept_block(big).unwrap().missing.is_some());
+    }
+
+    #[tokio::test]
+    async fn blocks_are_downloaded_only_from_peers_serving_them() {
+        let blocks = mined_blocks(2 * MAX_BLOCKS_PER_MESSAGE as u64);
+        let full = listening(chain_of(&blocks), vec![]).await;
+        let mut pruned_chain = chain_of(&blocks);
+        pruned_chain.set_prune_depth(Some(crate::blockchain::MIN_PRUNE_DEPTH)).unwrap();
+        let pruned = listening(pruned_chain, vec![]).await;
+        assert_eq!(pruned.version().services & SERVICE_BLOCKS, 0);
+
+        // The headers come from the pruned peer, but it cannot serve the old bodies
+        let node = listening(chain_of(&blocks[..1]), vec![]).await;
+        let headers: Vec<BlockHeader> = blocks[1..].iter().map(|b| b.header.clone()).collect();
+        let peers = [pruned.addr.clone(), full.addr.clone()];
+        node.download_blocks(&headers, &pruned.addr, &peers).await;
+        assert_eq!(node.blockchain.read().unwrap().len(), blocks.len());
+        assert_eq!(node.blockchain.read().unwrap().tip_hash(), blocks.last().unwrap().hash);
+    }
+
+    #[tokio::test]
+    async fn relayed_items_are_not_announced_back_to_their_sender() {
+        let wallet = Wallet::generate();
+        let blocks = funded_blocks(&wallet);
+        let receiver = listening(chain_of(&blocks), vec![]).await;
+        let sender = listening(chain_of(&blocks), vec![receiver.addr.clone()]).await;
+
+        let tx = spend_reward(&wallet, &blocks[1]);
+        sender.add_transaction(tx.clone()).unwrap();
+        sender.broadcast_transaction(&tx).await;
+        eventually(|| receiver.mempool.read().unwrap().contains(&tx.hash())).await;
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+
+        // The sender's session is under its listening address, so the
+        // receiver neither announces the item back nor dials it again
+        assert!(receiver.known_inventory.read().unwrap().knows(&sender.addr, &tx.hash()));
+        let sessions = receiver.sessions.read().unwrap();
+        assert_eq!(sessions.keys().collect::<Vec<_>>(), [&sender.addr]);
+        assert!(!sessions[&sender.addr].outbound);
+    }
+
+    #[test]
+    fn data_responses_stop_before_the_size_limit() {
+        let blocks = mined_blocks(3);
+        let node = Node::new(chain_of(&blocks), Network::Regtest, "127.0.0.1:0".to_string(), vec![]);
+        let items: Vec<Inventory> = blocks[1..].iter().map(|b| Inventory::Block(b.hash.clone())).collect();
+        let size = |block: &Block| bincode::serialized_size(block).unwrap() as usize;
+
+        let (sent, _) = node.get_data(&items, size(&blocks[1]) + size(&blocks[2]) + size(&blocks[3]) - 1);
+        let hashes: Vec<&str> = sent.iter().map(|b| b.hash.as_str()).collect();
+        assert_eq!(hashes, [blocks[1].hash.as_str(), blocks[2].hash.as_str()]);
+        assert_eq!(node.get_data(&items, usize::MAX).0.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn ban_management_needs_the_admin_token() {
+        let node = listening(Blockchain::new(test_params()), vec![]).await;
+        let local: IpAddr = "127.0.0.1".parse().unwrap();
+        node.bans.write().unwrap().ban(local, "invalid block");
+
+        // Closed until there is a token, then open only with it
+        assert!(query_bans(&node.addr, Network::Regtest, "").await.is_err());
+        node.set_admin_token("secret".to_string());
+        assert!(query_bans(&node.addr, Network::Regtest, "guess").await.is_err());
+        assert!(clear_bans(&node.addr, Network::Regtest, "guess", Some(local)).await.is_err());
+        assert!(node.is_banned(local));
+
+        // The token works from a banned IP too
+        let bans = query_bans(&node.addr, Network::Regtest, "secret").await.unwrap();
+        assert_eq!(bans.iter().map(|ban| ban.ip).collect::<Vec<_>>(), [local]);
+        assert_eq!(clear_bans(&node.addr, Network::Regtest, "secret", Some(local)).await.unwrap(), 1);
+        assert!(!node.is_banned(local));
+    }
+
+    #[test]
+    fn whitelisted_peers_are_not_banned() {
+        let node = test_node();
+        let peer = "127.0.0.1:8333".to_string();
+        node.add_peer(peer.clone());
+        node.bans.write().unwrap().whitelist("127.0.0.1".parse().unwrap());
+
+        node.misbehaving(&peer, Offence::InvalidBlock);
+        assert!(!node.is_banned("127.0.0.1".parse().unwrap()));
+        assert_eq!(node.get_peers(), [peer]);
+    }
+
+    #[tokio::test]
+    async fn items_already_seen_are_not_fetched_again() {
+        let wallet = Wallet::generate();
+        let blocks = funded_blocks(&wallet);
+        let node = listening(chain_of(&blocks), vec![]).await;
+        let tx = spend_reward(&wallet, &blocks[1]);
+
+        let (mut reader, mut writer) = raw_peer(&node, "127.0.0.1:1").await;
+        relay_transaction(&mut reader, &mut writer, &tx).await;
+        eventually(|| node.mempool.read().unwrap().contains(&tx.hash())).await;
+        assert!(node.seen.read().unwrap().contains(&tx.hash()));
+
+        // Gone from the mempool, but still remembered as seen: another
+        // peer's announcement of it is not followed up
+        *node.mempool.write().unwrap() = Mempool::new(node.blockchain.read().unwrap().params.ledger);
+        let (mut reader, mut writer) = raw_peer(&node, "127.0.0.1:2").await;
+        let inv = Message::Inv(vec![Inventory::Transaction(tx.hash())]);
+        codec::write_envelope(&mut writer, Network::Regtest.magic(), &Envelope::new(1, inv)).await.unwrap();
+        assert!(next_message(&mut reader).await.is_none());
+        assert!(node.known_inventory.read().unwrap().knows("127.0.0.1:2", &tx.hash()));
+    }
+
+    #[tokio::test]
+    async fn wallets_can_submit_transactions_larger_than_a_control_message() {
+        let wallet = Wallet::generate();
+        let mut blockchain = Blockchain::new(ChainParams { initial_subsidy: 1000, ..test_params() });
+        blockchain.add_block(blockchain.block_template(&[], &wallet.address));
+        let reward = blockchain.block_at(1).unwrap().transactions[0].clone();
+        let node = listening(blockchain, vec![]).await;
+
+        let payee = Wallet::generate().address;
+        let outputs = (1..reward.outputs[0].amount).map(|_| TxOutput::new(payee.clone(), 1)).collect();
+        let mut tx = Transaction::new(wallet.address.clone(), vec![OutPoint::new(reward.hash(), 0)], outputs);
+        tx.sign(&wallet.secret_key).unwrap();
+        assert!(tx.size() > Command::GetStatus.max_size());
+
+        let mut stream = TcpStream::connect(&node.addr).await.unwrap();
+        let submission = Envelope::new(1, Message::NewTransaction(tx.clone()));
+        codec::write_envelope(&mut stream, Network::Regtest.magic(), &submission).await.unwrap();
+        eventually(|| node.mempool.read().unwrap().contains(&tx.hash())).await;
+    }
+
+    #[tokio::test]
+    async fn only_responses_to_requests_never_made_are_unsolicited() {
+        let node = listening(Blockchain::new(test_params()), vec![]).await;
+        let ip = "127.0.0.1".parse().unwrap();
+        let (mut reader, mut writer) = raw_peer(&node, "127.0.0.1:1").await;
+        let magic = Network::Regtest.magic();
+
+        // Stands in for a request that timed out before its response came
+        let session = node.sessions.read().unwrap()["127.0.0.1:1"].clone();
+        session.send(Message::GetPeers).unwrap();
+        let late = next_message(&mut reader).await.expect("message was not sent");
+        let response = Envelope {
+            id: 1,
+            reply_to: Some(late.id),
+            message: Message::Peers(vec![]),
+        };
+        codec::write_envelope(&mut writer, magic, &response).await.unwrap();
+        // Answered only once the response before it has been dealt with
+        codec::write_envelope(&mut writer, magic, &Envelope::new(2, Message::GetStatus)).await.unwrap();
+        assert!(next_message(&mut reader).await.is_some());
+        assert_eq!(node.bans.read().unwrap().score(ip), 0);
+
+        let unsolicited = Envelope {
+            reply_to: Some(late.id + 100),
+            ..response
+        };
+        codec::write_envelope(&mut writer, magic, &unsolicited).await.unwrap();
+        eventually(|| node.bans.read().unwrap().score(ip) > 0).await;
+        assert_eq!(node.bans.read().unwrap().score(ip), Offence::UnsolicitedData.score());
+    }
+
+    #[tokio::test]
+    async fn only_valid_items_are_relayed() {
+        let wallet = Wallet::generate();
+        let blocks = funded_blocks(&wallet);
+        let node = listening(chain_of(&blocks), vec![]).await;
+        let (mut source_reader, mut source_writer) = raw_peer(&node, "127.0.0.1:1").await;
+        let (mut reader, _writer) = raw_peer(&node, "127.0.0.1:2").await;
+
+        // Spends a coin that does not exist: rejected, so not passed on
+        let mut unfunded = spend_reward(&wallet, &blocks[1]);
+        unfunded.inputs[0] = OutPoint::new("ab".repeat(32), 0);
+        unfunded.sign(&wallet.secret_key).unwrap();
+        relay_transaction(&mut source_reader, &mut source_writer, &unfunded).await;
+        assert!(next_message(&mut reader).await.is_none());
+        assert!(!node.mempool.read().unwrap().contains(&unfunded.hash()));
+
+        let tx = spend_reward(&wallet, &blocks[1]);
+        relay_transaction(&mut source_reader, &mut source_writer, &tx).await;
+        let announced = next_message(&mut reader).await.expect("transaction was not relayed");
+        assert!(matches!(announced.message, Message::Inv(ref items) if items == &[Inventory::Transaction(tx.hash())]));
+        assert!(node.mempool.read().unwrap().contains(&tx.hash()));
+
+        // Not announced back to the peer it came from
+        assert!(next_message(&mut source_reader).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn clock_samples_are_kept_per_ip() {
+        let node = listening(Blockchain::new(test_params()), vec![]).await;
+        let _first = raw_peer(&node, "127.0.0.1:1").await;
+        let _second = raw_peer(&node, "127.0.0.1:2").await;
+        assert_eq!(node.sessions.read().unwrap().len(), 2);
+        assert_eq!(node.time.read().unwrap().sample_count(), 1);
+    }
+}
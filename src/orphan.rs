@@ -0,0 +1,204 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::block::Block;
+
+/// Default number of orphan blocks kept at once
+pub const DEFAULT_MAX_ORPHANS: usize = 100;
+
+/// Default time an orphan block is kept waiting for its parent
+pub const DEFAULT_ORPHAN_EXPIRY: Duration = Duration::from_secs(20 * 60);
+
+/// A block whose parent is not known yet
+#[derive(Debug, Clone)]
+struct Orphan {
+    block: Block,
+    received: Instant,
+}
+
+/// Blocks received before their parent, indexed by the missing parent hash
+#[derive(Debug)]
+pub struct OrphanPool {
+    orphans: HashMap<String, Orphan>,
+    by_parent: HashMap<String, Vec<String>>,
+    max_orphans: usize,
+    expiry: Duration,
+}
+
+impl Default for OrphanPool {
+    fn default() -> Self {
+        OrphanPool::new(DEFAULT_MAX_ORPHANS, DEFAULT_ORPHAN_EXPIRY)
+    }
+}
+
+impl OrphanPool {
+    /// Create a pool holding at most `max_orphans` blocks for at most `expiry`
+    pub fn new(max_orphans: usize, expiry: Duration) -> Self {
+        OrphanPool {
+            orphans: HashMap::new(),
+            by_parent: HashMap::new(),
+            max_orphans,
+            expiry,
+        }
+    }
+
+    /// Check if a block is held in the pool
+    pub fn contains(&self, hash: &str) -> bool {
+        self.orphans.contains_key(hash)
+    }
+
+    /// Number of blocks in the pool
+    pub fn len(&self) -> usize {
+        self.orphans.len()
+    }
+
+    /// Check if the pool is empty
+    pub fn is_empty(&self) -> bool {
+        self.orphans.is_empty()
+    }
+
+    /// Add an orphan block, dropping expired blocks and, if the pool is full,
+    /// the oldest one. Returns false if the block was already held.
+    pub fn insert(&mut self, block: Block) -> bool {
+        if self.contains(&block.hash) || self.max_orphans == 0 {
+            return false;
+        }
+
+        self.expire();
+        if self.orphans.len() >= self.max_orphans {
+            let oldest = self
+                .orphans
+                .iter()
+                .min_by_key(|(_, orphan)| orphan.received)
+                .map(|(hash, _)| hash.clone());
+            if let Some(hash) = oldest {
+                self.remove(&hash);
+            }
+        }
+
+        self.by_parent
+            .entry(block.header.prev_hash.clone())
+            .or_default()
+            .push(block.hash.clone());
+        self.orphans.insert(
+            block.hash.clone(),
+            Orphan {
+                block,
+                received: Instant::now(),
+            },
+        );
+        true
+    }
+
+    /// Remove and return the orphans whose parent is `parent_hash`
+    pub fn take_children(&mut self, parent_hash: &str) -> Vec<Block> {
+        let hashes = self.by_parent.remove(parent_hash).unwrap_or_default();
+        hashes
+            .into_iter()
+            .filter_map(|hash| self.orphans.remove(&hash))
+            .map(|orphan| orphan.block)
+            .collect()
+    }
+
+    /// Follow the parents of an orphan chain back to the first block not held
+    /// in the pool: the ancestor that has to be fetched to connect `hash`
+    pub fn missing_ancestor(&self, hash: &str) -> String {
+        let mut cursor = hash;
+        while let Some(orphan) = self.orphans.get(cursor) {
+            cursor = &orphan.block.header.prev_hash;
+        }
+        cursor.to_string()
+    }
+
+    /// Drop orphans held for longer than the expiry time
+    pub fn expire(&mut self) {
+        let expired: Vec<String> = self
+            .orphans
+            .iter()
+            .filter(|(_, orphan)| orphan.received.elapsed() > self.expiry)
+            .map(|(hash, _)| hash.clone())
+            .collect();
+        for hash in expired {
+            self.remove(&hash);
+        }
+    }
+
+    /// Remove a single orphan
+    fn remove(&mut self, hash: &str) {
+        let Some(orphan) = self.orphans.remove(hash) else {
+            return;
+        };
+        let parent = &orphan.block.header.prev_hash;
+        if let Some(children) = self.by_parent.get_mut(parent) {
+            children.retain(|h| h != hash);
+            if children.is_empty() {
+                self.by_parent.remove(parent);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Unmined block on top of `parent`
+    fn child_of(parent: &str) -> Block {
+        Block::new(0, parent.to_string(), vec![], 0x207fffff)
+    }
+
+    #[test]
+    fn children_are_taken_once_their_parent_arrives() {
+        let mut pool = OrphanPool::default();
+        let parent = "ab".repeat(32);
+        let a = child_of(&parent);
+        let mut b = child_of(&parent);
+        b.header.nonce = 1;
+        b.hash = b.calculate_hash();
+        let grandchild = child_of(&a.hash);
+        assert!(pool.insert(a.clone()));
+        assert!(!pool.insert(a.clone()));
+        pool.insert(grandchild.clone());
+        pool.insert(b.clone());
+        assert_eq!(pool.missing_ancestor(&grandchild.hash), parent);
+
+        let children: Vec<String> = pool.take_children(&parent).into_iter().map(|b| b.hash).collect();
+        assert_eq!(children.len(), 2);
+        assert!(children.contains(&a.hash) && children.contains(&b.hash));
+        assert!(pool.take_children(&parent).is_empty());
+        assert_eq!(pool.missing_ancestor(&grandchild.hash), a.hash);
+        assert_eq!(pool.take_children(&a.hash)[0].hash, grandchild.hash);
+        assert!(pool.is_empty());
+    }
+
+    #[test]
+    fn full_pool_drops_the_oldest_orphan() {
+        let mut pool = OrphanPool::new(2, DEFAULT_ORPHAN_EXPIRY);
+        let first = child_of(&"01".repeat(32));
+        let second = child_of(&first.hash);
+        let third = child_of(&second.hash);
+        for block in [&first, &second, &third] {
+            pool.insert(block.clone());
+            std::thread::sleep(Duration::from_millis(2));
+        }
+
+        assert_eq!(pool.len(), 2);
+        assert!(!pool.contains(&first.hash));
+        assert!(pool.take_children(&first.header.prev_hash).is_empty());
+        assert_eq!(pool.missing_ancestor(&third.hash), first.hash);
+    }
+
+    #[test]
+    fn expired_orphans_are_dropped() {
+        let mut pool = OrphanPool::new(10, Duration::from_millis(20));
+        let old = child_of(&"02".repeat(32));
+        pool.insert(old.clone());
+        std::thread::sleep(Duration::from_millis(40));
+
+        let new = child_of(&"03".repeat(32));
+        pool.insert(new.clone());
+        assert!(!pool.contains(&old.hash));
+        assert!(pool.contains(&new.hash));
+        assert!(pool.take_children(&old.header.prev_hash).is_empty());
+    }
+}
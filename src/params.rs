@@ -0,0 +1,182 @@
+use serde::{Deserialize, Serialize};
+
+use crate::ledger::LedgerModel;
+use crate::pow;
+
+/// How the proof-of-work target follows the observed block rate
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RetargetAlgorithm {
+    /// Bitcoin-style: adjust once every `difficulty_window` blocks
+    #[default]
+    Window,
+    /// Linearly weighted moving average over the last `difficulty_window` blocks,
+    /// adjusted every block
+    Lwma,
+}
+
+impl std::str::FromStr for RetargetAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "window" => Ok(RetargetAlgorithm::Window),
+            "lwma" => Ok(RetargetAlgorithm::Lwma),
+            _ => Err(format!("unknown retarget algorithm '{}' (expected window or lwma)", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for RetargetAlgorithm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RetargetAlgorithm::Window => write!(f, "window"),
+            RetargetAlgorithm::Lwma => write!(f, "lwma"),
+        }
+    }
+}
+
+/// Consensus parameters shared by every node on a network
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChainParams {
+    /// How coin ownership is tracked
+    pub ledger: LedgerModel,
+    /// Block reward before the first halving
+    pub initial_subsidy: u64,
+    /// Number of blocks between reward halvings
+    pub halving_interval: u64,
+    /// Maximum number of coins that can ever be created
+    pub max_supply: u64,
+    /// Maximum total serialized size of a block's transactions, in bytes
+    pub max_block_size: usize,
+    /// Compact target of the genesis block, used until the first retarget
+    pub genesis_bits: u32,
+    /// Easiest target any block may have. Networks set it to their starting
+    /// difficulty; the default is the easiest encodable target.
+    pub pow_limit_bits: u32,
+    /// Desired number of seconds between blocks
+    pub target_block_time: u64,
+    /// Difficulty adjustment algorithm
+    pub retarget: RetargetAlgorithm,
+    /// Number of blocks the difficulty adjustment looks back over
+    pub difficulty_window: u64,
+    /// Number of previous blocks whose median timestamp a new block must exceed
+    pub median_time_span: usize,
+    /// How many seconds a block timestamp may be ahead of network-adjusted time
+    pub max_future_drift: u64,
+}
+
+impl Default for ChainParams {
+    fn default() -> Self {
+        ChainParams {
+            ledger: LedgerModel::Utxo,
+            initial_subsidy: 50,
+            halving_interval: 210_000,
+            max_supply: 21_000_000,
+            max_block_size: 1_000_000,
+            genesis_bits: pow::bits_for_leading_zeros(4),
+            pow_limit_bits: pow::bits_for_leading_zeros(0),
+            target_block_time: 10,
+            retarget: RetargetAlgorithm::Window,
+            difficulty_window: 20,
+            median_time_span: 11,
+            max_future_drift: 2 * 60 * 60,
+        }
+    }
+}
+
+impl ChainParams {
+    /// Scheduled reward of the era containing `height`, ignoring the supply cap
+    fn era_subsidy(&self, height: u64) -> u64 {
+        let halvings = height / self.halving_interval.max(1);
+        if halvings >= 64 {
+            0
+        } else {
+            self.initial_subsidy >> halvings
+        }
+    }
+
+    /// Total scheduled rewards of blocks 1 up to (excluding) `height`,
+    /// ignoring the supply cap. The genesis block carries no reward.
+    fn scheduled_before(&self, height: u64) -> u64 {
+        let interval = self.halving_interval.max(1);
+        let mut issued: u64 = 0;
+        let mut era_start = 0;
+
+        while era_start < height {
+            let subsidy = self.era_subsidy(era_start);
+            if subsidy == 0 {
+                break;
+            }
+            let first = era_start.max(1);
+            let end = era_start.saturating_add(interval).min(height);
+            issued = issued.saturating_add(subsidy.saturating_mul(end.saturating_sub(first)));
+            era_start = era_start.saturating_add(interval);
+        }
+
+        issued
+    }
+
+    /// Total rewards of all blocks below `height`
+    pub fn issued_before(&self, height: u64) -> u64 {
+        self.scheduled_before(height).min(self.max_supply)
+    }
+
+    /// Block reward at `height`, halved every `halving_interval` blocks and
+    /// reduced so that total issuance never exceeds `max_supply`
+    pub fn subsidy(&self, height: u64) -> u64 {
+        if height == 0 {
+            return 0;
+        }
+
+        let remaining = self.max_supply - self.issued_before(height);
+        self.era_subsidy(height).min(remaining)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(initial_subsidy: u64, halving_interval: u64, max_supply: u64) -> ChainParams {
+        ChainParams {
+            initial_subsidy,
+            halving_interval,
+            max_supply,
+            ..ChainParams::default()
+        }
+    }
+
+    #[test]
+    fn subsidy_halves_every_interval() {
+        let params = params(50, 10, u64::MAX);
+        assert_eq!(params.subsidy(0), 0);
+        assert_eq!(params.subsidy(1), 50);
+        assert_eq!(params.subsidy(9), 50);
+        assert_eq!(params.subsidy(10), 25);
+        assert_eq!(params.subsidy(20), 12);
+        assert_eq!(params.subsidy(60), 0);
+        assert_eq!(params.subsidy(u64::MAX), 0);
+        assert_eq!(params.issued_before(11), 9 * 50 + 25);
+    }
+
+    #[test]
+    fn issuance_stops_at_the_supply_cap() {
+        let params = params(50, 10, 120);
+        assert_eq!(params.subsidy(1), 50);
+        assert_eq!(params.subsidy(2), 50);
+        assert_eq!(params.subsidy(3), 20);
+        assert_eq!(params.subsidy(4), 0);
+        assert_eq!(params.issued_before(1000), 120);
+
+        let total: u64 = (0..100).map(|height| params.subsidy(height)).sum();
+        assert_eq!(total, 120);
+    }
+
+    #[test]
+    fn default_schedule_issues_every_era_once() {
+        // 50 + 25 + 12 + 6 + 3 + 1 per block of each era, none for the genesis block
+        let params = ChainParams::default();
+        assert_eq!(params.issued_before(u64::MAX), 97 * 210_000 - 50);
+        assert!(params.issued_before(u64::MAX) <= params.max_supply);
+    }
+}
@@ -0,0 +1,382 @@
+//! Long-lived peer sessions.
+//!
+//! Messages travel in `Envelope`s framed by `codec`, which rejects frames
+//! from another network on arrival. A connection opens
+//! with a handshake: the connecting side sends `Version`, the other side
+//! answers with its own `Version`, and each side sends `Verack` once it has
+//! accepted the other's: same network, chain id and (once both have one)
+//! genesis block. After that both sides may send requests and
+//! notifications at any time; a response names the request it answers, so
+//! several requests can be outstanding at once.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::io::AsyncWriteExt;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::sync::{mpsc, oneshot, Notify};
+
+use crate::codec::{self, Envelope};
+use crate::message::{Message, Version};
+
+/// Version of the peer protocol spoken by this build
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Oldest peer protocol version we can talk to
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// Service flag: the peer serves every block body (it does not prune)
+pub const SERVICE_BLOCKS: u64 = 1;
+
+/// Service flag: the peer answers transaction and address queries
+pub const SERVICE_INDEX: u64 = 2;
+
+/// Latest clock a peer may report in its handshake, so its offset from ours
+/// always fits an `i64`
+pub const MAX_TIMESTAMP: u64 = i64::MAX as u64;
+
+/// Time allowed for a connection to be established
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Time allowed for the handshake
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Time allowed for a peer to answer a request
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// An established session with a peer. Messages are queued to a writer task;
+/// the node's reader task hands responses back through `complete`.
+#[derive(Debug)]
+pub struct Session {
+    /// Key of the session: the address we connected to for outbound
+    /// sessions; for inbound ones the listening address the peer reports if
+    /// it is on the IP the connection comes from, else its socket address
+    pub addr: String,
+    /// Address the connection comes from. Unlike what the peer reports about
+    /// itself, it cannot be made up, so it is what bans apply to.
+    pub remote: SocketAddr,
+    /// What the peer announced in its handshake
+    pub version: Version,
+    /// Whether we opened the connection
+    pub outbound: bool,
+    /// Magic of the network the session is on
+    pub magic: [u8; 4],
+    sender: mpsc::UnboundedSender<Option<Envelope>>,
+    pending: Mutex<HashMap<u64, oneshot::Sender<Message>>>,
+    next_id: AtomicU64,
+    closed: Notify,
+}
+
+impl Session {
+    /// Create a session writing to `writer` from a background task
+    pub fn start(
+        addr: String,
+        remote: SocketAddr,
+        version: Version,
+        outbound: bool,
+        magic: [u8; 4],
+        writer: OwnedWriteHalf,
+    ) -> Self {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(write_loop(writer, magic, receiver));
+        Session {
+            addr,
+            remote,
+            version,
+            outbound,
+            magic,
+            sender,
+            pending: Mutex::new(HashMap::new()),
+            // Id 0 is used by the handshake
+            next_id: AtomicU64::new(1),
+            closed: Notify::new(),
+        }
+    }
+
+    /// Send a message that expects no response
+    pub fn send(&self, message: Message) -> Result<(), String> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.queue(Envelope::new(id, message))
+    }
+
+    /// Send a request and wait for the response
+    pub async fn request(&self, message: Message) -> Result<Message, String> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (sender, receiver) = oneshot::channel();
+        self.pending.lock().unwrap().insert(id, sender);
+
+        if let Err(e) = self.queue(Envelope::new(id, message)) {
+            self.pending.lock().unwrap().remove(&id);
+            return Err(e);
+        }
+
+        match tokio::time::timeout(REQUEST_TIMEOUT, receiver).await {
+            Ok(Ok(response)) => Ok(response),
+            Ok(Err(_)) => Err(format!("{} disconnected", self.addr)),
+            Err(_) => {
+                self.pending.lock().unwrap().remove(&id);
+                Err(format!("{} did not respond in time", self.addr))
+            }
+        }
+    }
+
+    /// Answer the request with id `reply_to`
+    pub fn respond(&self, reply_to: u64, message: Message) -> Result<(), String> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.queue(Envelope {
+            id,
+            reply_to: Some(reply_to),
+            message,
+        })
+    }
+
+    /// Hand a response to the request waiting for it. Returns false if no
+    /// request is waiting (it timed out, or the peer sent an unsolicited response).
+    pub fn complete(&self, reply_to: u64, message: Message) -> bool {
+        match self.pending.lock().unwrap().remove(&reply_to) {
+            Some(waiter) => waiter.send(message).is_ok(),
+            None => false,
+        }
+    }
+
+    /// Check if `id` was given to a message of ours, so a response to it is
+    /// at worst late rather than unsolicited
+    pub fn issued(&self, id: u64) -> bool {
+        id < self.next_id.load(Ordering::Relaxed)
+    }
+
+    /// Close the connection once the queued messages are written. Waiting
+    /// requests fail.
+    pub fn close(&self) {
+        let _ = self.sender.send(None);
+        self.pending.lock().unwrap().clear();
+        self.closed.notify_one();
+    }
+
+    /// Wait until the session is closed by `close`
+    pub async fn closed(&self) {
+        self.closed.notified().await
+    }
+
+    /// Check if the peer sent a service flag
+    pub fn has_service(&self, service: u64) -> bool {
+        self.version.services & service != 0
+    }
+
+    fn queue(&self, envelope: Envelope) -> Result<(), String> {
+        self.sender
+            .send(Some(envelope))
+            .map_err(|_| format!("{} disconnected", self.addr))
+    }
+}
+
+/// Write queued envelopes until the session is closed
+async fn write_loop(
+    mut writer: OwnedWriteHalf,
+    magic: [u8; 4],
+    mut receiver: mpsc::UnboundedReceiver<Option<Envelope>>,
+) {
+    while let Some(Some(envelope)) = receiver.recv().await {
+        match codec::write_envelope(&mut writer, magic, &envelope).await {
+            Ok(()) => {}
+            // A message too large to send is dropped, the session stays open
+            Err(e) if e.is_malformed() => eprintln!("Not sending message to peer: {}", e),
+            Err(e) => {
+                eprintln!("Failed to write to peer: {}", e);
+                break;
+            }
+        }
+    }
+    let _ = writer.shutdown().await;
+}
+
+/// Check a peer's `Version` against ours
+pub fn check_version(ours: &Version, theirs: &Version) -> Result<(), String> {
+    if theirs.version < MIN_PROTOCOL_VERSION {
+        return Err(format!("protocol version {} is too old", theirs.version));
+    }
+    if theirs.timestamp > MAX_TIMESTAMP {
+        return Err(format!("timestamp {} is out of range", theirs.timestamp));
+    }
+    if theirs.network != ours.network {
+        return Err(format!("peer is on network {}, not {}", theirs.network, ours.network));
+    }
+    if theirs.chain_id != ours.chain_id {
+        return Err(format!(
+            "peer follows chain {}, not {} (different consensus rules)",
+            theirs.chain_id, ours.chain_id
+        ));
+    }
+    if let (Some(their_genesis), Some(our_genesis)) = (&theirs.genesis, &ours.genesis) {
+        if their_genesis != our_genesis {
+            return Err(format!("peer has genesis block {}, not {}", their_genesis, our_genesis));
+        }
+    }
+    Ok(())
+}
+
+/// Handshake of the connecting side: send our version, then expect the
+/// peer's version and its verack
+pub async fn connect_handshake(
+    reader: &mut OwnedReadHalf,
+    writer: &mut OwnedWriteHalf,
+    magic: [u8; 4],
+    ours: &Version,
+) -> Result<Version, String> {
+    tokio::time::timeout(HANDSHAKE_TIMEOUT, async {
+        send_handshake(writer, magic, Message::Version(ours.clone())).await?;
+        let theirs = match codec::read_envelope(reader, magic).await.map_err(|e| e.to_string())?.message {
+            Message::Version(theirs) => theirs,
+            other => return Err(format!("expected version, got {:?}", other)),
+        };
+        check_version(ours, &theirs)?;
+        send_handshake(writer, magic, Message::Verack).await?;
+        expect_verack(reader, magic).await?;
+        Ok(theirs)
+    })
+    .await
+    .map_err(|_| String::from("handshake timed out"))?
+}
+
+/// Handshake of the accepting side, after the peer's `Version` arrived:
+/// answer with our version and verack, then expect the peer's verack
+pub async fn accept_handshake(
+    reader: &mut OwnedReadHalf,
+    writer: &mut OwnedWriteHalf,
+    magic: [u8; 4],
+    ours: &Version,
+    theirs: &Version,
+) -> Result<(), String> {
+    tokio::time::timeout(HANDSHAKE_TIMEOUT, async {
+        check_version(ours, theirs)?;
+        send_handshake(writer, magic, Message::Version(ours.clone())).await?;
+        send_handshake(writer, magic, Message::Verack).await?;
+        expect_verack(reader, magic).await
+    })
+    .await
+    .map_err(|_| String::from("handshake timed out"))?
+}
+
+async fn send_handshake(writer: &mut OwnedWriteHalf, magic: [u8; 4], message: Message) -> Result<(), String> {
+    codec::write_envelope(writer, magic, &Envelope::new(0, message)).await.map_err(|e| e.to_string())
+}
+
+async fn expect_verack(reader: &mut OwnedReadHalf, magic: [u8; 4]) -> Result<(), String> {
+    match codec::read_envelope(reader, magic).await.map_err(|e| e.to_string())?.message {
+        Message::Verack => Ok(()),
+        other => Err(format!("expected verack, got {:?}", other)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::{TcpListener, TcpStream};
+
+    const MAGIC: [u8; 4] = [0xc5, 0x70, 0x0c, 0xc3];
+
+    /// Version of a node on regtest with genesis block `genesis`
+    fn version(addr: &str, genesis: &str) -> Version {
+        Version {
+            version: PROTOCOL_VERSION,
+            network: "regtest".to_string(),
+            chain_id: "chain".to_string(),
+            genesis: Some(genesis.to_string()),
+            best_height: 1,
+            services: SERVICE_BLOCKS,
+            addr: addr.to_string(),
+            timestamp: 0,
+        }
+    }
+
+    /// Both ends of a local connection: the connecting one and the accepted one
+    async fn connection() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        (client, server)
+    }
+
+    /// Run the accepting side of a handshake on `stream` as `ours`
+    async fn accept(stream: TcpStream, ours: Version) -> Result<Version, String> {
+        let (mut reader, mut writer) = stream.into_split();
+        let theirs = match codec::read_envelope(&mut reader, MAGIC).await.map_err(|e| e.to_string())?.message {
+            Message::Version(theirs) => theirs,
+            other => return Err(format!("expected version, got {:?}", other)),
+        };
+        accept_handshake(&mut reader, &mut writer, MAGIC, &ours, &theirs).await?;
+        Ok(theirs)
+    }
+
+    #[tokio::test]
+    async fn handshake_exchanges_versions() {
+        let (client, server) = connection().await;
+        let accepting = tokio::spawn(accept(server, version("127.0.0.1:2", "aa")));
+
+        let (mut reader, mut writer) = client.into_split();
+        let theirs = connect_handshake(&mut reader, &mut writer, MAGIC, &version("127.0.0.1:1", "aa"))
+            .await
+            .unwrap();
+        assert_eq!(theirs, version("127.0.0.1:2", "aa"));
+        assert_eq!(accepting.await.unwrap().unwrap(), version("127.0.0.1:1", "aa"));
+    }
+
+    #[tokio::test]
+    async fn handshake_with_another_genesis_is_rejected() {
+        let (client, server) = connection().await;
+        let accepting = tokio::spawn(accept(server, version("127.0.0.1:2", "bb")));
+
+        let (mut reader, mut writer) = client.into_split();
+        let ours = version("127.0.0.1:1", "aa");
+        assert!(connect_handshake(&mut reader, &mut writer, MAGIC, &ours).await.is_err());
+        assert!(accepting.await.unwrap().unwrap_err().contains("genesis block"));
+    }
+
+    #[test]
+    fn versions_from_other_networks_and_chains_are_rejected() {
+        let ours = version("127.0.0.1:1", "aa");
+        assert!(check_version(&ours, &version("127.0.0.1:2", "aa")).is_ok());
+
+        let mut theirs = version("127.0.0.1:2", "aa");
+        theirs.genesis = None;
+        assert!(check_version(&ours, &theirs).is_ok());
+
+        let mut theirs = version("127.0.0.1:2", "aa");
+        theirs.network = "main".to_string();
+        assert!(check_version(&ours, &theirs).unwrap_err().contains("network"));
+
+        let mut theirs = version("127.0.0.1:2", "aa");
+        theirs.chain_id = "other".to_string();
+        assert!(check_version(&ours, &theirs).unwrap_err().contains("chain"));
+
+        let theirs = version("127.0.0.1:2", "bb");
+        assert!(check_version(&ours, &theirs).unwrap_err().contains("genesis block"));
+
+        let mut theirs = version("127.0.0.1:2", "aa");
+        theirs.version = MIN_PROTOCOL_VERSION - 1;
+        assert!(check_version(&ours, &theirs).unwrap_err().contains("too old"));
+
+        let mut theirs = version("127.0.0.1:2", "aa");
+        theirs.timestamp = MAX_TIMESTAMP + 1;
+        assert!(check_version(&ours, &theirs).unwrap_err().contains("out of range"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn silent_peer_times_out() {
+        let (client, _server) = connection().await;
+        let (mut reader, mut writer) = client.into_split();
+        let ours = version("127.0.0.1:1", "aa");
+        let result = connect_handshake(&mut reader, &mut writer, MAGIC, &ours).await;
+        assert_eq!(result.unwrap_err(), "handshake timed out");
+
+        // A peer that sends its version but never its verack
+        let (_client, server) = connection().await;
+        let (mut reader, mut writer) = server.into_split();
+        let theirs = version("127.0.0.1:2", "aa");
+        let result = accept_handshake(&mut reader, &mut writer, MAGIC, &ours, &theirs).await;
+        assert_eq!(result.unwrap_err(), "handshake timed out");
+    }
+}
@@ -0,0 +1,153 @@
+//! Proof-of-work targets: 256-bit thresholds stored in block headers in the
+//! compact "bits" format used by Bitcoin (one exponent byte giving the size
+//! of the number in bytes, followed by a 3-byte mantissa).
+
+mod uint256 {
+    // Lints fire inside the code generated by `construct_uint!`
+    #![allow(clippy::assign_op_pattern, clippy::manual_div_ceil)]
+
+    uint::construct_uint! {
+        /// 256-bit unsigned integer used for targets and chain work
+        pub struct U256(4);
+    }
+}
+
+pub use uint256::U256;
+
+/// Decode a compact target. Returns `None` for negative or overflowing encodings.
+pub fn target_from_bits(bits: u32) -> Option<U256> {
+    let size = bits >> 24;
+    let mut word = bits & 0x007f_ffff;
+
+    if word != 0 && bits & 0x0080_0000 != 0 {
+        return None;
+    }
+    if word != 0 && (size > 34 || (word > 0xff && size > 33) || (word > 0xffff && size > 32)) {
+        return None;
+    }
+
+    if size <= 3 {
+        word >>= 8 * (3 - size);
+        Some(U256::from(word))
+    } else {
+        Some(U256::from(word) << (8 * (size - 3) as usize))
+    }
+}
+
+/// Encode a target in compact form (rounding down to a 3-byte mantissa)
+pub fn bits_from_target(target: U256) -> u32 {
+    let mut size = (target.bits() as u32).div_ceil(8);
+    let mut compact = if size <= 3 {
+        (target.low_u64() << (8 * (3 - size))) as u32
+    } else {
+        (target >> (8 * (size - 3) as usize)).low_u64() as u32
+    };
+
+    // The mantissa's top bit is a sign bit; move it into the exponent instead
+    if compact & 0x0080_0000 != 0 {
+        compact >>= 8;
+        size += 1;
+    }
+
+    compact | (size << 24)
+}
+
+/// Compact target equivalent to requiring `zeros` leading hex zeros in the hash
+pub fn bits_for_leading_zeros(zeros: usize) -> u32 {
+    let target = if zeros >= 64 {
+        U256::zero()
+    } else {
+        U256::MAX >> (4 * zeros)
+    };
+    bits_from_target(target)
+}
+
+/// Interpret a hex-encoded hash as a big-endian 256-bit number
+pub fn hash_to_u256(hash: &str) -> Option<U256> {
+    let bytes = hex::decode(hash).ok()?;
+    if bytes.len() != 32 {
+        return None;
+    }
+    Some(U256::from_big_endian(&bytes))
+}
+
+/// Check if a hash is at or below the target encoded by `bits`
+pub fn hash_meets_bits(hash: &str, bits: u32) -> bool {
+    match (hash_to_u256(hash), target_from_bits(bits)) {
+        (Some(value), Some(target)) => value <= target,
+        _ => false,
+    }
+}
+
+/// Check if `bits` encodes a valid target no easier than the one encoded by `limit_bits`
+pub fn bits_within_limit(bits: u32, limit_bits: u32) -> bool {
+    let limit = target_from_bits(limit_bits).unwrap_or(U256::MAX);
+    matches!(target_from_bits(bits), Some(target) if target <= limit)
+}
+
+/// Expected number of hashes needed to meet a target: 2^256 / (target + 1)
+pub fn work_from_target(target: U256) -> U256 {
+    if target == U256::MAX {
+        return U256::one();
+    }
+    // 2^256 does not fit, but 2^256 / (t + 1) == (!t / (t + 1)) + 1
+    (!target / (target + U256::one())) + U256::one()
+}
+
+/// Work represented by a block with compact target `bits`
+pub fn work_from_bits(bits: u32) -> U256 {
+    target_from_bits(bits)
+        .map(work_from_target)
+        .unwrap_or_else(U256::zero)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn canonical_bits_round_trip() {
+        for bits in [0x1d00ffff, 0x1b0404cb, 0x207fffff, 0x2100ffff, 0x03123456, 0x02008000, 0x01120000] {
+            let target = target_from_bits(bits).unwrap();
+            assert_eq!(bits_from_target(target), bits, "bits {:08x}", bits);
+        }
+    }
+
+    #[test]
+    fn bitcoin_genesis_target_and_work() {
+        let target = target_from_bits(0x1d00ffff).unwrap();
+        assert_eq!(target, U256::from(0xffff) << 208);
+        assert_eq!(work_from_bits(0x1d00ffff), U256::from(0x0100010001u64));
+        assert_eq!(work_from_target(U256::MAX), U256::one());
+    }
+
+    #[test]
+    fn mantissa_sign_bit_moves_into_the_exponent() {
+        // 0x80 would set the sign bit of a 1-byte mantissa
+        assert_eq!(bits_from_target(U256::from(0x80)), 0x02008000);
+        assert_eq!(bits_from_target(U256::from(0x12)), 0x01120000);
+        assert_eq!(bits_from_target(U256::zero()), 0);
+    }
+
+    #[test]
+    fn negative_and_overflowing_bits_are_rejected() {
+        assert_eq!(target_from_bits(0x04923456), None);
+        assert_eq!(target_from_bits(0x23000001), None);
+        assert_eq!(target_from_bits(0x22000100), None);
+        assert_eq!(target_from_bits(0x21010000), None);
+        assert_eq!(target_from_bits(0xff123456), None);
+        assert_eq!(target_from_bits(0x22000001), Some(U256::one() << 248));
+        // A zero mantissa is zero whatever the exponent or sign
+        assert_eq!(target_from_bits(0xff800000), Some(U256::zero()));
+        assert_eq!(work_from_bits(0x04923456), U256::zero());
+    }
+
+    #[test]
+    fn leading_zero_targets() {
+        let bits = bits_for_leading_zeros(2);
+        assert!(hash_meets_bits(&format!("00{}", "0".repeat(62)), bits));
+        assert!(!hash_meets_bits(&format!("01{}", "0".repeat(62)), bits));
+        assert!(!hash_meets_bits("00", bits));
+        assert!(!hash_meets_bits(&"z".repeat(64), bits));
+    }
+}
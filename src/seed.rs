@@ -1,21 +1,25 @@
 use std::collections::HashSet;
 use std::sync::{Arc, RwLock};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
 
+use crate::codec::{self, Envelope};
 use crate::message::Message;
+use crate::network::Network;
+use crate::peer::{HANDSHAKE_TIMEOUT, REQUEST_TIMEOUT};
 
 /// A seed node that maintains a list of known peers
 pub struct SeedNode {
     pub addr: String,
+    pub network: Network,
     pub peers: Arc<RwLock<HashSet<String>>>,
 }
 
 impl SeedNode {
     /// Create a new seed node
-    pub fn new(addr: String) -> Self {
+    pub fn new(addr: String, network: Network) -> Self {
         SeedNode {
             addr,
+            network,
             peers: Arc::new(RwLock::new(HashSet::new())),
         }
     }
@@ -30,8 +34,9 @@ impl SeedNode {
             println!("Connection from {}", addr);
 
             let peers = self.peers.clone();
+            let magic = self.network.magic();
             tokio::spawn(async move {
-                if let Err(e) = handle_seed_connection(stream, peers).await {
+                if let Err(e) = handle_seed_connection(stream, magic, peers).await {
                     eprintln!("Seed connection error: {}", e);
                 }
             });
@@ -47,20 +52,14 @@ impl SeedNode {
 /// Handle incoming connection to seed node
 async fn handle_seed_connection(
     mut stream: TcpStream,
+    magic: [u8; 4],
     peers: Arc<RwLock<HashSet<String>>>,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    // Read message length
-    let mut len_buf = [0u8; 4];
-    stream.read_exact(&mut len_buf).await?;
-    let len = u32::from_be_bytes(len_buf) as usize;
+    let request = tokio::time::timeout(HANDSHAKE_TIMEOUT, codec::read_envelope(&mut stream, magic))
+        .await
+        .map_err(|_| "no message received")??;
 
-    // Read message
-    let mut buf = vec![0u8; len];
-    stream.read_exact(&mut buf).await?;
-
-    let msg: Message = bincode::deserialize(&buf)?;
-
-    match msg {
+    match request.message {
         Message::Register(peer_addr) => {
             println!("Registering peer: {}", peer_addr);
             let mut peer_list = peers.write().unwrap();
@@ -74,11 +73,12 @@ async fn handle_seed_connection(
                 let peers = peers.read().unwrap();
                 peers.iter().cloned().collect()
             };
-            let response = Message::Peers(peer_list);
-            let data = bincode::serialize(&response)?;
-            let len = (data.len() as u32).to_be_bytes();
-            stream.write_all(&len).await?;
-            stream.write_all(&data).await?;
+            let response = Envelope {
+                id: 0,
+                reply_to: Some(request.id),
+                message: Message::Peers(peer_list),
+            };
+            codec::write_envelope(&mut stream, magic, &response).await?;
         }
 
         _ => {
@@ -90,37 +90,30 @@ async fn handle_seed_connection(
 }
 
 /// Client functions to interact with seed node
-pub async fn register_with_seed(seed_addr: &str, our_addr: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+pub async fn register_with_seed(
+    seed_addr: &str,
+    network: Network,
+    our_addr: &str,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let mut stream = TcpStream::connect(seed_addr).await?;
     let msg = Message::Register(our_addr.to_string());
-    let data = bincode::serialize(&msg)?;
-    let len = (data.len() as u32).to_be_bytes();
-
-    stream.write_all(&len).await?;
-    stream.write_all(&data).await?;
+    codec::write_envelope(&mut stream, network.magic(), &Envelope::new(1, msg)).await?;
 
     Ok(())
 }
 
-pub async fn get_peers_from_seed(seed_addr: &str) -> Result<Vec<String>, Box<dyn std::error::Error + Send + Sync>> {
+pub async fn get_peers_from_seed(
+    seed_addr: &str,
+    network: Network,
+) -> Result<Vec<String>, Box<dyn std::error::Error + Send + Sync>> {
     let mut stream = TcpStream::connect(seed_addr).await?;
-    let msg = Message::GetPeers;
-    let data = bincode::serialize(&msg)?;
-    let len = (data.len() as u32).to_be_bytes();
-
-    stream.write_all(&len).await?;
-    stream.write_all(&data).await?;
+    codec::write_envelope(&mut stream, network.magic(), &Envelope::new(1, Message::GetPeers)).await?;
 
     // Read response
-    let mut len_buf = [0u8; 4];
-    stream.read_exact(&mut len_buf).await?;
-    let len = u32::from_be_bytes(len_buf) as usize;
-
-    let mut buf = vec![0u8; len];
-    stream.read_exact(&mut buf).await?;
-
-    let response: Message = bincode::deserialize(&buf)?;
-    match response {
+    let response = tokio::time::timeout(REQUEST_TIMEOUT, codec::read_envelope(&mut stream, network.magic()))
+        .await
+        .map_err(|_| "seed node did not respond")??;
+    match response.message {
         Message::Peers(peers) => Ok(peers),
         _ => Ok(vec![]),
     }
@@ -0,0 +1,893 @@
+//! On-disk block storage: blocks are appended to numbered block files and
+//! located through an embedded key-value index, which also holds the active
+//! chain headers, undo data, a periodic snapshot of the ledger state and metadata.
+//!
+//! Each record in a block file is `[length u32 LE][crc32 u32 LE][bincode block]`.
+//! A block is written and synced to its file before the index entry pointing
+//! at it is committed, together with the new end of the file. On open, anything
+//! past that recorded end is a torn write from a crash and is cut off.
+//!
+//! The ledger state is written every `STATE_SNAPSHOT_INTERVAL` blocks rather
+//! than after each one; on open, the blocks connected since the snapshot are
+//! applied to it again.
+//!
+//! When pruning, the index entries of old bodies are dropped and block files
+//! holding nothing newer than the prune height are deleted; headers are kept.
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+
+use crate::block::{Block, BlockHeader, ZERO_HASH};
+use crate::ledger::{LedgerState, LedgerUndo};
+use crate::store::ChainStore;
+
+/// Size after which a new block file is started
+pub const MAX_BLOCK_FILE_SIZE: u64 = 128 * 1024 * 1024;
+
+/// Number of blocks between ledger state snapshots
+pub const STATE_SNAPSHOT_INTERVAL: u64 = 1000;
+
+/// Index key of the active chain tip hash
+const TIP_KEY: &[u8] = b"tip";
+/// Index key of the current write position
+const POSITION_KEY: &[u8] = b"position";
+/// Index key of the latest ledger state snapshot
+const SNAPSHOT_KEY: &[u8] = b"snapshot";
+/// Index key of the lowest active chain height whose body is kept
+const PRUNED_KEY: &[u8] = b"pruned";
+
+/// Active chain entry kept for every height, even once the body is pruned
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ChainEntry {
+    hash: String,
+    header: BlockHeader,
+}
+
+/// Ledger state after the first `len` active chain blocks
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StateSnapshot {
+    /// Number of active chain blocks applied to the state
+    pub len: u64,
+    /// Hash of the last of those blocks (`ZERO_HASH` if none)
+    pub tip: String,
+    pub state: LedgerState,
+}
+
+/// Where a block record lives in the block files
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockLocation {
+    pub file: u32,
+    pub offset: u64,
+    pub len: u32,
+}
+
+/// End of the data in the block file currently appended to
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+struct WritePosition {
+    file: u32,
+    offset: u64,
+}
+
+/// The block file being appended to
+#[derive(Debug)]
+struct BlockFile {
+    file: File,
+    position: WritePosition,
+}
+
+/// Append-only block files plus an index by hash and by active chain height
+#[derive(Debug)]
+pub struct BlockStore {
+    dir: PathBuf,
+    index: sled::Db,
+    current: Mutex<BlockFile>,
+}
+
+impl BlockStore {
+    /// Open (or create) the store in `dir`, discarding any partially written record
+    pub fn open(dir: &Path) -> Result<Self, String> {
+        fs::create_dir_all(dir).map_err(|e| format!("Cannot create {}: {}", dir.display(), e))?;
+        let index = sled::open(dir.join("index")).map_err(|e| format!("Cannot open block index: {}", e))?;
+
+        let position: WritePosition = match index.get(POSITION_KEY).map_err(|e| e.to_string())? {
+            Some(bytes) => bincode::deserialize(&bytes).map_err(|e| e.to_string())?,
+            None => WritePosition::default(),
+        };
+
+        let path = block_file_path(dir, position.file);
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(&path)
+            .map_err(|e| format!("Cannot open {}: {}", path.display(), e))?;
+        let len = file.metadata().map_err(|e| e.to_string())?.len();
+        if len > position.offset {
+            println!("Discarding {} bytes of incomplete block data", len - position.offset);
+            file.set_len(position.offset).map_err(|e| e.to_string())?;
+        }
+
+        Ok(BlockStore {
+            dir: dir.to_path_buf(),
+            index,
+            current: Mutex::new(BlockFile { file, position }),
+        })
+    }
+
+    /// Hash of the stored active chain tip
+    pub fn tip(&self) -> Result<Option<String>, String> {
+        let tip = self.index.get(TIP_KEY).map_err(|e| e.to_string())?;
+        Ok(tip.map(|hash| String::from_utf8_lossy(&hash).into_owned()))
+    }
+
+    /// Hash of the active chain block at `height`
+    pub fn hash_at(&self, height: u64) -> Result<Option<String>, String> {
+        Ok(self.entry_at(height)?.map(|entry| entry.hash))
+    }
+
+    /// Active chain entry at `height`
+    fn entry_at(&self, height: u64) -> Result<Option<ChainEntry>, String> {
+        match self.index.get(height_key(height)).map_err(|e| e.to_string())? {
+            Some(bytes) => Ok(Some(bincode::deserialize(&bytes).map_err(|e| e.to_string())?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Location of a stored block
+    pub fn location(&self, hash: &str) -> Result<Option<BlockLocation>, String> {
+        match self.index.get(hash_key(hash)).map_err(|e| e.to_string())? {
+            Some(bytes) => Ok(Some(bincode::deserialize(&bytes).map_err(|e| e.to_string())?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Check if a block is stored
+    pub fn contains(&self, hash: &str) -> Result<bool, String> {
+        self.index.contains_key(hash_key(hash)).map_err(|e| e.to_string())
+    }
+
+    /// Read a stored block, checking its checksum
+    pub fn get_block(&self, hash: &str) -> Result<Option<Block>, String> {
+        let location = match self.location(hash)? {
+            Some(location) => location,
+            None => return Ok(None),
+        };
+
+        let path = block_file_path(&self.dir, location.file);
+        let mut file = File::open(&path).map_err(|e| format!("Cannot open {}: {}", path.display(), e))?;
+        file.seek(SeekFrom::Start(location.offset)).map_err(|e| e.to_string())?;
+
+        let mut header = [0u8; 8];
+        file.read_exact(&mut header).map_err(|e| e.to_string())?;
+        let len = u32::from_le_bytes(header[0..4].try_into().unwrap());
+        let checksum = u32::from_le_bytes(header[4..8].try_into().unwrap());
+        if len.checked_add(8) != Some(location.len) {
+            return Err(format!("Block {} has a corrupt record length", hash));
+        }
+
+        let mut data = vec![0u8; len as usize];
+        file.read_exact(&mut data).map_err(|e| e.to_string())?;
+        if crc32fast::hash(&data) != checksum {
+            return Err(format!("Block {} failed its checksum", hash));
+        }
+
+        let block: Block = bincode::deserialize(&data).map_err(|e| e.to_string())?;
+        Ok(Some(block))
+    }
+
+    /// Hashes of every stored block, on the active chain or not
+    pub fn block_hashes(&self) -> Result<Vec<String>, String> {
+        self.index
+            .scan_prefix(b"b:")
+            .map(|item| {
+                let (key, _) = item.map_err(|e| e.to_string())?;
+                Ok(String::from_utf8_lossy(&key[2..]).into_owned())
+            })
+            .collect()
+    }
+
+    /// Store a block without making it part of the active chain (e.g. a side branch)
+    pub fn put_block(&self, block: &Block) -> Result<(), String> {
+        if self.contains(&block.hash)? {
+            return Ok(());
+        }
+        let mut batch = sled::Batch::default();
+        self.append(block, &mut batch)?;
+        self.commit(batch)
+    }
+
+    /// Forget a stored block that is not on the active chain. Its record stays
+    /// in the block file until the file is deleted by pruning.
+    pub fn remove_block(&self, hash: &str) -> Result<(), String> {
+        let mut batch = sled::Batch::default();
+        batch.remove(hash_key(hash));
+        self.commit(batch)
+    }
+
+    /// Store a block as the new tip of the active chain, with its undo data
+    /// and, if given, a snapshot of the ledger state after it
+    pub fn connect_block(&self, block: &Block, undo: &LedgerUndo, snapshot: Option<&LedgerState>) -> Result<(), String> {
+        let mut batch = sled::Batch::default();
+        if !self.contains(&block.hash)? {
+            self.append(block, &mut batch)?;
+        }
+        let entry = ChainEntry {
+            hash: block.hash.clone(),
+            header: block.header.clone(),
+        };
+        batch.insert(
+            height_key(block.index).to_vec(),
+            bincode::serialize(&entry).map_err(|e| e.to_string())?,
+        );
+        batch.insert(undo_key(&block.hash), bincode::serialize(undo).map_err(|e| e.to_string())?);
+        batch.insert(TIP_KEY, block.hash.as_bytes());
+        if let Some(state) = snapshot {
+            insert_snapshot(&mut batch, block.index + 1, &block.hash, state)?;
+        }
+        self.commit(batch)
+    }
+
+    /// Undo data of an active chain block
+    pub fn get_undo(&self, hash: &str) -> Result<Option<LedgerUndo>, String> {
+        match self.index.get(undo_key(hash)).map_err(|e| e.to_string())? {
+            Some(bytes) => Ok(Some(bincode::deserialize(&bytes).map_err(|e| e.to_string())?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Remove the tip of the active chain (the block itself stays stored),
+    /// with, if given, a snapshot of the ledger state after its parent
+    pub fn disconnect_block(&self, block: &Block, snapshot: Option<&LedgerState>) -> Result<(), String> {
+        let mut batch = sled::Batch::default();
+        batch.remove(height_key(block.index).to_vec());
+        batch.remove(undo_key(&block.hash));
+        if block.header.prev_hash == ZERO_HASH {
+            batch.remove(TIP_KEY);
+        } else {
+            batch.insert(TIP_KEY, block.header.prev_hash.as_bytes());
+        }
+        if let Some(state) = snapshot {
+            insert_snapshot(&mut batch, block.index, &block.header.prev_hash, state)?;
+        }
+        self.commit(batch)
+    }
+
+    /// Load the hashes and headers of the stored active chain, genesis first
+    pub fn load_headers(&self) -> Result<Vec<(String, BlockHeader)>, String> {
+        let mut headers = Vec::new();
+        if self.tip()?.is_none() {
+            return Ok(headers);
+        }
+
+        let mut height = 0;
+        while let Some(entry) = self.entry_at(height)? {
+            headers.push((entry.hash, entry.header));
+            height += 1;
+        }
+        if headers.last().map(|(hash, _)| hash) != self.tip()?.as_ref() {
+            return Err(String::from("Stored active chain does not end at the stored tip"));
+        }
+        Ok(headers)
+    }
+
+    /// Latest ledger state snapshot
+    pub fn snapshot(&self) -> Result<Option<StateSnapshot>, String> {
+        match self.index.get(SNAPSHOT_KEY).map_err(|e| e.to_string())? {
+            Some(bytes) => Ok(Some(bincode::deserialize(&bytes).map_err(|e| e.to_string())?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Replace the ledger state snapshot with the state after the first
+    /// `len` active chain blocks, the last of which is `tip`
+    pub fn set_snapshot(&self, len: u64, tip: &str, state: &LedgerState) -> Result<(), String> {
+        let mut batch = sled::Batch::default();
+        insert_snapshot(&mut batch, len, tip, state)?;
+        self.commit(batch)
+    }
+
+    /// Lowest active chain height whose body is kept
+    pub fn pruned_height(&self) -> Result<u64, String> {
+        match self.index.get(PRUNED_KEY).map_err(|e| e.to_string())? {
+            Some(bytes) => {
+                let bytes: [u8; 8] = bytes.as_ref().try_into().map_err(|_| "Corrupt prune height")?;
+                Ok(u64::from_be_bytes(bytes))
+            }
+            None => Ok(0),
+        }
+    }
+
+    /// Drop the bodies and undo data of the active chain blocks from the
+    /// current prune height up to `height`, then delete every block file
+    /// (other than the one being appended to) holding no block at or above it
+    pub fn prune(&self, height: u64) -> Result<(), String> {
+        let pruned = self.pruned_height()?;
+        if height <= pruned {
+            return Ok(());
+        }
+
+        let mut batch = sled::Batch::default();
+        for h in pruned..height {
+            if let Some(entry) = self.entry_at(h)? {
+                batch.remove(hash_key(&entry.hash));
+                batch.remove(undo_key(&entry.hash));
+            }
+        }
+        batch.insert(PRUNED_KEY, &height.to_be_bytes());
+        self.commit(batch)?;
+
+        let current = self.current.lock().unwrap().position.file;
+        let mut deleted = Vec::new();
+        for item in self.index.scan_prefix(b"f:") {
+            let (key, value) = item.map_err(|e| e.to_string())?;
+            let file = u32::from_be_bytes(key[2..].try_into().map_err(|_| "Corrupt block file key")?);
+            let max_height = u64::from_be_bytes(value.as_ref().try_into().map_err(|_| "Corrupt block file height")?);
+            if file < current && max_height < height {
+                deleted.push(file);
+            }
+        }
+        if deleted.is_empty() {
+            return Ok(());
+        }
+
+        // Side branch blocks in a deleted file go with it
+        let mut batch = sled::Batch::default();
+        for item in self.index.scan_prefix(b"b:") {
+            let (key, value) = item.map_err(|e| e.to_string())?;
+            let location: BlockLocation = bincode::deserialize(&value).map_err(|e| e.to_string())?;
+            if deleted.contains(&location.file) {
+                batch.remove(key);
+            }
+        }
+        self.commit(batch)?;
+
+        let mut batch = sled::Batch::default();
+        for file in deleted {
+            let path = block_file_path(&self.dir, file);
+            match fs::remove_file(&path) {
+                Ok(()) => println!("Deleted pruned block file {}", path.display()),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(format!("Cannot delete {}: {}", path.display(), e)),
+            }
+            batch.remove(file_key(file).to_vec());
+        }
+        self.commit(batch)
+    }
+
+    /// Read a metadata value
+    pub fn metadata(&self, key: &str) -> Result<Option<Vec<u8>>, String> {
+        let value = self.index.get(metadata_key(key)).map_err(|e| e.to_string())?;
+        Ok(value.map(|v| v.to_vec()))
+    }
+
+    /// Write a metadata value
+    pub fn set_metadata(&self, key: &str, value: &[u8]) -> Result<(), String> {
+        let mut batch = sled::Batch::default();
+        batch.insert(metadata_key(key), value);
+        self.commit(batch)
+    }
+
+    /// Append a block record to the current block file, syncing it to disk,
+    /// and add its index entries to `batch`
+    fn append(&self, block: &Block, batch: &mut sled::Batch) -> Result<(), String> {
+        let data = bincode::serialize(block).map_err(|e| e.to_string())?;
+        let mut record = Vec::with_capacity(data.len() + 8);
+        record.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        record.extend_from_slice(&crc32fast::hash(&data).to_le_bytes());
+        record.extend_from_slice(&data);
+
+        let mut current = self.current.lock().unwrap();
+        if current.position.offset > 0 && current.position.offset + record.len() as u64 > MAX_BLOCK_FILE_SIZE {
+            let next = current.position.file + 1;
+            let path = block_file_path(&self.dir, next);
+            current.file = OpenOptions::new()
+                .create(true)
+                .read(true)
+                .append(true)
+                .open(&path)
+                .map_err(|e| format!("Cannot open {}: {}", path.display(), e))?;
+            current.file.set_len(0).map_err(|e| e.to_string())?;
+            current.position = WritePosition { file: next, offset: 0 };
+        }
+
+        current.file.write_all(&record).map_err(|e| e.to_string())?;
+        current.file.sync_data().map_err(|e| e.to_string())?;
+
+        let location = BlockLocation {
+            file: current.position.file,
+            offset: current.position.offset,
+            len: record.len() as u32,
+        };
+        current.position.offset += record.len() as u64;
+
+        // Remember the highest block in each file so pruning knows when it can go
+        let key = file_key(location.file);
+        let max_height = match self.index.get(key).map_err(|e| e.to_string())? {
+            Some(bytes) => u64::from_be_bytes(bytes.as_ref().try_into().map_err(|_| "Corrupt block file height")?),
+            None => 0,
+        };
+        batch.insert(key.to_vec(), &max_height.max(block.index).to_be_bytes());
+        batch.insert(
+            hash_key(&block.hash),
+            bincode::serialize(&location).map_err(|e| e.to_string())?,
+        );
+        batch.insert(
+            POSITION_KEY,
+            bincode::serialize(&current.position).map_err(|e| e.to_string())?,
+        );
+        Ok(())
+    }
+
+    /// Atomically apply index changes and flush them to disk
+    fn commit(&self, batch: sled::Batch) -> Result<(), String> {
+        self.index.apply_batch(batch).map_err(|e| e.to_string())?;
+        self.index.flush().map_err(|e| e.to_string())?;
+        Ok(())
+    }
+}
+
+/// `ChainStore` persisting blocks, undo data, ledger state snapshots and
+/// metadata in a `BlockStore`. Headers of the active chain and the ledger
+/// state are also kept in memory.
+#[derive(Debug)]
+pub struct DiskStore {
+    blocks: BlockStore,
+    headers: Vec<BlockHeader>,
+    hashes: Vec<String>,
+    heights: HashMap<String, u64>,
+    pruned_height: u64,
+    state: LedgerState,
+    /// Active chain length the stored snapshot was taken at
+    snapshot_len: u64,
+}
+
+impl DiskStore {
+    /// Open (or create) the store in `dir`, bringing the ledger state
+    /// snapshot up to the stored tip
+    pub fn open(dir: &Path) -> Result<Self, String> {
+        let blocks = BlockStore::open(dir)?;
+        let chain = blocks.load_headers()?;
+        let pruned_height = blocks.pruned_height()?;
+        let snapshot = blocks.snapshot()?.unwrap_or_else(|| StateSnapshot {
+            len: 0,
+            tip: ZERO_HASH.to_string(),
+            state: LedgerState::default(),
+        });
+
+        let mut store = DiskStore {
+            blocks,
+            headers: Vec::with_capacity(chain.len()),
+            hashes: Vec::with_capacity(chain.len()),
+            heights: HashMap::new(),
+            pruned_height,
+            state: snapshot.state,
+            snapshot_len: snapshot.len,
+        };
+        for (height, (hash, header)) in chain.into_iter().enumerate() {
+            store.heights.insert(hash.clone(), height as u64);
+            store.hashes.push(hash);
+            store.headers.push(header);
+        }
+
+        let tip = match snapshot.len {
+            0 => Some(ZERO_HASH.to_string()),
+            len => store.hash_at(len - 1),
+        };
+        if tip.as_ref() != Some(&snapshot.tip) {
+            return Err(String::from("Stored ledger snapshot is not on the active chain"));
+        }
+        if snapshot.len < pruned_height {
+            return Err(String::from("Stored ledger snapshot is older than the pruned blocks"));
+        }
+        for height in snapshot.len..store.len() {
+            let block = store.blocks.get_block(&store.hashes[height as usize])?;
+            let block = block.ok_or_else(|| format!("Block #{} is missing from the store", height))?;
+            store.state.apply_block(&block);
+        }
+        Ok(store)
+    }
+
+    /// Snapshot the ledger state at the current tip
+    fn write_snapshot(&mut self) -> Result<(), String> {
+        let tip = self.hashes.last().map_or(ZERO_HASH, |hash| hash.as_str());
+        self.blocks.set_snapshot(self.len(), tip, &self.state)?;
+        self.snapshot_len = self.len();
+        Ok(())
+    }
+}
+
+impl ChainStore for DiskStore {
+    fn len(&self) -> u64 {
+        self.headers.len() as u64
+    }
+
+    fn header(&self, height: u64) -> Option<BlockHeader> {
+        self.headers.get(height as usize).cloned()
+    }
+
+    fn hash_at(&self, height: u64) -> Option<String> {
+        self.hashes.get(height as usize).cloned()
+    }
+
+    fn height_of(&self, hash: &str) -> Option<u64> {
+        self.heights.get(hash).copied()
+    }
+
+    fn block(&self, height: u64) -> Option<Block> {
+        let hash = self.hashes.get(height as usize)?;
+        match self.blocks.get_block(hash) {
+            Ok(block) => block,
+            Err(e) => {
+                eprintln!("Failed to read block #{}: {}", height, e);
+                None
+            }
+        }
+    }
+
+    fn push_block(&mut self, block: &Block) -> Result<(), String> {
+        let undo = self.state.apply_block(block);
+        let snapshot = (block.index + 1).is_multiple_of(STATE_SNAPSHOT_INTERVAL).then_some(&self.state);
+        if let Err(e) = self.blocks.connect_block(block, &undo, snapshot) {
+            self.state.undo_block(block, undo);
+            return Err(e);
+        }
+        if snapshot.is_some() {
+            self.snapshot_len = block.index + 1;
+        }
+        self.heights.insert(block.hash.clone(), block.index);
+        self.hashes.push(block.hash.clone());
+        self.headers.push(block.header.clone());
+        Ok(())
+    }
+
+    fn pop_block(&mut self) -> Result<Option<Block>, String> {
+        let hash = match self.hashes.last() {
+            Some(hash) => hash.clone(),
+            None => return Ok(None),
+        };
+        if self.len() <= self.pruned_height {
+            return Err(String::from("Cannot disconnect a pruned block"));
+        }
+        let block = self
+            .blocks
+            .get_block(&hash)?
+            .ok_or_else(|| format!("Block {} is missing from the store", hash))?;
+        let undo = self
+            .blocks
+            .get_undo(&hash)?
+            .ok_or_else(|| format!("Undo data of block {} is missing", hash))?;
+
+        let mut state = self.state.clone();
+        state.undo_block(&block, undo);
+        // A snapshot taken after this block no longer matches the chain
+        let stale = self.snapshot_len > block.index;
+        self.blocks.disconnect_block(&block, stale.then_some(&state))?;
+        if stale {
+            self.snapshot_len = block.index;
+        }
+        self.state = state;
+        self.heights.remove(&hash);
+        self.hashes.pop();
+        self.headers.pop();
+        Ok(Some(block))
+    }
+
+    fn put_side_block(&mut self, block: &Block) -> Result<(), String> {
+        self.blocks.put_block(block)
+    }
+
+    fn side_blocks(&self) -> Vec<Block> {
+        let hashes = match self.blocks.block_hashes() {
+            Ok(hashes) => hashes,
+            Err(e) => {
+                eprintln!("Failed to list stored blocks: {}", e);
+                return Vec::new();
+            }
+        };
+        hashes
+            .iter()
+            .filter(|hash| !self.heights.contains_key(*hash))
+            .filter_map(|hash| match self.blocks.get_block(hash) {
+                Ok(block) => block,
+                Err(e) => {
+                    eprintln!("Failed to read block {}: {}", hash, e);
+                    None
+                }
+            })
+            .collect()
+    }
+
+    fn remove_side_block(&mut self, hash: &str) -> Result<(), String> {
+        if self.heights.contains_key(hash) {
+            return Err(format!("Block {} is on the active chain", hash));
+        }
+        self.blocks.remove_block(hash)
+    }
+
+    fn state(&self) -> &LedgerState {
+        &self.state
+    }
+
+    fn set_state(&mut self, state: LedgerState) -> Result<(), String> {
+        self.state = state;
+        self.write_snapshot()
+    }
+
+    fn pruned_height(&self) -> u64 {
+        self.pruned_height
+    }
+
+    fn prune(&mut self, height: u64) -> Result<(), String> {
+        let height = height.min(self.len());
+        if height <= self.pruned_height {
+            return Ok(());
+        }
+        // The blocks since the snapshot can no longer be replayed once pruned
+        if self.snapshot_len < height {
+            self.write_snapshot()?;
+        }
+        self.blocks.prune(height)?;
+        self.pruned_height = height;
+        Ok(())
+    }
+
+    fn metadata(&self, key: &str) -> Option<Vec<u8>> {
+        match self.blocks.metadata(key) {
+            Ok(value) => value,
+            Err(e) => {
+                eprintln!("Failed to read metadata {}: {}", key, e);
+                None
+            }
+        }
+    }
+
+    fn set_metadata(&mut self, key: &str, value: &[u8]) -> Result<(), String> {
+        self.blocks.set_metadata(key, value)
+    }
+}
+
+/// Add a ledger state snapshot to `batch` (bincode encodes the tuple like a
+/// `StateSnapshot`, without copying the state into one)
+fn insert_snapshot(batch: &mut sled::Batch, len: u64, tip: &str, state: &LedgerState) -> Result<(), String> {
+    let snapshot = bincode::serialize(&(len, tip, state)).map_err(|e| e.to_string())?;
+    batch.insert(SNAPSHOT_KEY, snapshot);
+    Ok(())
+}
+
+/// Path of the block file with the given number
+fn block_file_path(dir: &Path, file: u32) -> PathBuf {
+    dir.join(format!("blk{:05}.dat", file))
+}
+
+/// Index key of a block location
+fn hash_key(hash: &str) -> Vec<u8> {
+    let mut key = b"b:".to_vec();
+    key.extend_from_slice(hash.as_bytes());
+    key
+}
+
+/// Index key of a block's undo data
+fn undo_key(hash: &str) -> Vec<u8> {
+    let mut key = b"u:".to_vec();
+    key.extend_from_slice(hash.as_bytes());
+    key
+}
+
+/// Index key of a metadata value
+fn metadata_key(key: &str) -> Vec<u8> {
+    let mut index_key = b"m:".to_vec();
+    index_key.extend_from_slice(key.as_bytes());
+    index_key
+}
+
+/// Index key of the highest block stored in a block file
+fn file_key(file: u32) -> [u8; 6] {
+    let mut key = [0u8; 6];
+    key[0..2].copy_from_slice(b"f:");
+    key[2..].copy_from_slice(&file.to_be_bytes());
+    key
+}
+
+/// Index key of the active chain block at a height (big-endian so keys sort by height)
+fn height_key(height: u64) -> [u8; 10] {
+    let mut key = [0u8; 10];
+    key[0..2].copy_from_slice(b"h:");
+    key[2..].copy_from_slice(&height.to_be_bytes());
+    key
+}
+
+/// Fresh directory for a test store, unique within the test run
+#[cfg(test)]
+pub(crate) fn test_dir(name: &str) -> PathBuf {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    let n = NEXT.fetch_add(1, Ordering::Relaxed);
+    let dir = std::env::temp_dir().join(format!("simple-pow-chain-{}-{}-{}", name, std::process::id(), n));
+    let _ = fs::remove_dir_all(&dir);
+    dir
+}
+
+/// Chain parameters for tests: difficulty and proof-of-work limit of one
+/// leading zero digit, so blocks mine instantly
+#[cfg(test)]
+pub(crate) fn test_params() -> crate::params::ChainParams {
+    let bits = crate::pow::bits_for_leading_zeros(1);
+    crate::params::ChainParams {
+        genesis_bits: bits,
+        pow_limit_bits: bits,
+        ..Default::default()
+    }
+}
+
+/// Open a store again in a test. sled releases the lock of a dropped index
+/// from a background thread, so opening right away can find it still held.
+#[cfg(test)]
+pub(crate) fn reopen<T>(open: impl Fn() -> Result<T, String>) -> T {
+    for _ in 0..100 {
+        match open() {
+            Err(e) if e.contains("could not acquire lock") => std::thread::sleep(std::time::Duration::from_millis(50)),
+            result => return result.unwrap(),
+        }
+    }
+    open().unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::address::Wallet;
+    use crate::blockchain::Blockchain;
+    use crate::transaction::Transaction;
+
+    /// Unmined block at `index` on top of `parent`, paying 50 to `miner`
+    fn block_on(parent: &str, index: u64, miner: &str) -> Block {
+        let coinbase = Transaction::coinbase(miner.to_string(), 50, index);
+        Block::new(index, parent.to_string(), vec![coinbase], 0x207fffff)
+    }
+
+    /// Push `count` blocks paying `miner` on top of the store's tip
+    fn push_blocks(store: &mut DiskStore, count: u64, miner: &str) {
+        for _ in 0..count {
+            let parent = store.hash_at(store.len().wrapping_sub(1)).unwrap_or_else(|| ZERO_HASH.to_string());
+            store.push_block(&block_on(&parent, store.len(), miner)).unwrap();
+        }
+    }
+
+    #[test]
+    fn torn_write_is_cut_off_on_open() {
+        let dir = test_dir("torn");
+        let first = block_on(ZERO_HASH, 0, &Wallet::generate().address);
+        let len = {
+            let store = BlockStore::open(&dir).unwrap();
+            store.put_block(&first).unwrap();
+            fs::metadata(block_file_path(&dir, 0)).unwrap().len()
+        };
+
+        // A record whose index entry was never committed
+        let mut file = OpenOptions::new().append(true).open(block_file_path(&dir, 0)).unwrap();
+        file.write_all(&[7u8; 100]).unwrap();
+        drop(file);
+
+        let store = reopen(|| BlockStore::open(&dir));
+        assert_eq!(fs::metadata(block_file_path(&dir, 0)).unwrap().len(), len);
+        assert_eq!(store.get_block(&first.hash).unwrap().unwrap().hash, first.hash);
+        let second = block_on(&first.hash, 1, &Wallet::generate().address);
+        store.put_block(&second).unwrap();
+        assert_eq!(store.get_block(&second.hash).unwrap().unwrap().hash, second.hash);
+
+        drop(store);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn corrupt_record_lengths_are_reported() {
+        let dir = test_dir("corrupt-length");
+        let block = block_on(ZERO_HASH, 0, &Wallet::generate().address);
+        let store = BlockStore::open(&dir).unwrap();
+        store.put_block(&block).unwrap();
+
+        // The length field of the only record, at the start of the file
+        let mut file = OpenOptions::new().write(true).open(block_file_path(&dir, 0)).unwrap();
+        file.write_all(&u32::MAX.to_le_bytes()).unwrap();
+        drop(file);
+        let error = store.get_block(&block.hash).unwrap_err();
+        assert!(error.contains("corrupt record length"), "{}", error);
+
+        drop(store);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn state_is_replayed_from_the_last_snapshot() {
+        let dir = test_dir("snapshot");
+        let miner = Wallet::generate().address;
+        {
+            let mut store = DiskStore::open(&dir).unwrap();
+            push_blocks(&mut store, 4, &miner);
+            store.set_state(store.state().clone()).unwrap();
+            push_blocks(&mut store, 3, &miner);
+            assert_eq!(store.snapshot_len, 4);
+        }
+
+        let mut store = reopen(|| DiskStore::open(&dir));
+        assert_eq!(store.len(), 7);
+        assert_eq!(store.state().balance(&miner), 7 * 50);
+
+        // Disconnecting below the snapshot moves it back
+        store.pop_block().unwrap();
+        store.pop_block().unwrap();
+        store.pop_block().unwrap();
+        store.pop_block().unwrap();
+        assert_eq!(store.snapshot_len, 3);
+        drop(store);
+
+        let store = reopen(|| DiskStore::open(&dir));
+        assert_eq!(store.len(), 3);
+        assert_eq!(store.state().balance(&miner), 3 * 50);
+
+        drop(store);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn pruning_snapshots_the_state_it_can_no_longer_replay() {
+        let dir = test_dir("prune-snapshot");
+        let miner = Wallet::generate().address;
+        {
+            let mut store = DiskStore::open(&dir).unwrap();
+            push_blocks(&mut store, 5, &miner);
+            store.prune(3).unwrap();
+            assert_eq!(store.snapshot_len, 5);
+            push_blocks(&mut store, 2, &miner);
+        }
+
+        let store = reopen(|| DiskStore::open(&dir));
+        assert_eq!(store.pruned_height(), 3);
+        assert_eq!(store.state().balance(&miner), 7 * 50);
+
+        drop(store);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn invalid_stored_blocks_are_dropped_on_reopen() {
+        let dir = test_dir("revalidate");
+        let params = test_params();
+        let miner = Wallet::generate().address;
+        {
+            let mut blockchain = Blockchain::open(params.clone(), &dir).unwrap();
+            blockchain.create_genesis();
+            for _ in 0..2 {
+                let block = blockchain.add_block(blockchain.block_template(&[], &miner));
+                assert!(blockchain.contains_block(&block.hash));
+            }
+        }
+
+        // Written behind the chain's back: claims too much and has no proof of work
+        {
+            let mut store = reopen(|| DiskStore::open(&dir));
+            let tip = store.hash_at(2).unwrap();
+            let greedy = Block::new(3, tip, vec![Transaction::coinbase(miner.clone(), 1000, 3)], 0);
+            store.push_block(&greedy).unwrap();
+            assert_eq!(store.len(), 4);
+        }
+
+        let blockchain = reopen(|| Blockchain::open(params.clone(), &dir));
+        assert_eq!(blockchain.len(), 3);
+        assert_eq!(blockchain.balance(&miner), 2 * params.subsidy(1));
+        drop(blockchain);
+
+        // The state written back after revalidating is the one reopened
+        let store = reopen(|| DiskStore::open(&dir));
+        assert_eq!(store.len(), 3);
+        assert_eq!(store.state().balance(&miner), 2 * params.subsidy(1));
+
+        drop(store);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
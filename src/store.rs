@@ -0,0 +1,215 @@
+use std::collections::HashMap;
+
+use crate::block::{Block, BlockHeader};
+use crate::ledger::{LedgerState, LedgerUndo};
+
+/// Storage behind a `Blockchain`: the blocks and headers of the active chain,
+/// the undo data to disconnect them, the ledger state after the tip and
+/// arbitrary metadata. Side branches are kept by the `Blockchain` itself and
+/// only handed to the store for safekeeping.
+///
+/// Headers are kept for the whole chain; bodies and undo data below
+/// `pruned_height()` may have been discarded.
+pub trait ChainStore: Send + Sync {
+    /// Number of blocks in the active chain
+    fn len(&self) -> u64;
+
+    /// Check if the active chain is empty
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Header of the active chain block at `height`
+    fn header(&self, height: u64) -> Option<BlockHeader>;
+
+    /// Hash of the active chain block at `height`
+    fn hash_at(&self, height: u64) -> Option<String>;
+
+    /// Height of an active chain block
+    fn height_of(&self, hash: &str) -> Option<u64>;
+
+    /// Active chain block at `height`, unless its body was pruned
+    fn block(&self, height: u64) -> Option<Block>;
+
+    /// Append a block to the active chain, applying it to the ledger state
+    fn push_block(&mut self, block: &Block) -> Result<(), String>;
+
+    /// Remove the active chain tip, reverting it from the ledger state
+    fn pop_block(&mut self) -> Result<Option<Block>, String>;
+
+    /// Keep a block that is not part of the active chain
+    fn put_side_block(&mut self, block: &Block) -> Result<(), String>;
+
+    /// Kept blocks that are not part of the active chain, in no particular order
+    fn side_blocks(&self) -> Vec<Block>;
+
+    /// Stop keeping a block that is not part of the active chain
+    fn remove_side_block(&mut self, hash: &str) -> Result<(), String>;
+
+    /// Ledger state after the active chain tip
+    fn state(&self) -> &LedgerState;
+
+    /// Replace the ledger state (e.g. after replaying the chain)
+    fn set_state(&mut self, state: LedgerState) -> Result<(), String>;
+
+    /// Lowest height whose body is still available (0 if nothing was pruned)
+    fn pruned_height(&self) -> u64;
+
+    /// Discard the bodies and undo data of active chain blocks below `height`
+    fn prune(&mut self, height: u64) -> Result<(), String>;
+
+    /// Read a metadata value
+    fn metadata(&self, key: &str) -> Option<Vec<u8>>;
+
+    /// Write a metadata value
+    fn set_metadata(&mut self, key: &str, value: &[u8]) -> Result<(), String>;
+}
+
+/// Store keeping everything in memory; nothing survives a restart
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    headers: Vec<BlockHeader>,
+    hashes: Vec<String>,
+    /// Bodies with their undo data, `None` once pruned
+    blocks: Vec<Option<(Block, LedgerUndo)>>,
+    heights: HashMap<String, u64>,
+    pruned_height: u64,
+    state: LedgerState,
+    metadata: HashMap<String, Vec<u8>>,
+}
+
+impl MemoryStore {
+    /// Create an empty store
+    pub fn new() -> Self {
+        MemoryStore::default()
+    }
+}
+
+impl ChainStore for MemoryStore {
+    fn len(&self) -> u64 {
+        self.headers.len() as u64
+    }
+
+    fn header(&self, height: u64) -> Option<BlockHeader> {
+        self.headers.get(height as usize).cloned()
+    }
+
+    fn hash_at(&self, height: u64) -> Option<String> {
+        self.hashes.get(height as usize).cloned()
+    }
+
+    fn height_of(&self, hash: &str) -> Option<u64> {
+        self.heights.get(hash).copied()
+    }
+
+    fn block(&self, height: u64) -> Option<Block> {
+        match self.blocks.get(height as usize) {
+            Some(Some((block, _))) => Some(block.clone()),
+            _ => None,
+        }
+    }
+
+    fn push_block(&mut self, block: &Block) -> Result<(), String> {
+        let undo = self.state.apply_block(block);
+        self.heights.insert(block.hash.clone(), block.index);
+        self.hashes.push(block.hash.clone());
+        self.headers.push(block.header.clone());
+        self.blocks.push(Some((block.clone(), undo)));
+        Ok(())
+    }
+
+    fn pop_block(&mut self) -> Result<Option<Block>, String> {
+        let (block, undo) = match self.blocks.last() {
+            None => return Ok(None),
+            Some(None) => return Err(String::from("Cannot disconnect a pruned block")),
+            Some(Some(_)) => self.blocks.pop().unwrap().unwrap(),
+        };
+        self.state.undo_block(&block, undo);
+        self.heights.remove(&block.hash);
+        self.hashes.pop();
+        self.headers.pop();
+        Ok(Some(block))
+    }
+
+    fn put_side_block(&mut self, _block: &Block) -> Result<(), String> {
+        // Side blocks already live in the blockchain's memory
+        Ok(())
+    }
+
+    fn side_blocks(&self) -> Vec<Block> {
+        Vec::new()
+    }
+
+    fn remove_side_block(&mut self, _hash: &str) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn state(&self) -> &LedgerState {
+        &self.state
+    }
+
+    fn set_state(&mut self, state: LedgerState) -> Result<(), String> {
+        self.state = state;
+        Ok(())
+    }
+
+    fn pruned_height(&self) -> u64 {
+        self.pruned_height
+    }
+
+    fn prune(&mut self, height: u64) -> Result<(), String> {
+        let height = height.min(self.len());
+        if height <= self.pruned_height {
+            return Ok(());
+        }
+        for body in &mut self.blocks[self.pruned_height as usize..height as usize] {
+            *body = None;
+        }
+        self.pruned_height = height;
+        Ok(())
+    }
+
+    fn metadata(&self, key: &str) -> Option<Vec<u8>> {
+        self.metadata.get(key).cloned()
+    }
+
+    fn set_metadata(&mut self, key: &str, value: &[u8]) -> Result<(), String> {
+        self.metadata.insert(key.to_string(), value.to_vec());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::ZERO_HASH;
+
+    /// Store holding `len` unmined blocks
+    fn store_with(len: u64) -> MemoryStore {
+        let mut store = MemoryStore::new();
+        for index in 0..len {
+            let parent = store.hash_at(index.wrapping_sub(1)).unwrap_or_else(|| ZERO_HASH.to_string());
+            store.push_block(&Block::new(index, parent, vec![], 0x207fffff)).unwrap();
+        }
+        store
+    }
+
+    #[test]
+    fn pruning_drops_bodies_but_keeps_headers() {
+        let mut store = store_with(5);
+        store.prune(3).unwrap();
+        assert_eq!(store.pruned_height(), 3);
+        assert!(store.block(2).is_none());
+        assert!(store.header(2).is_some());
+        assert!(store.block(3).is_some());
+
+        // Lower heights are already pruned, higher ones stop at the tip
+        store.prune(1).unwrap();
+        assert_eq!(store.pruned_height(), 3);
+        store.prune(100).unwrap();
+        assert_eq!(store.pruned_height(), 5);
+        assert!(store.block(4).is_none());
+        assert!(store.pop_block().is_err());
+        assert_eq!(store.len(), 5);
+    }
+}
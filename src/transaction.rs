@@ -2,47 +2,141 @@ use secp256k1::{Message, PublicKey, Secp256k1, SecretKey};
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 
-/// A transaction transferring coins between addresses
+use crate::address;
+
+/// Reference to an output of a previous transaction
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub struct OutPoint {
+    pub txid: String,
+    pub index: u32,
+}
+
+impl OutPoint {
+    /// Create a reference to output `index` of transaction `txid`
+    pub fn new(txid: String, index: u32) -> Self {
+        OutPoint { txid, index }
+    }
+
+    /// Placeholder input of a coinbase transaction, carrying the full block
+    /// height as its txid so that rewards to the same address in different
+    /// blocks hash differently. Its index names no output.
+    pub fn coinbase(height: u64) -> Self {
+        OutPoint {
+            txid: format!("{:064x}", height),
+            index: u32::MAX,
+        }
+    }
+}
+
+impl std::fmt::Display for OutPoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", &self.txid[..16.min(self.txid.len())], self.index)
+    }
+}
+
+/// An amount of coins locked to an address
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct TxOutput {
+    pub address: String,
+    pub amount: u64,
+}
+
+impl TxOutput {
+    pub fn new(address: String, amount: u64) -> Self {
+        TxOutput { address, amount }
+    }
+}
+
+/// A transaction moving coins owned by `from` to new outputs.
+/// In UTXO mode the coins come from `inputs` and the fee is whatever the
+/// outputs leave unclaimed; in account mode they are debited from the
+/// sender's balance together with the explicit `fee`, and `nonce` orders
+/// its transfers.
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct Transaction {
     pub from: String,
-    pub to: String,
-    pub amount: u64,
+    pub inputs: Vec<OutPoint>,
+    pub outputs: Vec<TxOutput>,
+    pub nonce: u64,
+    pub fee: u64,
     pub signature: Option<String>,
     pub public_key: Option<String>,
 }
 
 impl Transaction {
-    /// Create a new unsigned transaction
-    pub fn new(from: String, to: String, amount: u64) -> Self {
+    /// Create a new unsigned transaction spending UTXOs
+    pub fn new(from: String, inputs: Vec<OutPoint>, outputs: Vec<TxOutput>) -> Self {
+        Transaction {
+            from,
+            inputs,
+            outputs,
+            nonce: 0,
+            fee: 0,
+            signature: None,
+            public_key: None,
+        }
+    }
+
+    /// Create a new unsigned account transfer with the sender's next nonce
+    pub fn transfer(from: String, nonce: u64, fee: u64, outputs: Vec<TxOutput>) -> Self {
         Transaction {
             from,
-            to,
-            amount,
+            inputs: vec![],
+            outputs,
+            nonce,
+            fee,
             signature: None,
             public_key: None,
         }
     }
 
-    /// Create a coinbase (mining reward) transaction
-    pub fn coinbase(to: String, amount: u64) -> Self {
+    /// Create a coinbase (mining reward) transaction for the block at `height`
+    pub fn coinbase(to: String, amount: u64, height: u64) -> Self {
         Transaction {
             from: String::from("coinbase"),
-            to,
-            amount,
+            inputs: vec![OutPoint::coinbase(height)],
+            outputs: vec![TxOutput::new(to, amount)],
+            nonce: 0,
+            fee: 0,
             signature: None,
             public_key: None,
         }
     }
 
-    /// Calculate hash of the transaction
+    /// Check if this is a coinbase (mining reward) transaction
+    pub fn is_coinbase(&self) -> bool {
+        self.from == "coinbase"
+    }
+
+    /// Total amount of coins created by the outputs, `None` on overflow
+    pub fn output_total(&self) -> Option<u64> {
+        self.outputs
+            .iter()
+            .try_fold(0u64, |total, output| total.checked_add(output.amount))
+    }
+
+    /// Serialized size of the transaction in bytes
+    pub fn size(&self) -> usize {
+        bincode::serialized_size(self).expect("transaction serialization cannot fail") as usize
+    }
+
+    /// Calculate hash of the transaction (signature excluded)
     pub fn hash(&self) -> String {
-        let data = format!("{}{}{}", self.from, self.to, self.amount);
+        let data = bincode::serialize(&(&self.from, &self.inputs, &self.outputs, self.nonce, self.fee))
+            .expect("transaction serialization cannot fail");
         let mut hasher = Sha256::new();
-        hasher.update(data.as_bytes());
+        hasher.update(&data);
         hex::encode(hasher.finalize())
     }
 
+    /// Hash of the whole transaction, signature and public key included.
+    /// This is what a block's Merkle root commits to, so a block's proof of
+    /// work also covers the signatures of its transactions.
+    pub fn leaf_hash(&self) -> String {
+        let data = bincode::serialize(self).expect("transaction serialization cannot fail");
+        hex::encode(Sha256::digest(&data))
+    }
+
     /// Sign the transaction with a private key
     pub fn sign(&mut self, secret_key_hex: &str) -> Result<(), String> {
         let secp = Secp256k1::new();
@@ -51,6 +145,10 @@ impl Transaction {
         let secret_key = SecretKey::from_slice(&secret_bytes).map_err(|e| e.to_string())?;
         let public_key = PublicKey::from_secret_key(&secp, &secret_key);
 
+        if address::from_public_key(&public_key) != self.from {
+            return Err(format!("Key does not belong to sender address {}", self.from));
+        }
+
         let hash_bytes = hex::decode(self.hash()).map_err(|e| e.to_string())?;
         let message = Message::from_digest_slice(&hash_bytes).map_err(|e| e.to_string())?;
 
@@ -62,10 +160,15 @@ impl Transaction {
         Ok(())
     }
 
-    /// Verify the transaction signature
+    /// Verify the transaction signature and that the signing key owns `from`
     pub fn verify(&self) -> bool {
-        // Coinbase transactions don't need verification
-        if self.from == "coinbase" {
+        // Coins can only be sent to well-formed addresses
+        if !self.outputs.iter().all(|o| address::is_valid(&o.address)) {
+            return false;
+        }
+
+        // Coinbase transactions don't need a signature
+        if self.is_coinbase() {
             return true;
         }
 
@@ -96,6 +199,11 @@ impl Transaction {
             Err(_) => return false,
         };
 
+        // The sender address must be derived from the signing key
+        if address::from_public_key(&public_key) != self.from {
+            return false;
+        }
+
         let hash_bytes = match hex::decode(self.hash()) {
             Ok(b) => b,
             Err(_) => return false,
@@ -113,6 +221,74 @@ impl Transaction {
 impl std::fmt::Display for Transaction {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let signed = if self.signature.is_some() { " (signed)" } else { "" };
-        write!(f, "{} -> {}: {}{}", self.from, self.to, self.amount, signed)
+        let outputs: Vec<String> = self
+            .outputs
+            .iter()
+            .map(|o| format!("{}: {}", o.address, o.amount))
+            .collect();
+        write!(f, "{} -> [{}]{}", self.from, outputs.join(", "), signed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::address::Wallet;
+
+    fn transfer(from: &Wallet) -> Transaction {
+        let outputs = vec![TxOutput::new(Wallet::generate().address, 10)];
+        Transaction::transfer(from.address.clone(), 0, 1, outputs)
+    }
+
+    #[test]
+    fn coinbase_inputs_keep_the_full_height() {
+        let low = Transaction::coinbase(Wallet::generate().address, 50, 1);
+        let high = Transaction {
+            inputs: vec![OutPoint::coinbase(1 + (1 << 32))],
+            ..low.clone()
+        };
+        assert_ne!(low.inputs, high.inputs);
+        assert_ne!(low.hash(), high.hash());
+        assert_eq!(OutPoint::coinbase(u64::MAX).txid, "0".repeat(48) + &"f".repeat(16));
+    }
+
+    #[test]
+    fn signed_transactions_verify() {
+        let wallet = Wallet::generate();
+        let mut tx = transfer(&wallet);
+        assert!(!tx.verify());
+        tx.sign(&wallet.secret_key).unwrap();
+        assert!(tx.verify());
+
+        // Any change to the signed fields breaks the signature
+        tx.fee += 1;
+        assert!(!tx.verify());
+    }
+
+    #[test]
+    fn key_must_own_the_sender_address() {
+        let (owner, other) = (Wallet::generate(), Wallet::generate());
+        let mut tx = transfer(&owner);
+        assert!(tx.sign(&other.secret_key).is_err());
+
+        // Signed by another key that then claims the owner's address
+        let mut forged = transfer(&other);
+        forged.sign(&other.secret_key).unwrap();
+        forged.from = owner.address.clone();
+        assert!(!forged.verify());
+
+        // A valid signature over the owner's transaction, but the wrong key attached
+        tx.sign(&owner.secret_key).unwrap();
+        tx.public_key = Some(other.public_key.clone());
+        assert!(!tx.verify());
+    }
+
+    #[test]
+    fn outputs_must_pay_valid_addresses() {
+        let wallet = Wallet::generate();
+        let mut tx = transfer(&wallet);
+        tx.outputs[0].address = String::from("not-an-address");
+        tx.sign(&wallet.secret_key).unwrap();
+        assert!(!tx.verify());
     }
 }
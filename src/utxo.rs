@@ -0,0 +1,306 @@
+use std::collections::{HashMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+
+use crate::block::Block;
+use crate::transaction::{OutPoint, Transaction, TxOutput};
+
+/// Outputs spent by each transaction of a block, in block order
+pub type UtxoUndo = Vec<Vec<(OutPoint, TxOutput)>>;
+
+/// Outputs spent and created by transactions that are not in a block yet
+/// (e.g. the mempool), on top of a `UtxoSet`
+#[derive(Debug, Clone, Default)]
+pub struct UtxoPending {
+    spent: HashSet<OutPoint>,
+    created: HashMap<OutPoint, TxOutput>,
+}
+
+/// The set of unspent transaction outputs
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct UtxoSet {
+    utxos: HashMap<OutPoint, TxOutput>,
+}
+
+impl UtxoSet {
+    /// Create an empty UTXO set
+    pub fn new() -> Self {
+        UtxoSet::default()
+    }
+
+    /// Look up an unspent output
+    pub fn get(&self, outpoint: &OutPoint) -> Option<&TxOutput> {
+        self.utxos.get(outpoint)
+    }
+
+    /// Number of unspent outputs
+    pub fn len(&self) -> usize {
+        self.utxos.len()
+    }
+
+    /// Check if there are no unspent outputs
+    pub fn is_empty(&self) -> bool {
+        self.utxos.is_empty()
+    }
+
+    /// All unspent outputs locked to an address
+    pub fn unspent_for(&self, address: &str) -> Vec<(OutPoint, TxOutput)> {
+        self.utxos
+            .iter()
+            .filter(|(_, output)| output.address == address)
+            .map(|(outpoint, output)| (outpoint.clone(), output.clone()))
+            .collect()
+    }
+
+    /// Sum of all unspent outputs locked to an address
+    pub fn balance(&self, address: &str) -> u64 {
+        self.utxos
+            .values()
+            .filter(|output| output.address == address)
+            .map(|output| output.amount)
+            .sum()
+    }
+
+    /// Check that a list of transactions can be applied in order on top of this set.
+    /// Outputs created earlier in the list may be spent by later transactions.
+    /// Returns the total fees (inputs minus outputs) of the non-coinbase transactions.
+    pub fn check_transactions(&self, transactions: &[Transaction]) -> Result<u64, String> {
+        let mut pending = UtxoPending::default();
+        transactions.iter().try_fold(0u64, |fees, tx| {
+            fees.checked_add(self.add_pending(&mut pending, tx)?)
+                .ok_or_else(|| String::from("Total fees overflow"))
+        })
+    }
+
+    /// Check that a transaction can be applied on top of this set and the
+    /// `pending` transactions before it, and add it to `pending`. Returns its
+    /// fee (zero for a coinbase). `pending` is left unchanged on error.
+    pub fn add_pending(&self, pending: &mut UtxoPending, tx: &Transaction) -> Result<u64, String> {
+        let output_total = tx
+            .output_total()
+            .ok_or_else(|| format!("Transaction {} output total overflows", tx.hash()))?;
+
+        if tx.fee != 0 {
+            return Err(format!("Transaction {} has an explicit fee in UTXO mode", tx.hash()));
+        }
+
+        let mut fee = 0;
+        if !tx.is_coinbase() {
+            if tx.inputs.is_empty() {
+                return Err(format!("Transaction {} has no inputs", tx.hash()));
+            }
+
+            let mut input_total: u64 = 0;
+            let mut spent: HashSet<&OutPoint> = HashSet::new();
+            for input in &tx.inputs {
+                if !spent.insert(input) || pending.spent.contains(input) {
+                    return Err(format!("Output {} is spent twice", input));
+                }
+
+                let output = match pending.created.get(input) {
+                    Some(output) => output,
+                    None => self
+                        .utxos
+                        .get(input)
+                        .ok_or_else(|| format!("Output {} is unknown or already spent", input))?,
+                };
+
+                if output.address != tx.from {
+                    return Err(format!("Output {} is not owned by {}", input, tx.from));
+                }
+
+                input_total = input_total
+                    .checked_add(output.amount)
+                    .ok_or_else(|| format!("Transaction {} input total overflows", tx.hash()))?;
+            }
+
+            if output_total > input_total {
+                return Err(format!(
+                    "Transaction {} spends {} but only has {}",
+                    tx.hash(),
+                    output_total,
+                    input_total
+                ));
+            }
+            fee = input_total - output_total;
+        }
+
+        if !tx.is_coinbase() {
+            pending.spent.extend(tx.inputs.iter().cloned());
+        }
+        let txid = tx.hash();
+        for (index, output) in tx.outputs.iter().enumerate() {
+            pending.created.insert(OutPoint::new(txid.clone(), index as u32), output.clone());
+        }
+        Ok(fee)
+    }
+
+    /// Apply a block's transactions: remove spent outputs and add new ones.
+    /// Returns the outputs each transaction spent, needed to undo the block.
+    pub fn apply_block(&mut self, block: &Block) -> UtxoUndo {
+        let mut undo = Vec::with_capacity(block.transactions.len());
+
+        for tx in &block.transactions {
+            let mut spent = Vec::new();
+            if !tx.is_coinbase() {
+                for input in &tx.inputs {
+                    if let Some(output) = self.utxos.remove(input) {
+                        spent.push((input.clone(), output));
+                    }
+                }
+            }
+            undo.push(spent);
+
+            let txid = tx.hash();
+            for (index, output) in tx.outputs.iter().enumerate() {
+                self.utxos
+                    .insert(OutPoint::new(txid.clone(), index as u32), output.clone());
+            }
+        }
+
+        undo
+    }
+
+    /// Revert `apply_block`: remove the block's outputs and restore what it spent,
+    /// last transaction first
+    pub fn undo_block(&mut self, block: &Block, undo: UtxoUndo) {
+        for (tx, spent) in block.transactions.iter().zip(undo).rev() {
+            let txid = tx.hash();
+            for index in 0..tx.outputs.len() {
+                self.utxos.remove(&OutPoint::new(txid.clone(), index as u32));
+            }
+            for (outpoint, output) in spent {
+                self.utxos.insert(outpoint, output);
+            }
+        }
+    }
+
+    /// Fee implied by a transaction (inputs minus outputs). Inputs may also
+    /// spend outputs of `pending` transactions, given by hash. `None` if an
+    /// input is unknown.
+    pub fn fee(&self, tx: &Transaction, pending: &HashMap<String, &Transaction>) -> Option<u64> {
+        let mut input_total: u64 = 0;
+        for input in &tx.inputs {
+            let amount = match self.utxos.get(input) {
+                Some(output) => output.amount,
+                None => pending.get(&input.txid)?.outputs.get(input.index as usize)?.amount,
+            };
+            input_total = input_total.checked_add(amount)?;
+        }
+        input_total.checked_sub(tx.output_total()?)
+    }
+
+    /// Select unspent outputs of `address` covering at least `amount`.
+    /// Returns the selected outpoints and their total value.
+    pub fn select(&self, address: &str, amount: u64) -> Option<(Vec<OutPoint>, u64)> {
+        let mut selected = Vec::new();
+        let mut total: u64 = 0;
+
+        for (outpoint, output) in self.unspent_for(address) {
+            total += output.amount;
+            selected.push(outpoint);
+            if total >= amount {
+                break;
+            }
+        }
+
+        if !selected.is_empty() && total >= amount {
+            Some((selected, total))
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::ZERO_HASH;
+
+    /// Block at height 1 with the given transactions (not mined)
+    fn block(transactions: Vec<Transaction>) -> Block {
+        Block::new(1, ZERO_HASH.to_string(), transactions, 0)
+    }
+
+    /// Set holding a single 50 coin output of `alice`, and that output
+    fn funded() -> (UtxoSet, OutPoint) {
+        let mut utxos = UtxoSet::new();
+        let coinbase = Transaction::coinbase(String::from("alice"), 50, 1);
+        utxos.apply_block(&block(vec![coinbase.clone()]));
+        (utxos, OutPoint::new(coinbase.hash(), 0))
+    }
+
+    fn pay(input: &OutPoint, outputs: &[(&str, u64)]) -> Transaction {
+        let outputs = outputs.iter().map(|(to, amount)| TxOutput::new(to.to_string(), *amount)).collect();
+        Transaction::new(String::from("alice"), vec![input.clone()], outputs)
+    }
+
+    #[test]
+    fn spends_pay_the_difference_as_fee() {
+        let (utxos, coin) = funded();
+        let tx = pay(&coin, &[("bob", 30), ("alice", 15)]);
+        assert_eq!(utxos.check_transactions(std::slice::from_ref(&tx)), Ok(5));
+        assert_eq!(utxos.fee(&tx, &HashMap::new()), Some(5));
+
+        // Outputs created earlier in the list can be spent later in it
+        let change = OutPoint::new(tx.hash(), 1);
+        let next = pay(&change, &[("bob", 15)]);
+        assert_eq!(utxos.check_transactions(&[tx, next]), Ok(5));
+    }
+
+    #[test]
+    fn double_spends_are_rejected() {
+        let (utxos, coin) = funded();
+        let first = pay(&coin, &[("bob", 50)]);
+        let second = pay(&coin, &[("carol", 50)]);
+        assert!(utxos.check_transactions(&[first, second]).is_err());
+
+        let twice = Transaction::new(
+            String::from("alice"),
+            vec![coin.clone(), coin],
+            vec![TxOutput::new(String::from("bob"), 100)],
+        );
+        assert!(utxos.check_transactions(&[twice]).is_err());
+    }
+
+    #[test]
+    fn overspends_are_rejected() {
+        let (utxos, coin) = funded();
+        assert!(utxos.check_transactions(&[pay(&coin, &[("bob", 51)])]).is_err());
+        assert!(utxos.check_transactions(&[pay(&coin, &[("bob", u64::MAX), ("bob", 1)])]).is_err());
+    }
+
+    #[test]
+    fn unknown_and_foreign_outputs_are_rejected() {
+        let (utxos, coin) = funded();
+        let unknown = OutPoint::new("ab".repeat(32), 0);
+        assert!(utxos.check_transactions(&[pay(&unknown, &[("bob", 1)])]).is_err());
+        assert!(utxos.check_transactions(&[pay(&OutPoint::new(coin.txid.clone(), 1), &[("bob", 1)])]).is_err());
+
+        let mut stolen = pay(&coin, &[("bob", 50)]);
+        stolen.from = String::from("mallory");
+        assert!(utxos.check_transactions(&[stolen]).is_err());
+
+        let no_inputs = Transaction::new(String::from("alice"), vec![], vec![]);
+        assert!(utxos.check_transactions(&[no_inputs]).is_err());
+    }
+
+    #[test]
+    fn undo_restores_the_set() {
+        let (mut utxos, coin) = funded();
+        let tx = pay(&coin, &[("bob", 30), ("alice", 20)]);
+        let spending = block(vec![tx.clone()]);
+
+        let undo = utxos.apply_block(&spending);
+        assert!(utxos.get(&coin).is_none());
+        assert_eq!(utxos.balance("bob"), 30);
+        assert_eq!(utxos.balance("alice"), 20);
+        assert_eq!(utxos.len(), 2);
+
+        utxos.undo_block(&spending, undo);
+        assert_eq!(utxos.get(&coin), Some(&TxOutput::new(String::from("alice"), 50)));
+        assert_eq!(utxos.balance("bob"), 0);
+        assert_eq!(utxos.len(), 1);
+        assert!(utxos.get(&OutPoint::new(tx.hash(), 0)).is_none());
+    }
+}